@@ -0,0 +1,166 @@
+// Performance of the engines and the searches on fixed boards, to catch regressions: run with `cargo bench`, followed
+// by words to only run the benchmarks whose name contains one of them (as in `cargo bench -- expectimax`).
+// Each benchmark reports the median rate over SAMPLES samples, and the slowest and fastest samples. The searches
+// report nodes per second with `cargo bench --features node-count`, and searches per second otherwise
+use main::fastgame::FastGame;
+use main::game::{self, Direction, DIRECTIONS};
+use main::mcts::{MctsConfig, MonteCarloTree};
+use main::minimax::{self, EvaluationWeights};
+use seeded_random::{Random, Seed};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const SAMPLES: usize = 5;
+const SAMPLE_TIME: Duration = Duration::from_millis(500);
+const MINIMAX_DEPTH: usize = 6;
+const EXPECTIMAX_DEPTH: usize = 4;
+const MCTS_ITERATIONS: usize = 1000;
+const BARREL_ROLLS: usize = 1000;
+
+// Unit of the work returned by search_work
+const SEARCH_UNIT: &str = if cfg!(feature = "node-count") { "nodes" } else { "searches" };
+
+// Boards of exponents, from a fresh game to a crowded one
+const FIXTURES: [(&str, [u8; 16]); 3] = [
+    ("opening", [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0]),
+    ("midgame", [7, 6, 5, 2, 3, 4, 2, 1, 1, 2, 0, 0, 0, 1, 0, 0]),
+    ("endgame", [11, 10, 9, 8, 4, 5, 6, 7, 3, 2, 3, 1, 1, 0, 2, 0]),
+];
+
+struct Bencher {
+    filters: Vec<String>,
+}
+
+impl Bencher {
+    // Runs batch, which returns the number of units it processed, until each sample lasts SAMPLE_TIME
+    fn run(&self, name: &str, unit: &str, mut batch: impl FnMut() -> u64) {
+        if !self.filters.is_empty() && !self.filters.iter().any(|filter| name.contains(filter.as_str())) {
+            return;
+        }
+        // Warm-up
+        batch();
+        let mut rates: Vec<f64> = (0..SAMPLES)
+            .map(|_| {
+                let start = Instant::now();
+                let mut count = 0;
+                while start.elapsed() < SAMPLE_TIME {
+                    count += batch();
+                }
+                count as f64 / start.elapsed().as_secs_f64()
+            })
+            .collect();
+        rates.sort_by(f64::total_cmp);
+        println!(
+            "{:<36} {:>14.0} {}/s   ({:.0} to {:.0})",
+            name,
+            rates[SAMPLES / 2],
+            unit,
+            rates[0],
+            rates[SAMPLES - 1]
+        );
+    }
+}
+
+fn slow_move(board: &mut [u8; 16], direction: &Direction) -> i32 {
+    match direction {
+        Direction::Up => game::move_up(board),
+        Direction::Down => game::move_down(board),
+        Direction::Left => game::move_left(board),
+        Direction::Right => game::move_right(board),
+        Direction::None => 0,
+    }
+}
+
+// Moves in every direction in turn with a spawn after each round, the benchmark of the original lib.rs
+fn barrel_roll(fast: &FastGame, mut grid: [u32; 4], random: &Random) -> ([u32; 4], u32) {
+    let mut score = 0;
+    for _ in 0..BARREL_ROLLS {
+        for direction in &DIRECTIONS {
+            let (new_grid, move_score) = fast.make_move(&grid, direction);
+            grid = new_grid;
+            score += move_score;
+        }
+        grid = fast.add_random_block(grid, random);
+    }
+    (grid, score)
+}
+
+// Runs the search and returns its work in SEARCH_UNIT
+fn search_work(search: impl FnOnce()) -> u64 {
+    #[cfg(feature = "node-count")]
+    {
+        minimax::take_node_count();
+        search();
+        minimax::take_node_count()
+    }
+    #[cfg(not(feature = "node-count"))]
+    {
+        search();
+        1
+    }
+}
+
+fn main() {
+    let bencher = Bencher { filters: std::env::args().skip(1).filter(|arg| !arg.starts_with("--")).collect() };
+    let fast = FastGame::new();
+    let grids = FIXTURES.map(|(_, board)| FastGame::from_flat_array(board));
+    let weights = EvaluationWeights::default();
+    // The searches run on a single thread, whose node count is then complete, and whose rate is less noisy
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+
+    bencher.run("game.rs moves", "moves", || {
+        for (_, board) in &FIXTURES {
+            for direction in &DIRECTIONS {
+                let mut board = *board;
+                black_box(slow_move(black_box(&mut board), direction));
+            }
+        }
+        (FIXTURES.len() * DIRECTIONS.len()) as u64
+    });
+    bencher.run("FastGame moves", "moves", || {
+        for grid in &grids {
+            for direction in &DIRECTIONS {
+                black_box(fast.make_move(black_box(grid), direction));
+            }
+        }
+        (grids.len() * DIRECTIONS.len()) as u64
+    });
+    let random = Random::from_seed(Seed::unsafe_new(0));
+    bencher.run("FastGame barrel roll", "moves", || {
+        black_box(barrel_roll(&fast, black_box([1, 0, 0, 0]), &random));
+        (BARREL_ROLLS * DIRECTIONS.len()) as u64
+    });
+    bencher.run("minimax::evaluate", "evaluations", || {
+        for grid in &grids {
+            black_box(minimax::evaluate(black_box(*grid), &weights));
+        }
+        grids.len() as u64
+    });
+
+    for ((name, _), grid) in FIXTURES.iter().zip(grids) {
+        bencher.run(&format!("minimax depth {}, {}", MINIMAX_DEPTH, name), SEARCH_UNIT, || {
+            pool.install(|| {
+                search_work(|| {
+                    black_box(minimax::get_best_direction_minimax(&fast, grid, MINIMAX_DEPTH, &weights));
+                })
+            })
+        });
+    }
+    for ((name, _), grid) in FIXTURES.iter().zip(grids) {
+        bencher.run(&format!("expectimax depth {}, {}", EXPECTIMAX_DEPTH, name), SEARCH_UNIT, || {
+            pool.install(|| {
+                search_work(|| {
+                    black_box(minimax::get_best_direction_expectimax(&fast, grid, EXPECTIMAX_DEPTH, &weights));
+                })
+            })
+        });
+    }
+    for ((name, _), grid) in FIXTURES.iter().zip(grids) {
+        bencher.run(&format!("MCTS, {}", name), "iterations", || {
+            let mut tree = MonteCarloTree::new(&fast, grid, MctsConfig::default());
+            tree.grow_tree(&fast, 0.0, MCTS_ITERATIONS);
+            black_box(tree.get_best_direction());
+            MCTS_ITERATIONS as u64
+        });
+    }
+}
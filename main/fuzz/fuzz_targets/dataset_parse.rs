@@ -0,0 +1,14 @@
+// Parsing of the imitation datasets, the recorded games of the searches
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use main::fastgame::FastGame;
+use main::imitation;
+
+fuzz_target!(|contents: &str| {
+    if let Ok(samples) = imitation::parse_dataset(contents, "fuzz") {
+        for sample in samples {
+            assert!(sample.board.iter().all(|&row| FastGame::is_valid_row(row)));
+            assert!(sample.direction < 4);
+        }
+    }
+});
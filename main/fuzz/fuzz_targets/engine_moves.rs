@@ -0,0 +1,47 @@
+// FastGame on arbitrary packed grids, whose bits outside the cells must be ignored, checked against game.rs
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use main::fastgame::FastGame;
+use main::game::{self, Direction, DIRECTIONS};
+use std::sync::OnceLock;
+
+static FAST: OnceLock<FastGame> = OnceLock::new();
+
+fn slow_move(board: &[u8; 16], direction: &Direction) -> ([u8; 16], i32) {
+    let mut moved = *board;
+    let score = match direction {
+        Direction::Up => game::move_up(&mut moved),
+        Direction::Down => game::move_down(&mut moved),
+        Direction::Left => game::move_left(&mut moved),
+        Direction::Right => game::move_right(&mut moved),
+        Direction::None => 0,
+    };
+    (moved, score)
+}
+
+fuzz_target!(|grid: [u32; 4]| {
+    let fast = FAST.get_or_init(FastGame::new);
+    let flat = FastGame::to_flat_array(grid);
+    let board = FastGame::from_flat_array(flat);
+    for direction in &DIRECTIONS {
+        let (moved, score) = fast.make_move(&grid, direction);
+        assert_eq!((moved, score), fast.make_move(&board, direction));
+        assert!(moved.iter().all(|&row| FastGame::is_valid_row(row)));
+        assert_eq!(fast.can_move(&grid, direction), moved != board);
+        let (slow_moved, slow_score) = slow_move(&flat, direction);
+        assert_eq!(FastGame::to_flat_array(moved), slow_moved);
+        assert_eq!(score.min(i32::MAX as u32), slow_score as u32);
+    }
+    assert_eq!(fast.is_lost(&grid), fast.get_possible_directions(&grid).is_empty());
+    assert_eq!(fast.is_lost(&grid), game::is_lost(&flat));
+    for (row, column) in FastGame::empty_list(&grid) {
+        for value in [1, 2] {
+            let placed = FastGame::to_flat_array(fast.place_block(grid, (row, column), value));
+            // Columns of empty_list and place_block count from the right
+            let cell = row * 4 + 3 - column;
+            assert_eq!(flat[cell], 0);
+            assert_eq!(placed[cell], value as u8);
+            assert!((0..16).all(|i| i == cell || placed[i] == flat[i]));
+        }
+    }
+});
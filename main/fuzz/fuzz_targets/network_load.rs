@@ -0,0 +1,12 @@
+// NeuralNetwork::load on arbitrary files, in the binary or the legacy text format
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use main::neural_network::{ForwardBuffers, NeuralNetwork};
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Ok((network, _)) = NeuralNetwork::from_file_bytes(bytes) {
+        // A loaded network must be usable
+        let mut outputs = [0.0; 4];
+        network.evaluate_board(&[0x08421, 0, 0, 0], &mut ForwardBuffers::default(), &mut outputs);
+    }
+});
@@ -0,0 +1,11 @@
+// NTupleNetwork::load on arbitrary files
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use main::ntuple::NTupleNetwork;
+
+fuzz_target!(|bytes: &[u8]| {
+    if let Ok((network, _)) = NTupleNetwork::from_file_bytes(bytes) {
+        // A loaded network must be usable
+        network.value(&[0x08421, 0x1FFFF, 0, 0]);
+    }
+});
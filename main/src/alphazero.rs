@@ -0,0 +1,235 @@
+// AlphaZero-style training: a policy/value network guides the MCTS, and the MCTS games train the network
+use crate::encoding::InputEncoding;
+use crate::fastgame::FastGame;
+use crate::game;
+use crate::mcts;
+use crate::neural_network::{Activation, ForwardBuffers, Gradients, NeuralNetwork};
+use crate::training::{self, Loss, Optimizer, OptimizerKind};
+use rand::seq::{IndexedRandom, SliceRandom};
+use rayon::prelude::*;
+use seeded_random::{Random, Seed};
+
+// The network has 4 policy outputs (in game::DIRECTIONS order) followed by a value output,
+// which predicts the score left to gain from the board divided by VALUE_SCALE.
+// The input layer depends on the encoding
+pub const HIDDEN_LAYERS: [u32; 2] = [256, 256];
+const OUTPUTS: usize = 5;
+pub const VALUE_SCALE: f32 = 10_000.0;
+
+// Self-play settings
+pub const SELF_PLAY_GAMES: usize = 16;
+pub const SELF_PLAY_ITERATIONS: usize = 200;
+// Number of most recent samples kept to train the network
+pub const REPLAY_WINDOW: usize = 20_000;
+// The network is fitted by TRAINING_EPOCHS passes of Adam over TRAINING_BATCH_SIZE recent samples per generation,
+// by mini-batches of MINI_BATCH_SIZE samples
+pub const TRAINING_EPOCHS: usize = 4;
+pub const TRAINING_BATCH_SIZE: usize = 2_000;
+const MINI_BATCH_SIZE: usize = 64;
+pub const LEARNING_RATE: f32 = 0.001;
+
+#[derive(Clone)]
+pub struct Sample {
+    pub board: [u32; 4],
+    // Visit distribution of the root's moves
+    pub policy: [f32; 4],
+    // Score gained from this board until the end of the game, divided by VALUE_SCALE
+    pub value: f32,
+}
+
+pub fn new_network(encoding: InputEncoding) -> NeuralNetwork {
+    let mut layers = vec![encoding.input_size() as u32];
+    layers.extend(HIDDEN_LAYERS);
+    layers.push(OUTPUTS as u32);
+    // The output stays linear since it mixes the policy logits and the value
+    let activations = vec![Activation::LeakyRelu, Activation::LeakyRelu, Activation::Linear];
+    NeuralNetwork::new(layers, activations, encoding, (-0.1, 0.1), (-0.01, 0.01))
+}
+
+// Move probabilities (softmax of the policy outputs) and predicted score left to gain from the board
+pub fn policy_value(network: &NeuralNetwork, board: [u32; 4]) -> ([f32; 4], f32) {
+    thread_local! {
+        static BUFFERS: std::cell::RefCell<ForwardBuffers> = std::cell::RefCell::new(ForwardBuffers::default());
+    }
+    let mut outputs = [0.0; OUTPUTS];
+    BUFFERS.with(|buffers| network.evaluate_board(&board, &mut buffers.borrow_mut(), &mut outputs));
+    policy_value_from_outputs(&outputs)
+}
+
+fn policy_value_from_outputs(outputs: &[f32]) -> ([f32; 4], f32) {
+    let max_logit = outputs[..4].iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exponentials: [f32; 4] = core::array::from_fn(|i| (outputs[i] - max_logit).exp());
+    let total: f32 = exponentials.iter().sum();
+    (exponentials.map(|exponential| exponential / total), outputs[4] * VALUE_SCALE)
+}
+
+// Plays a full game with the guided MCTS, returning the training samples and the final score
+pub fn self_play_game(
+    fast: &FastGame,
+    network: &NeuralNetwork,
+    config: mcts::MctsConfig,
+    iteration_limit: usize,
+    seed: u64,
+) -> (Vec<Sample>, u32) {
+    let rand = Random::from_seed(Seed::unsafe_new(seed));
+    let mut game_state = [0; 4];
+    game_state = fast.add_random_block(game_state, &rand);
+    game_state = fast.add_random_block(game_state, &rand);
+    let mut game_score = 0;
+    // Boards, visit distributions and scores reached before each move
+    let mut history = Vec::new();
+    let mut tree = mcts::MonteCarloTree::from_network(fast, game_state, config, network.clone());
+    while !fast.is_lost(&game_state) {
+        tree.grow_tree(fast, 0.0, iteration_limit);
+        let visit_distribution = tree.get_visit_distribution();
+        history.push((game_state, visit_distribution, game_score));
+        let direction = if history.len() <= config.temperature_moves {
+            sample_direction(&visit_distribution, config.temperature, rand.gen::<f32>())
+        } else {
+            tree.get_best_direction()
+        };
+        let (new_game_state, move_score) = fast.play_move(game_state, direction, &rand);
+        game_score += move_score;
+        game_state = new_game_state;
+        tree.reroot(fast, move_score, game_state);
+    }
+    let samples = history
+        .into_iter()
+        .map(|(board, policy, score)| Sample {
+            board,
+            policy,
+            value: (game_score - score) as f32 / VALUE_SCALE,
+        })
+        .collect();
+    (samples, game_score)
+}
+
+// Direction drawn in proportion to visit_share^(1/temperature) by the uniform sample in [0, 1), the most visited one
+// when the temperature is 0
+pub fn sample_direction(visit_distribution: &[f32; 4], temperature: f32, uniform: f32) -> game::Direction {
+    let weights = if temperature > 0.0 {
+        visit_distribution.map(|share| share.powf(1.0 / temperature))
+    } else {
+        let most_visited = visit_distribution.iter().cloned().fold(0.0, f32::max);
+        visit_distribution.map(|share| if share == most_visited { 1.0 } else { 0.0 })
+    };
+    let total: f32 = weights.iter().sum();
+    if total <= 0.0 {
+        return game::Direction::None;
+    }
+    let mut remaining = uniform * total;
+    let mut sampled = game::Direction::None;
+    for (direction, weight) in game::DIRECTIONS.iter().zip(weights) {
+        if weight > 0.0 {
+            sampled = direction.clone();
+            if remaining < weight {
+                break;
+            }
+            remaining -= weight;
+        }
+    }
+    sampled
+}
+
+// Adam optimizer for the network, kept from one generation to the next
+pub fn new_optimizer(network: &NeuralNetwork) -> Optimizer {
+    Optimizer::new(OptimizerKind::Adam, LEARNING_RATE, 0.0, network)
+}
+
+// Adds the gradient of the cross-entropy of the policy plus the squared error of the value, returns that loss
+fn add_sample_gradient(network: &NeuralNetwork, sample: &Sample, gradients: &mut Gradients) -> f32 {
+    let mut outputs = [0.0; OUTPUTS];
+    training::add_board_gradient_with(network, &sample.board, &mut outputs, gradients, |outputs, gradient| {
+        let (policy_gradient, value_gradient) = gradient.split_at_mut(4);
+        Loss::CrossEntropy.evaluate(&outputs[..4], &sample.policy, policy_gradient)
+            + Loss::MeanSquaredError.evaluate(&outputs[4..], &[sample.value], value_gradient)
+    })
+}
+
+// Fits the network to the samples by epochs of mini-batch gradient descent, returns the mean loss of the last epoch
+pub fn fit(network: &mut NeuralNetwork, optimizer: &mut Optimizer, samples: &[Sample], epochs: usize) -> f32 {
+    let mut order: Vec<usize> = (0..samples.len()).collect();
+    let mut mean_loss = 0.0;
+    for _ in 0..epochs {
+        order.shuffle(&mut rand::rng());
+        let mut total_loss = 0.0;
+        for batch in order.chunks(MINI_BATCH_SIZE) {
+            let (gradients, loss) = training::batch_gradients(network, batch, |&index, gradients| {
+                add_sample_gradient(network, &samples[index], gradients)
+            });
+            total_loss += loss;
+            optimizer.step(network, &gradients, 1.0 / batch.len() as f32);
+        }
+        mean_loss = total_loss / samples.len().max(1) as f32;
+    }
+    mean_loss
+}
+
+// One generation: self-play games in parallel, then fitting on a batch of the recent samples
+pub fn train_generation(
+    fast: &FastGame,
+    network: &mut NeuralNetwork,
+    optimizer: &mut Optimizer,
+    config: mcts::MctsConfig,
+    replay: &mut Vec<Sample>,
+    generation: u64,
+) -> (f32, f32) {
+    let games: Vec<(Vec<Sample>, u32)> = (0..SELF_PLAY_GAMES)
+        .into_par_iter()
+        .map(|game| {
+            self_play_game(
+                fast,
+                network,
+                config,
+                SELF_PLAY_ITERATIONS,
+                generation * SELF_PLAY_GAMES as u64 + game as u64,
+            )
+        })
+        .collect();
+    let average_score = games.iter().map(|(_, score)| *score as f32).sum::<f32>() / games.len() as f32;
+    for (samples, _) in games {
+        replay.extend(samples);
+    }
+    if replay.len() > REPLAY_WINDOW {
+        replay.drain(..replay.len() - REPLAY_WINDOW);
+    }
+    // Train on a random batch of the window, to bound the cost of a generation
+    let batch: Vec<Sample> = replay
+        .choose_multiple(&mut rand::rng(), TRAINING_BATCH_SIZE)
+        .cloned()
+        .collect();
+    let loss = fit(network, optimizer, &batch, TRAINING_EPOCHS);
+    (average_score, loss)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampled_directions_follow_the_tempered_visits() {
+        let visits = [0.5, 0.0, 0.3, 0.2];
+        let frequencies = |temperature: f32| {
+            let mut counts = [0.0; 4];
+            for step in 0..1000 {
+                counts[sample_direction(&visits, temperature, (step as f32 + 0.5) / 1000.0).index()] += 0.001;
+            }
+            counts
+        };
+        let expected = |temperature: f32| {
+            let weights = visits.map(|share: f32| share.powf(1.0 / temperature));
+            let total: f32 = weights.iter().sum();
+            weights.map(|weight| weight / total)
+        };
+        for temperature in [0.5, 1.0, 2.0] {
+            for (frequency, probability) in frequencies(temperature).iter().zip(expected(temperature)) {
+                assert!((frequency - probability).abs() < 2e-3, "temperature {}: {:?}", temperature, frequencies(temperature));
+            }
+        }
+        // Unvisited moves are never played, and a zero temperature plays the most visited move
+        assert_eq!(frequencies(1.0)[1], 0.0);
+        let greedy = frequencies(0.0);
+        assert!((greedy[0] - 1.0).abs() < 1e-3 && greedy[1..].iter().all(|&frequency| frequency == 0.0));
+        assert_eq!(sample_direction(&[0.0; 4], 1.0, 0.5), game::Direction::None);
+    }
+}
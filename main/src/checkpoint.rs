@@ -0,0 +1,166 @@
+// Checkpoints of the whole genetic training state, so that training can be resumed exactly where it stopped.
+// A checkpoint is a directory holding state.cfg (counters, seeds and GA config), history.csv (best fitness of
+// every generation), best.ntwk (best network so far), best_validation.ntwk (best network on the validation games)
+// and one network file per agent in agents/
+use crate::config::Config;
+use crate::neural_network::{NetworkMetadata, NeuralNetwork};
+use crate::population::{Agent, GaConfig};
+use rayon::prelude::*;
+use std::path::Path;
+
+pub struct TrainingState {
+    // Generation the agents are about to play
+    pub generation: u64,
+    // Seed of the genetic algorithm, whose generators only depend on it and on the generation
+    pub seed: u64,
+    // Seed of the agents' next games
+    pub game_seed: u64,
+    pub config: GaConfig,
+    pub networks: Vec<NeuralNetwork>,
+    // Best fitness of every generation played
+    pub history: Vec<(u64, f32)>,
+    // Best network so far and its fitness
+    pub best_ever: Option<(NeuralNetwork, f32)>,
+    // Best network on the validation games so far and its validation score
+    pub best_validation: Option<(NeuralNetwork, f32)>,
+}
+
+pub fn directory(name: &str) -> String {
+    format!("checkpoints/{}", name)
+}
+
+fn agent_path(directory: &str, index: usize) -> String {
+    format!("{}/agents/{}.ntwk", directory, index)
+}
+
+fn load_best(path: &str) -> Result<Option<(NeuralNetwork, f32)>, String> {
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+    let (network, metadata) =
+        NeuralNetwork::load(path).map_err(|error| format!("Could not load {} : {}", path, error))?;
+    Ok(Some((network, metadata.fitness)))
+}
+
+// A temporary checkpoint is complete once its state.cfg, written last, exists
+fn is_complete(directory: &str) -> bool {
+    Path::new(&format!("{}/state.cfg", directory)).exists()
+}
+
+// Saves the state of a population about to play generation. The checkpoint is written to <name>.tmp, the previous
+// one is moved to <name>.old, and only then is the new one moved into place and the old one deleted, so that at
+// any point of an interrupted save load still finds a complete checkpoint
+#[allow(clippy::too_many_arguments)]
+pub fn save(
+    directory: &str,
+    generation: u64,
+    seed: u64,
+    config: &GaConfig,
+    agents: &[Agent],
+    history: &[(u64, f32)],
+    best_ever: Option<(&NeuralNetwork, f32)>,
+    best_validation: Option<(&NeuralNetwork, f32)>,
+) -> std::io::Result<()> {
+    let temporary = format!("{}.tmp", directory);
+    if Path::new(&temporary).exists() {
+        std::fs::remove_dir_all(&temporary)?;
+    }
+    std::fs::create_dir_all(format!("{}/agents", temporary))?;
+
+    let mut csv = String::from("generation,best_fitness\n");
+    for (generation, fitness) in history {
+        csv += &format!("{},{}\n", generation, fitness);
+    }
+    std::fs::write(format!("{}/history.csv", temporary), csv)?;
+    if let Some((network, fitness)) = best_ever {
+        network.save(&format!("{}/best.ntwk", temporary), &NetworkMetadata::new(generation as usize, fitness))?;
+    }
+    if let Some((network, score)) = best_validation {
+        let metadata = NetworkMetadata::new(generation as usize, score);
+        network.save(&format!("{}/best_validation.ntwk", temporary), &metadata)?;
+    }
+    agents.par_iter().enumerate().try_for_each(|(index, agent)| {
+        let metadata = NetworkMetadata::new(generation as usize, 0.0);
+        agent.neural_network.save(&agent_path(&temporary, index), &metadata)
+    })?;
+    let game_seed = agents.first().map(|agent| agent.seed()).unwrap_or(0);
+    let state = format!(
+        "generation = {}\nseed = {}\ngame_seed = {}\nagents = {}\n{}",
+        generation,
+        seed,
+        game_seed,
+        agents.len(),
+        config.to_config_string()
+    );
+    std::fs::write(format!("{}/state.cfg", temporary), state)?;
+
+    let old = format!("{}.old", directory);
+    if Path::new(directory).exists() {
+        if Path::new(&old).exists() {
+            std::fs::remove_dir_all(&old)?;
+        }
+        std::fs::rename(directory, &old)?;
+    }
+    std::fs::rename(&temporary, directory)?;
+    if Path::new(&old).exists() {
+        std::fs::remove_dir_all(&old)?;
+    }
+    Ok(())
+}
+
+// Loads the checkpoint, or when a save was interrupted before it was moved into place, the complete new
+// checkpoint left in <name>.tmp, else the previous one left in <name>.old
+pub fn load(directory: &str) -> Result<TrainingState, String> {
+    let temporary = format!("{}.tmp", directory);
+    let old = format!("{}.old", directory);
+    let directory = if Path::new(directory).exists() {
+        directory
+    } else if is_complete(&temporary) {
+        &temporary
+    } else if Path::new(&old).exists() {
+        &old
+    } else {
+        directory
+    };
+    let state_path = format!("{}/state.cfg", directory);
+    let state = Config::load(&state_path)?;
+    let config = GaConfig::load(&state_path)?;
+    let agents: usize = state.get("agents", 0)?;
+    let networks = (0..agents)
+        .into_par_iter()
+        .map(|index| {
+            let path = agent_path(directory, index);
+            NeuralNetwork::load(&path)
+                .map(|(network, _)| network)
+                .map_err(|error| format!("Could not load {} : {}", path, error))
+        })
+        .collect::<Result<Vec<NeuralNetwork>, String>>()?;
+    if networks.is_empty() {
+        return Err(format!("{} holds no agents", directory));
+    }
+
+    let history_path = format!("{}/history.csv", directory);
+    let csv = std::fs::read_to_string(&history_path)
+        .map_err(|error| format!("Could not read {} : {}", history_path, error))?;
+    let mut history = Vec::new();
+    for line in csv.lines().skip(1) {
+        let parsed = line
+            .split_once(',')
+            .and_then(|(generation, fitness)| Some((generation.parse().ok()?, fitness.parse().ok()?)));
+        history.push(parsed.ok_or(format!("Invalid line \"{}\" in {}", line, history_path))?);
+    }
+
+    let best_ever = load_best(&format!("{}/best.ntwk", directory))?;
+    let best_validation = load_best(&format!("{}/best_validation.ntwk", directory))?;
+
+    Ok(TrainingState {
+        generation: state.get("generation", 1)?,
+        seed: state.get("seed", 0)?,
+        game_seed: state.get("game_seed", 0)?,
+        config,
+        networks,
+        history,
+        best_ever,
+        best_validation,
+    })
+}
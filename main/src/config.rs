@@ -0,0 +1,45 @@
+// Simple "key = value" configuration files, used to tune the AIs without recompiling
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| format!("Could not read {}: {}", path, error))?;
+        Config::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, String> {
+        let mut values = HashMap::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            // Everything after a # is a comment
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(format!("Line {}: expected \"key = value\"", line_number + 1))?;
+            values.insert(key.trim().to_string(), value.trim().to_string());
+        }
+        Ok(Config { values })
+    }
+
+    // Returns the parsed value of the key, or the default if the key is absent
+    pub fn get<T: FromStr>(&self, key: &str, default: T) -> Result<T, String>
+    where
+        T::Err: Display,
+    {
+        match self.values.get(key) {
+            Some(value) => value
+                .parse()
+                .map_err(|error| format!("Invalid value \"{}\" for {}: {}", value, key, error)),
+            None => Ok(default),
+        }
+    }
+}
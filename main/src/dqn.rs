@@ -0,0 +1,394 @@
+// Deep Q-learning: a network with one Q-value per direction is trained on transitions of its own games, sampled
+// from an experience replay buffer, towards targets computed by a periodically synchronized target network
+use crate::config::Config;
+use crate::fastgame::FastGame;
+use crate::game;
+use crate::neural_network::{ForwardBuffers, Initialization, NeuralNetwork};
+use crate::training::{self, Loss, Optimizer, OptimizerKind};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use seeded_random::{Random, Seed};
+
+// Reward of a move, before scaling
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RewardShaping {
+    // The merge score of the move
+    Score,
+    // log2(1 + merge score), which keeps the big merges from dominating the targets
+    LogScore,
+    // Number of merges of the move
+    Merges,
+}
+
+impl std::str::FromStr for RewardShaping {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "score" => Ok(RewardShaping::Score),
+            "log_score" => Ok(RewardShaping::LogScore),
+            "merges" => Ok(RewardShaping::Merges),
+            _ => Err("expected score, log_score or merges".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DqnConfig {
+    pub episodes: usize,
+    // Episodes between two progress lines and saves of the network
+    pub report_interval: usize,
+    // Shape and initialization of new networks
+    pub hidden_layers: usize,
+    pub hidden_size: u32,
+    pub initialization: Initialization,
+    pub optimizer: OptimizerKind,
+    pub learning_rate: f32,
+    pub momentum: f32,
+    // Replay: transitions kept, transitions stored before the first update, moves between two updates
+    pub replay_capacity: usize,
+    pub warmup: usize,
+    pub batch_size: usize,
+    pub train_interval: usize,
+    // Moves between two copies of the network to the target network
+    pub target_update_interval: usize,
+    pub discount: f32,
+    // Epsilon-greedy exploration, decreasing linearly from start to end over the first epsilon_episodes episodes
+    pub epsilon_start: f32,
+    pub epsilon_end: f32,
+    pub epsilon_episodes: usize,
+    pub reward: RewardShaping,
+    pub reward_scale: f32,
+    // Subtracted from the reward of the move which loses the game
+    pub loss_penalty: f32,
+}
+
+impl Default for DqnConfig {
+    fn default() -> Self {
+        DqnConfig {
+            episodes: 2000,
+            report_interval: 50,
+            hidden_layers: 2,
+            hidden_size: 256,
+            initialization: Initialization::He,
+            optimizer: OptimizerKind::Adam,
+            learning_rate: 0.0005,
+            momentum: 0.0,
+            replay_capacity: 100000,
+            warmup: 1000,
+            batch_size: 64,
+            train_interval: 4,
+            target_update_interval: 2000,
+            discount: 0.95,
+            epsilon_start: 1.0,
+            epsilon_end: 0.05,
+            epsilon_episodes: 1000,
+            reward: RewardShaping::LogScore,
+            reward_scale: 1.0,
+            loss_penalty: 0.0,
+        }
+    }
+}
+
+impl DqnConfig {
+    pub fn load(path: &str) -> Result<DqnConfig, String> {
+        let config = Config::load(path)?;
+        let default = DqnConfig::default();
+        Ok(DqnConfig {
+            episodes: config.get("episodes", default.episodes)?,
+            report_interval: config.get("report_interval", default.report_interval)?,
+            hidden_layers: config.get("hidden_layers", default.hidden_layers)?,
+            hidden_size: config.get("hidden_size", default.hidden_size)?,
+            initialization: config.get("initialization", default.initialization)?,
+            optimizer: config.get("optimizer", default.optimizer)?,
+            learning_rate: config.get("learning_rate", default.learning_rate)?,
+            momentum: config.get("momentum", default.momentum)?,
+            replay_capacity: config.get("replay_capacity", default.replay_capacity)?,
+            warmup: config.get("warmup", default.warmup)?,
+            batch_size: config.get("batch_size", default.batch_size)?,
+            train_interval: config.get("train_interval", default.train_interval)?,
+            target_update_interval: config.get("target_update_interval", default.target_update_interval)?,
+            discount: config.get("discount", default.discount)?,
+            epsilon_start: config.get("epsilon_start", default.epsilon_start)?,
+            epsilon_end: config.get("epsilon_end", default.epsilon_end)?,
+            epsilon_episodes: config.get("epsilon_episodes", default.epsilon_episodes)?,
+            reward: config.get("reward", default.reward)?,
+            reward_scale: config.get("reward_scale", default.reward_scale)?,
+            loss_penalty: config.get("loss_penalty", default.loss_penalty)?,
+        })
+    }
+
+    fn epsilon(&self, episode: u64) -> f32 {
+        let progress = (episode as f32 / self.epsilon_episodes.max(1) as f32).min(1.0);
+        self.epsilon_start + (self.epsilon_end - self.epsilon_start) * progress
+    }
+
+    // Shaped reward of a move from grid to afterstate, which gained score
+    fn reward(&self, grid: &[u32; 4], afterstate: &[u32; 4], score: u32) -> f32 {
+        let reward = match self.reward {
+            RewardShaping::Score => score as f32,
+            RewardShaping::LogScore => (1.0 + score as f32).log2(),
+            // Each merge frees a cell
+            RewardShaping::Merges => (FastGame::empty_list(afterstate).len() - FastGame::empty_list(grid).len()) as f32,
+        };
+        reward * self.reward_scale
+    }
+}
+
+struct Transition {
+    grid: [u32; 4],
+    // Index in game::DIRECTIONS of the move played
+    action: usize,
+    reward: f32,
+    // Grid after the move and the spawn, lost when done
+    next_grid: [u32; 4],
+    done: bool,
+}
+
+// Ring buffer of the last transitions
+struct ReplayBuffer {
+    transitions: Vec<Transition>,
+    capacity: usize,
+    next: usize,
+}
+
+impl ReplayBuffer {
+    fn new(capacity: usize) -> ReplayBuffer {
+        ReplayBuffer { transitions: Vec::new(), capacity: capacity.max(1), next: 0 }
+    }
+
+    fn push(&mut self, transition: Transition) {
+        if self.transitions.len() < self.capacity {
+            self.transitions.push(transition);
+        } else {
+            self.transitions[self.next] = transition;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+}
+
+// The possible move with the highest Q-value, as an index in game::DIRECTIONS, and that value
+fn best_move(fast: &FastGame, grid: &[u32; 4], q_values: &[f32; 4]) -> Option<(usize, f32)> {
+    (0..4)
+        .filter(|&i| fast.can_move(grid, &game::DIRECTIONS[i]))
+        .map(|i| (i, q_values[i]))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+pub struct DqnTrainer {
+    pub network: NeuralNetwork,
+    target: NeuralNetwork,
+    optimizer: Optimizer,
+    replay: ReplayBuffer,
+    config: DqnConfig,
+    // Exploration and replay sampling
+    rng: SmallRng,
+    buffers: ForwardBuffers,
+    moves: u64,
+}
+
+impl DqnTrainer {
+    pub fn new(network: NeuralNetwork, config: DqnConfig, seed: u64) -> DqnTrainer {
+        DqnTrainer {
+            target: network.clone(),
+            optimizer: Optimizer::new(config.optimizer, config.learning_rate, config.momentum, &network),
+            network,
+            replay: ReplayBuffer::new(config.replay_capacity),
+            config,
+            rng: SmallRng::seed_from_u64(seed),
+            buffers: ForwardBuffers::default(),
+            moves: 0,
+        }
+    }
+
+    // Epsilon-greedy move over the possible ones, None when the game is lost
+    fn choose_move(&mut self, fast: &FastGame, grid: &[u32; 4], epsilon: f32) -> Option<usize> {
+        if self.rng.random::<f32>() < epsilon {
+            let possible = fast.get_possible_directions(grid);
+            if possible.is_empty() {
+                return None;
+            }
+            return Some(possible[self.rng.random_range(0..possible.len())].index());
+        }
+        let mut q_values = [0.0; 4];
+        self.network.evaluate_board(grid, &mut self.buffers, &mut q_values);
+        best_move(fast, grid, &q_values).map(|(action, _)| action)
+    }
+
+    // Plays the episode-th training game, storing its transitions and training every train_interval moves.
+    // Returns the score, the highest tile exponent and the mean loss of the updates
+    pub fn play_episode(&mut self, fast: &FastGame, episode: u64) -> (u32, u8, f32) {
+        let rand = Random::from_seed(Seed::unsafe_new(episode));
+        let epsilon = self.config.epsilon(episode);
+        let mut grid = [0; 4];
+        grid = fast.add_random_block(grid, &rand);
+        grid = fast.add_random_block(grid, &rand);
+        let mut score = 0;
+        let mut total_loss = 0.0;
+        let mut updates = 0;
+        while let Some(action) = self.choose_move(fast, &grid, epsilon) {
+            let (afterstate, move_score) = fast.make_move(&grid, &game::DIRECTIONS[action]);
+            let next_grid = fast.add_random_block(afterstate, &rand);
+            let done = fast.is_lost(&next_grid);
+            let mut reward = self.config.reward(&grid, &afterstate, move_score);
+            if done {
+                reward -= self.config.loss_penalty;
+            }
+            self.replay.push(Transition { grid, action, reward, next_grid, done });
+            score += move_score;
+            grid = next_grid;
+
+            self.moves += 1;
+            if self.replay.transitions.len() >= self.config.warmup.max(1)
+                && self.moves.is_multiple_of(self.config.train_interval.max(1) as u64)
+            {
+                total_loss += self.train_batch(fast);
+                updates += 1;
+            }
+            if self.moves.is_multiple_of(self.config.target_update_interval.max(1) as u64) {
+                self.target = self.network.clone();
+            }
+        }
+        let max_tile = FastGame::to_flat_array(grid).into_iter().max().unwrap_or(0);
+        (score, max_tile, total_loss / updates.max(1) as f32)
+    }
+
+    // Targets of the transitions: r + discount * max Q_target(s') over the possible moves of s', or r when done.
+    // The target network evaluates every next grid in one batch
+    fn targets(
+        target: &NeuralNetwork,
+        buffers: &mut ForwardBuffers,
+        discount: f32,
+        fast: &FastGame,
+        batch: &[&Transition],
+    ) -> Vec<f32> {
+        let next_grids: Vec<[u32; 4]> = batch.iter().map(|transition| transition.next_grid).collect();
+        let mut next_q_values = vec![0.0; 4 * batch.len()];
+        target.evaluate_boards(&next_grids, buffers, &mut next_q_values);
+        batch
+            .iter()
+            .zip(next_q_values.chunks_exact(4))
+            .map(|(transition, q_values)| {
+                let next_value = if transition.done {
+                    0.0
+                } else {
+                    best_move(fast, &transition.next_grid, q_values.try_into().unwrap()).map_or(0.0, |(_, value)| value)
+                };
+                transition.reward + discount * next_value
+            })
+            .collect()
+    }
+
+    // One update on a mini-batch sampled from the replay buffer, towards the targets of the moves played, the other
+    // moves having no target. Returns the mean loss
+    fn train_batch(&mut self, fast: &FastGame) -> f32 {
+        let batch: Vec<&Transition> = (0..self.config.batch_size.max(1))
+            .map(|_| &self.replay.transitions[self.rng.random_range(0..self.replay.transitions.len())])
+            .collect();
+        let targets = Self::targets(&self.target, &mut self.buffers, self.config.discount, fast, &batch);
+        let batch: Vec<(&Transition, f32)> = batch.into_iter().zip(targets).collect();
+        let (gradients, loss) = training::batch_gradients(&self.network, &batch, |&(transition, target), gradients| {
+            let mut targets = [f32::NAN; 4];
+            targets[transition.action] = target;
+            let mut outputs = [0.0; 4];
+            training::add_board_gradient(
+                &self.network,
+                &transition.grid,
+                &targets,
+                Loss::MeanSquaredError,
+                &mut outputs,
+                gradients,
+            )
+        });
+        self.optimizer.step(&mut self.network, &gradients, 1.0 / batch.len() as f32);
+        loss / batch.len() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::InputEncoding;
+
+    fn transition(grid: [u32; 4], reward: f32, next_grid: [u32; 4], done: bool) -> Transition {
+        Transition { grid, action: 0, reward, next_grid, done }
+    }
+
+    fn trainer(config: DqnConfig) -> DqnTrainer {
+        let network = training::direction_network(InputEncoding::default(), 1, 16, Initialization::He);
+        DqnTrainer::new(network, config, 1)
+    }
+
+    #[test]
+    fn replay_buffers_replace_their_oldest_transitions() {
+        let mut replay = ReplayBuffer::new(3);
+        let rewards = |replay: &ReplayBuffer| replay.transitions.iter().map(|transition| transition.reward).collect::<Vec<_>>();
+        for reward in 0..5 {
+            replay.push(transition([0; 4], reward as f32, [0; 4], false));
+        }
+        assert_eq!(rewards(&replay), [3.0, 4.0, 2.0]);
+        replay.push(transition([0; 4], 5.0, [0; 4], false));
+        assert_eq!(rewards(&replay), [3.0, 4.0, 5.0]);
+        replay.push(transition([0; 4], 6.0, [0; 4], false));
+        assert_eq!(rewards(&replay), [6.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn targets_bootstrap_from_the_best_possible_next_move() {
+        let fast = FastGame::new();
+        let mut trainer = trainer(DqnConfig::default());
+        // Up has the lowest Q-value, but only up is possible on the first grid, and every move on the second one
+        let up_bias = trainer.target.bias.len() - 4;
+        trainer.target.bias[up_bias] -= 100.0;
+        let only_up = FastGame::from_flat_array([0, 0, 0, 0, 1, 2, 1, 2, 2, 1, 2, 1, 1, 2, 1, 2]);
+        let open = FastGame::from_flat_array([1, 0, 0, 0, 0, 2, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0]);
+        let q_values = |grid: &[u32; 4]| {
+            let mut q_values = [0.0; 4];
+            trainer.target.evaluate_board(grid, &mut ForwardBuffers::default(), &mut q_values);
+            q_values
+        };
+        let transitions = [
+            transition([0; 4], 1.0, only_up, false),
+            transition([0; 4], 2.0, open, false),
+            transition([0; 4], 3.0, open, true),
+        ];
+        let batch: Vec<&Transition> = transitions.iter().collect();
+        let targets = DqnTrainer::targets(&trainer.target, &mut ForwardBuffers::default(), 0.5, &fast, &batch);
+        let best_open = q_values(&open).into_iter().fold(f32::NEG_INFINITY, f32::max);
+        assert!(q_values(&open)[0] < best_open);
+        let expected = [1.0 + 0.5 * q_values(&only_up)[0], 2.0 + 0.5 * best_open, 3.0];
+        for (target, expected) in targets.iter().zip(expected) {
+            assert!((target - expected).abs() < 1e-5, "{:?} instead of {:?}", targets, expected);
+        }
+    }
+
+    #[test]
+    fn rewards_follow_the_shaping() {
+        let fast = FastGame::new();
+        // 2 2 4 4 moved left merges into 4 8, scoring 12
+        let grid = FastGame::from_flat_array([1, 1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let (afterstate, score) = fast.make_move(&grid, &game::Direction::Left);
+        assert_eq!(score, 12);
+        let reward = |reward| DqnConfig { reward, reward_scale: 0.5, ..DqnConfig::default() }.reward(&grid, &afterstate, score);
+        assert_eq!(reward(RewardShaping::Score), 6.0);
+        assert!((reward(RewardShaping::LogScore) - 0.5 * 13f32.log2()).abs() < 1e-6);
+        assert_eq!(reward(RewardShaping::Merges), 1.0);
+    }
+
+    #[test]
+    fn updates_fit_the_rewards_of_finished_games() {
+        let fast = FastGame::new();
+        let config = DqnConfig { batch_size: 32, learning_rate: 0.005, ..DqnConfig::default() };
+        let mut trainer = trainer(config);
+        let mut rng = SmallRng::seed_from_u64(2);
+        for _ in 0..32 {
+            let grid = FastGame::from_flat_array(std::array::from_fn(|_| rng.random_range(0..8)));
+            trainer.replay.push(transition(grid, rng.random_range(-1.0..1.0), grid, true));
+        }
+        let first_loss = trainer.train_batch(&fast);
+        let mut loss = first_loss;
+        for _ in 0..300 {
+            loss = trainer.train_batch(&fast);
+        }
+        assert!(loss < 0.2 * first_loss, "loss {} after {}", loss, first_loss);
+    }
+}
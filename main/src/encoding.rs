@@ -0,0 +1,184 @@
+// Ways of turning a board into the inputs of a network. The encoding is saved in the network file,
+// so that a network is always fed the same inputs it was trained with
+use crate::fastgame::FastGame;
+use crate::GRID_SIZE;
+
+const CELLS: usize = GRID_SIZE * GRID_SIZE;
+// Number of exponents told apart by the one-hot encoding (0 being the empty cell), higher ones share the last input
+pub const ONE_HOT_EXPONENTS: usize = 18;
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum CellEncoding {
+    // (exponent + 2) / 10, 0 for an empty cell
+    #[default]
+    Scalar,
+    // One input per cell and exponent
+    OneHot,
+    // Exponent divided by the highest exponent of the board
+    LogNormalized,
+}
+
+// Order in which the cell encodings are numbered in network files
+const CELL_ENCODINGS: [CellEncoding; 3] = [CellEncoding::Scalar, CellEncoding::OneHot, CellEncoding::LogNormalized];
+
+impl CellEncoding {
+    pub fn from_index(index: usize) -> Option<CellEncoding> {
+        CELL_ENCODINGS.get(index).copied()
+    }
+
+    pub fn index(&self) -> usize {
+        CELL_ENCODINGS.iter().position(|encoding| encoding == self).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct InputEncoding {
+    pub cells: CellEncoding,
+    // When set, the network sees the 8 symmetries of the board and its outputs are averaged
+    // (the first 4 outputs being moves in game::DIRECTIONS order, they are mapped back first)
+    pub symmetric: bool,
+}
+
+impl std::str::FromStr for InputEncoding {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (symmetric, cells) = match s.strip_prefix("symmetric_") {
+            Some(cells) => (true, cells),
+            None => (false, s),
+        };
+        let cells = match cells {
+            "scalar" => CellEncoding::Scalar,
+            "one_hot" => CellEncoding::OneHot,
+            "log_normalized" => CellEncoding::LogNormalized,
+            _ => return Err("expected scalar, one_hot or log_normalized, optionally prefixed by symmetric_".to_string()),
+        };
+        Ok(InputEncoding { cells, symmetric })
+    }
+}
+
+impl std::fmt::Display for InputEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let cells = match self.cells {
+            CellEncoding::Scalar => "scalar",
+            CellEncoding::OneHot => "one_hot",
+            CellEncoding::LogNormalized => "log_normalized",
+        };
+        if self.symmetric {
+            write!(f, "symmetric_{}", cells)
+        } else {
+            write!(f, "{}", cells)
+        }
+    }
+}
+
+impl InputEncoding {
+    // Number of inputs of a single board
+    pub fn input_size(&self) -> usize {
+        match self.cells {
+            CellEncoding::Scalar | CellEncoding::LogNormalized => CELLS,
+            CellEncoding::OneHot => CELLS * ONE_HOT_EXPONENTS,
+        }
+    }
+
+    // Number of network samples needed for a single board
+    pub fn samples(&self) -> usize {
+        if self.symmetric {
+            8
+        } else {
+            1
+        }
+    }
+
+    // Appends the inputs of the grid's samples to inputs, reading the exponents straight from the packed grid
+    pub fn encode(&self, grid: &[u32; 4], inputs: &mut Vec<f32>) {
+        let grids = if self.symmetric { FastGame::symmetries(*grid) } else { [*grid; 8] };
+        for grid in &grids[..self.samples()] {
+            let start = inputs.len();
+            inputs.resize(start + self.input_size(), 0.0);
+            let sample_inputs = &mut inputs[start..];
+            match self.cells {
+                CellEncoding::Scalar => {
+                    for (i, input) in sample_inputs.iter_mut().enumerate() {
+                        let exponent = cell(grid, i);
+                        if exponent != 0 {
+                            *input = (exponent as f32 + 2.0) / 10.0;
+                        }
+                    }
+                }
+                CellEncoding::OneHot => {
+                    for i in 0..CELLS {
+                        let exponent = (cell(grid, i) as usize).min(ONE_HOT_EXPONENTS - 1);
+                        sample_inputs[i * ONE_HOT_EXPONENTS + exponent] = 1.0;
+                    }
+                }
+                CellEncoding::LogNormalized => {
+                    let highest = (0..CELLS).map(|i| cell(grid, i)).max().unwrap();
+                    if highest != 0 {
+                        for (i, input) in sample_inputs.iter_mut().enumerate() {
+                            *input = cell(grid, i) as f32 / highest as f32;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Turns the outputs of the board's samples (stored one after the other) into the outputs for the board.
+    // Only the first outputs.len() outputs of the network are used
+    pub fn combine(&self, sample_outputs: &[f32], outputs: &mut [f32]) {
+        let sample_len = sample_outputs.len() / self.samples();
+        let len = outputs.len().min(sample_len);
+        outputs.fill(0.0);
+        if !self.symmetric {
+            outputs[..len].copy_from_slice(&sample_outputs[..len]);
+            return;
+        }
+        for (symmetry, symmetry_outputs) in sample_outputs.chunks_exact(sample_len).enumerate() {
+            for (i, output) in outputs[..len].iter_mut().enumerate() {
+                // Move i on the board is move symmetric_direction(i) on the transformed board
+                let source = if i < 4 && sample_len >= 4 { symmetric_direction(i, symmetry) } else { i };
+                *output += symmetry_outputs[source] / 8.0;
+            }
+        }
+    }
+
+    // Transpose of combine: turns the gradient of a loss with respect to the board's outputs into its gradient
+    // with respect to the outputs of the samples, which have sample_len outputs each
+    pub fn split_gradient(&self, gradient: &[f32], sample_len: usize) -> Vec<f32> {
+        let mut sample_gradients = vec![0.0; sample_len * self.samples()];
+        let len = gradient.len().min(sample_len);
+        if !self.symmetric {
+            sample_gradients[..len].copy_from_slice(&gradient[..len]);
+            return sample_gradients;
+        }
+        for (symmetry, symmetry_gradients) in sample_gradients.chunks_exact_mut(sample_len).enumerate() {
+            for (i, &output_gradient) in gradient[..len].iter().enumerate() {
+                let source = if i < 4 && sample_len >= 4 { symmetric_direction(i, symmetry) } else { i };
+                symmetry_gradients[source] += output_gradient / 8.0;
+            }
+        }
+        sample_gradients
+    }
+}
+
+// Exponent of the i-th cell (row by row) of a packed grid, the first column being in the highest bits
+fn cell(grid: &[u32; 4], i: usize) -> u32 {
+    (grid[i / GRID_SIZE] >> ((GRID_SIZE - 1 - i % GRID_SIZE) * 5)) & 0x1F
+}
+
+// Index in game::DIRECTIONS of the move that does on the transformed board what direction does on the board
+pub fn symmetric_direction(direction: usize, symmetry: usize) -> usize {
+    let mut direction = direction;
+    // Mirroring the columns swaps Left and Right, mirroring the rows swaps Up and Down
+    if symmetry & 1 != 0 && direction >= 2 {
+        direction ^= 1;
+    }
+    if symmetry & 2 != 0 && direction < 2 {
+        direction ^= 1;
+    }
+    // Transposing swaps Up and Left, and Down and Right
+    if symmetry & 4 != 0 {
+        direction ^= 2;
+    }
+    direction
+}
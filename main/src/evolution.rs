@@ -0,0 +1,514 @@
+// Evolution strategies: OpenAI-ES moves a mean network along a gradient estimated from antithetic perturbations,
+// CMA-ES adapts a full covariance matrix and is used for small parameter sets such as the evaluation weights
+use crate::config::Config;
+use crate::fitness::FitnessConfig;
+use crate::fastgame::FastGame;
+use crate::minimax::{self, EvaluationWeights};
+use crate::neural_network::{Gradients, NeuralNetwork};
+use crate::population::{self, Agent};
+use crate::game;
+use crate::training::{Optimizer, OptimizerKind};
+use rand::Rng;
+use rayon::prelude::*;
+use seeded_random::{Random, Seed};
+
+#[derive(Clone, Copy, Debug)]
+pub struct EsConfig {
+    // Number of antithetic pairs, each evaluated as two agents
+    pub pairs: usize,
+    pub noise_std: f32,
+    pub learning_rate: f32,
+    pub weight_decay: f32,
+    pub fitness: FitnessConfig,
+}
+
+impl Default for EsConfig {
+    fn default() -> Self {
+        EsConfig {
+            pairs: 50,
+            noise_std: 0.02,
+            learning_rate: 0.01,
+            weight_decay: 0.005,
+            fitness: FitnessConfig::default(),
+        }
+    }
+}
+
+impl EsConfig {
+    pub fn load(path: &str) -> Result<EsConfig, String> {
+        let config = Config::load(path)?;
+        let default = EsConfig::default();
+        Ok(EsConfig {
+            pairs: config.get("pairs", default.pairs)?,
+            noise_std: config.get("noise_std", default.noise_std)?,
+            learning_rate: config.get("learning_rate", default.learning_rate)?,
+            weight_decay: config.get("weight_decay", default.weight_decay)?,
+            fitness: FitnessConfig::from_config(&config)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CmaConfig {
+    // Candidates per generation, 0 for the usual 4 + 3 ln(n)
+    pub population_size: usize,
+    pub initial_step: f64,
+    // Each candidate plays this many expectimax games with this search depth
+    pub games: usize,
+    pub search_depth: usize,
+}
+
+impl Default for CmaConfig {
+    fn default() -> Self {
+        CmaConfig {
+            population_size: 0,
+            initial_step: 2.0,
+            games: 4,
+            search_depth: 2,
+        }
+    }
+}
+
+impl CmaConfig {
+    pub fn load(path: &str) -> Result<CmaConfig, String> {
+        let config = Config::load(path)?;
+        let default = CmaConfig::default();
+        Ok(CmaConfig {
+            population_size: config.get("population_size", default.population_size)?,
+            initial_step: config.get("initial_step", default.initial_step)?,
+            games: config.get("games", default.games)?,
+            search_depth: config.get("search_depth", default.search_depth)?,
+        })
+    }
+}
+
+// Standard normal sample (Box-Muller transform)
+pub(crate) fn gaussian<R: Rng>(rng: &mut R) -> f64 {
+    let u1: f64 = rng.random_range(f64::MIN_POSITIVE..1.0);
+    let u2: f64 = rng.random_range(0.0..1.0);
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+// Perturbation of a pair, regenerated from its seed instead of being stored
+fn perturbation(seed: u64, generation: u64, pair: usize, len: usize) -> Vec<f32> {
+    let mut rng = population::child_rng(seed, generation, pair);
+    (0..len).map(|_| gaussian(&mut rng) as f32).collect()
+}
+
+// Fitness values replaced by their rank scaled to [-0.5, 0.5], so that the update ignores the fitness scale
+fn centered_ranks(fitness: &[f32]) -> Vec<f32> {
+    let mut order: Vec<usize> = (0..fitness.len()).collect();
+    order.sort_by(|&a, &b| fitness[a].total_cmp(&fitness[b]));
+    let mut ranks = vec![0.0; fitness.len()];
+    for (rank, &index) in order.iter().enumerate() {
+        ranks[index] = rank as f32 / (fitness.len().max(2) - 1) as f32 - 0.5;
+    }
+    ranks
+}
+
+pub struct OpenAiEs {
+    pub mean: NeuralNetwork,
+    config: EsConfig,
+    fast: FastGame,
+    optimizer: Optimizer,
+}
+
+impl OpenAiEs {
+    pub fn new(mean: NeuralNetwork, config: EsConfig) -> OpenAiEs {
+        let optimizer = Optimizer::new(OptimizerKind::Adam, config.learning_rate, 0.0, &mean);
+        OpenAiEs {
+            mean,
+            config,
+            fast: FastGame::new(),
+            optimizer,
+        }
+    }
+
+    fn perturbed(&self, noise: &[f32], sign: f32) -> NeuralNetwork {
+        let mut network = self.mean.clone();
+        let scale = sign * self.config.noise_std;
+        for (parameter, noise) in network.weights.iter_mut().chain(network.bias.iter_mut()).zip(noise) {
+            *parameter += scale * noise;
+        }
+        network
+    }
+
+    // Evaluates the generation's antithetic pairs with population::run_all and updates the mean,
+    // returns the mean and best fitness of the perturbed agents
+    pub fn step(&mut self, seed: u64, generation: u64) -> (f32, f32) {
+        let len = self.mean.weights.len() + self.mean.bias.len();
+        // Every agent plays the same games, so that the two agents of a pair only differ by their perturbation
+        let game_seed = generation * population::RUNS_PER_AGENT as u64;
+        let mut agents: Vec<Agent> = (0..self.config.pairs)
+            .into_par_iter()
+            .flat_map_iter(|pair| {
+                let noise = perturbation(seed, generation, pair, len);
+                [
+                    Agent::from(self.perturbed(&noise, 1.0), game_seed),
+                    Agent::from(self.perturbed(&noise, -1.0), game_seed),
+                ]
+            })
+            .collect();
+        population::run_all(&mut agents, &self.fast, &self.config.fitness);
+        let fitness: Vec<f32> = agents.iter_mut().map(|agent| agent.geometric_mean()).collect();
+        drop(agents);
+        let ranks = centered_ranks(&fitness);
+
+        // Gradient estimate, summed pair by pair so that only a few perturbations are alive at once
+        let gradient = (0..self.config.pairs)
+            .into_par_iter()
+            .fold(
+                || vec![0.0; len],
+                |mut gradient, pair| {
+                    let difference = ranks[2 * pair] - ranks[2 * pair + 1];
+                    for (total, noise) in gradient.iter_mut().zip(perturbation(seed, generation, pair, len)) {
+                        *total += difference * noise;
+                    }
+                    gradient
+                },
+            )
+            .reduce(
+                || vec![0.0; len],
+                |mut a, b| {
+                    for (x, y) in a.iter_mut().zip(b) {
+                        *x += y;
+                    }
+                    a
+                },
+            );
+        let scale = 1.0 / (2.0 * self.config.pairs.max(1) as f32 * self.config.noise_std);
+
+        // Adam ascent step (a descent step on the opposite of the fitness), the weight decay pulling the
+        // parameters towards 0
+        let parameters = self.mean.weights.iter().chain(self.mean.bias.iter());
+        let descent: Vec<f32> = gradient
+            .iter()
+            .zip(parameters)
+            .map(|(g, parameter)| self.config.weight_decay * parameter - g * scale)
+            .collect();
+        let (weights, bias) = descent.split_at(self.mean.weights.len());
+        let gradients = Gradients { weights: weights.to_vec(), bias: bias.to_vec() };
+        self.optimizer.step(&mut self.mean, &gradients, 1.0);
+
+        let mean_fitness = fitness.iter().sum::<f32>() / fitness.len().max(1) as f32;
+        let best_fitness = fitness.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        (mean_fitness, best_fitness)
+    }
+}
+
+// (mu/mu_w, lambda)-CMA-ES maximizing the fitness, following Hansen's tutorial
+pub struct CmaEs {
+    pub mean: Vec<f64>,
+    pub step_size: f64,
+    population_size: usize,
+    recombination_weights: Vec<f64>,
+    mu_eff: f64,
+    c_sigma: f64,
+    d_sigma: f64,
+    c_c: f64,
+    c_1: f64,
+    c_mu: f64,
+    // Expected norm of a standard normal vector
+    chi_n: f64,
+    covariance: Vec<Vec<f64>>,
+    // Eigendecomposition of the covariance: basis (eigenvectors as columns) and square roots of the eigenvalues
+    basis: Vec<Vec<f64>>,
+    scales: Vec<f64>,
+    path_sigma: Vec<f64>,
+    path_c: Vec<f64>,
+    generation: i32,
+}
+
+impl CmaEs {
+    pub fn new(mean: Vec<f64>, step_size: f64, population_size: usize) -> CmaEs {
+        let n = mean.len() as f64;
+        let population_size = if population_size == 0 {
+            4 + (3.0 * n.ln()).floor() as usize
+        } else {
+            population_size.max(2)
+        };
+        let parents = population_size / 2;
+        let mut recombination_weights: Vec<f64> = (0..parents)
+            .map(|i| (parents as f64 + 0.5).ln() - ((i + 1) as f64).ln())
+            .collect();
+        let total: f64 = recombination_weights.iter().sum();
+        recombination_weights.iter_mut().for_each(|weight| *weight /= total);
+        let mu_eff = 1.0 / recombination_weights.iter().map(|weight| weight * weight).sum::<f64>();
+
+        let c_sigma = (mu_eff + 2.0) / (n + mu_eff + 5.0);
+        let d_sigma = 1.0 + 2.0 * (((mu_eff - 1.0) / (n + 1.0)).sqrt() - 1.0).max(0.0) + c_sigma;
+        let c_c = (4.0 + mu_eff / n) / (n + 4.0 + 2.0 * mu_eff / n);
+        let c_1 = 2.0 / ((n + 1.3).powi(2) + mu_eff);
+        let c_mu = (1.0 - c_1).min(2.0 * (mu_eff - 2.0 + 1.0 / mu_eff) / ((n + 2.0).powi(2) + mu_eff));
+        let dimension = mean.len();
+        let identity: Vec<Vec<f64>> = (0..dimension)
+            .map(|i| (0..dimension).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+            .collect();
+        CmaEs {
+            mean,
+            step_size,
+            population_size,
+            recombination_weights,
+            mu_eff,
+            c_sigma,
+            d_sigma,
+            c_c,
+            c_1,
+            c_mu,
+            chi_n: n.sqrt() * (1.0 - 1.0 / (4.0 * n) + 1.0 / (21.0 * n * n)),
+            covariance: identity.clone(),
+            basis: identity,
+            scales: vec![1.0; dimension],
+            path_sigma: vec![0.0; dimension],
+            path_c: vec![0.0; dimension],
+            generation: 0,
+        }
+    }
+
+    // Samples the candidates of the next generation
+    pub fn ask<R: Rng>(&self, rng: &mut R) -> Vec<Vec<f64>> {
+        let dimension = self.mean.len();
+        (0..self.population_size)
+            .map(|_| {
+                let z: Vec<f64> = (0..dimension).map(|i| self.scales[i] * gaussian(rng)).collect();
+                (0..dimension)
+                    .map(|i| {
+                        let y: f64 = (0..dimension).map(|k| self.basis[i][k] * z[k]).sum();
+                        self.mean[i] + self.step_size * y
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Updates the distribution from the candidates returned by ask and their fitness
+    pub fn tell(&mut self, candidates: &[Vec<f64>], fitness: &[f64]) {
+        let dimension = self.mean.len();
+        let n = dimension as f64;
+        let mut order: Vec<usize> = (0..candidates.len()).collect();
+        order.sort_by(|&a, &b| fitness[b].total_cmp(&fitness[a]));
+        // Steps of the selected candidates, in units of the step size
+        let steps: Vec<Vec<f64>> = order
+            .iter()
+            .take(self.recombination_weights.len())
+            .map(|&index| {
+                (0..dimension)
+                    .map(|i| (candidates[index][i] - self.mean[i]) / self.step_size)
+                    .collect()
+            })
+            .collect();
+        let weighted_step: Vec<f64> = (0..dimension)
+            .map(|i| {
+                steps
+                    .iter()
+                    .zip(&self.recombination_weights)
+                    .map(|(step, weight)| weight * step[i])
+                    .sum()
+            })
+            .collect();
+        for (mean, step) in self.mean.iter_mut().zip(&weighted_step) {
+            *mean += self.step_size * step;
+        }
+
+        // Step size path, using C^-1/2 = B D^-1 B^T
+        let rotated: Vec<f64> = (0..dimension)
+            .map(|k| (0..dimension).map(|i| self.basis[i][k] * weighted_step[i]).sum::<f64>() / self.scales[k])
+            .collect();
+        let sigma_factor = (self.c_sigma * (2.0 - self.c_sigma) * self.mu_eff).sqrt();
+        for i in 0..dimension {
+            let whitened: f64 = (0..dimension).map(|k| self.basis[i][k] * rotated[k]).sum();
+            self.path_sigma[i] = (1.0 - self.c_sigma) * self.path_sigma[i] + sigma_factor * whitened;
+        }
+        self.generation += 1;
+        let path_sigma_norm = self.path_sigma.iter().map(|x| x * x).sum::<f64>().sqrt();
+        let stalled = path_sigma_norm / (1.0 - (1.0 - self.c_sigma).powi(2 * self.generation)).sqrt()
+            >= (1.4 + 2.0 / (n + 1.0)) * self.chi_n;
+        let h_sigma = if stalled { 0.0 } else { 1.0 };
+
+        // Covariance path and rank-one plus rank-mu update
+        let c_factor = (self.c_c * (2.0 - self.c_c) * self.mu_eff).sqrt();
+        for (path, step) in self.path_c.iter_mut().zip(&weighted_step) {
+            *path = (1.0 - self.c_c) * *path + h_sigma * c_factor * step;
+        }
+        let decay = 1.0 - self.c_1 - self.c_mu + (1.0 - h_sigma) * self.c_1 * self.c_c * (2.0 - self.c_c);
+        for i in 0..dimension {
+            for j in 0..dimension {
+                let rank_mu: f64 = steps
+                    .iter()
+                    .zip(&self.recombination_weights)
+                    .map(|(step, weight)| weight * step[i] * step[j])
+                    .sum();
+                self.covariance[i][j] = decay * self.covariance[i][j]
+                    + self.c_1 * self.path_c[i] * self.path_c[j]
+                    + self.c_mu * rank_mu;
+            }
+        }
+        self.step_size *= ((self.c_sigma / self.d_sigma) * (path_sigma_norm / self.chi_n - 1.0)).exp();
+
+        let (eigenvalues, eigenvectors) = symmetric_eigen(&self.covariance);
+        self.scales = eigenvalues.iter().map(|value| value.max(1e-20).sqrt()).collect();
+        self.basis = eigenvectors;
+    }
+}
+
+// Eigenvalues and eigenvectors (as columns) of a small symmetric matrix, with the cyclic Jacobi method
+fn symmetric_eigen(matrix: &[Vec<f64>]) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = matrix.len();
+    let mut a = matrix.to_vec();
+    let mut vectors: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect();
+    for _ in 0..100 {
+        let off_diagonal: f64 = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i][j] * a[i][j])
+            .sum();
+        if off_diagonal < 1e-30 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                if a[p][q].abs() < 1e-300 {
+                    continue;
+                }
+                // Rotation zeroing a[p][q]
+                let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                for row in a.iter_mut() {
+                    let (akp, akq) = (row[p], row[q]);
+                    row[p] = c * akp - s * akq;
+                    row[q] = s * akp + c * akq;
+                }
+                let (row_p, row_q) = (a[p].clone(), a[q].clone());
+                for k in 0..n {
+                    a[p][k] = c * row_p[k] - s * row_q[k];
+                    a[q][k] = s * row_p[k] + c * row_q[k];
+                }
+                for row in vectors.iter_mut() {
+                    let (vkp, vkq) = (row[p], row[q]);
+                    row[p] = c * vkp - s * vkq;
+                    row[q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+    ((0..n).map(|i| a[i][i]).collect(), vectors)
+}
+
+// Score of a whole expectimax game played with these evaluation weights
+pub fn expectimax_game_score(fast: &FastGame, weights: &EvaluationWeights, search_depth: usize, seed: u64) -> u32 {
+    let rand = Random::from_seed(Seed::unsafe_new(seed));
+    let mut game_state = [0; 4];
+    game_state = fast.add_random_block(game_state, &rand);
+    game_state = fast.add_random_block(game_state, &rand);
+    let mut score = 0;
+    while !fast.is_lost(&game_state) {
+        let direction = minimax::get_best_direction_expectimax(fast, game_state, search_depth, weights);
+        if direction == game::Direction::None {
+            break;
+        }
+        let (new_game_state, move_score) = fast.make_move(&game_state, &direction);
+        score += move_score;
+        game_state = fast.add_random_block(new_game_state, &rand);
+    }
+    score
+}
+
+// Mean score of the weights over the generation's games, every candidate of a generation playing the same games
+pub fn evaluation_weights_fitness(fast: &FastGame, weights: &EvaluationWeights, config: &CmaConfig, generation: u64) -> f64 {
+    let total: u64 = (0..config.games)
+        .into_par_iter()
+        .map(|game| {
+            let seed = generation * config.games as u64 + game as u64;
+            expectimax_game_score(fast, weights, config.search_depth, seed) as u64
+        })
+        .sum();
+    total as f64 / config.games.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::InputEncoding;
+    use crate::neural_network::Activation;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    // Runs CMA-ES maximizing the function from the start, returns the best candidate of the last generation
+    fn maximize(function: fn(&[f64]) -> f64, start: Vec<f64>, generations: usize) -> Vec<f64> {
+        let mut cma = CmaEs::new(start, 0.5, 0);
+        let mut rng = SmallRng::seed_from_u64(1);
+        let mut best = Vec::new();
+        for _ in 0..generations {
+            let candidates = cma.ask(&mut rng);
+            let fitness: Vec<f64> = candidates.iter().map(|candidate| function(candidate)).collect();
+            let best_index = (0..fitness.len()).max_by(|&a, &b| fitness[a].total_cmp(&fitness[b])).unwrap();
+            best = candidates[best_index].clone();
+            cma.tell(&candidates, &fitness);
+        }
+        best
+    }
+
+    #[test]
+    fn symmetric_eigen_diagonalizes() {
+        let matrix = vec![
+            vec![4.0, 1.0, -2.0, 0.5],
+            vec![1.0, 3.0, 0.0, 1.5],
+            vec![-2.0, 0.0, 5.0, -1.0],
+            vec![0.5, 1.5, -1.0, 2.0],
+        ];
+        let (values, vectors) = symmetric_eigen(&matrix);
+        for i in 0..4 {
+            for j in 0..4 {
+                // The eigenvectors are orthonormal and B diag(values) B^T gives back the matrix
+                let dot: f64 = (0..4).map(|k| vectors[k][i] * vectors[k][j]).sum();
+                assert!((dot - if i == j { 1.0 } else { 0.0 }).abs() < 1e-9);
+                let rebuilt: f64 = (0..4).map(|k| vectors[i][k] * values[k] * vectors[j][k]).sum();
+                assert!((rebuilt - matrix[i][j]).abs() < 1e-9, "{} instead of {}", rebuilt, matrix[i][j]);
+            }
+        }
+    }
+
+    #[test]
+    fn cma_es_converges_on_a_sphere() {
+        let sphere = |x: &[f64]| -x.iter().enumerate().map(|(i, x)| (x - i as f64).powi(2)).sum::<f64>();
+        let best = maximize(sphere, vec![5.0; 6], 300);
+        for (i, x) in best.iter().enumerate() {
+            assert!((x - i as f64).abs() < 1e-3, "{:?}", best);
+        }
+    }
+
+    // The curved valley of the Rosenbrock function needs the covariance to adapt
+    #[test]
+    fn cma_es_converges_on_rosenbrock() {
+        let rosenbrock = |x: &[f64]| {
+            -x.windows(2).map(|pair| 100.0 * (pair[1] - pair[0] * pair[0]).powi(2) + (1.0 - pair[0]).powi(2)).sum::<f64>()
+        };
+        let best = maximize(rosenbrock, vec![-1.0; 4], 1000);
+        for x in &best {
+            assert!((x - 1.0).abs() < 1e-2, "{:?}", best);
+        }
+    }
+
+    #[test]
+    fn open_ai_es_regenerates_antithetic_perturbations() {
+        let encoding = InputEncoding::default();
+        let mean = NeuralNetwork::new(vec![16, 4], vec![Activation::Linear], encoding, (-1.0, 1.0), (-1.0, 1.0));
+        let len = mean.weights.len() + mean.bias.len();
+        let es = OpenAiEs::new(mean, EsConfig::default());
+        let noise = perturbation(3, 7, 2, len);
+        // The update regenerates each pair's noise from the seeds instead of storing it
+        assert_eq!(noise, perturbation(3, 7, 2, len));
+        assert_ne!(noise, perturbation(3, 7, 3, len));
+        assert_ne!(noise, perturbation(3, 8, 2, len));
+        let (positive, negative) = (es.perturbed(&noise, 1.0), es.perturbed(&noise, -1.0));
+        let parameters = |network: &NeuralNetwork| network.weights.iter().chain(&network.bias).copied().collect::<Vec<f32>>();
+        let mean = parameters(&es.mean);
+        for (((positive, negative), mean), noise) in parameters(&positive).iter().zip(parameters(&negative)).zip(mean).zip(&noise) {
+            assert!((positive - mean - es.config.noise_std * noise).abs() < 1e-5);
+            assert!((positive + negative - 2.0 * mean).abs() < 1e-5);
+        }
+    }
+}
@@ -4,8 +4,14 @@
 use seeded_random::Random;
 use crate::game;
 
-const MAX_BLOCK_EXPONENT: u32 = 17;
-const TABLE_SIZE: u32 = (MAX_BLOCK_EXPONENT<<15) + (MAX_BLOCK_EXPONENT<<10) + (MAX_BLOCK_EXPONENT<<5) + (MAX_BLOCK_EXPONENT) + 1;
+// Highest exponent a 5 bits cell can hold, 2^31.
+// Overflow policy: two tiles of this exponent cannot merge (the result would not fit in a cell), they behave like
+// two different tiles. Scores saturate at u32::MAX
+pub const MAX_BLOCK_EXPONENT: u32 = 31;
+// Every row of 4 cells
+const TABLE_SIZE: u32 = 1 << 20;
+// The bits of a row above its 4 cells are not part of the board: moves ignore and clear them
+const ROW_MASK: u32 = TABLE_SIZE - 1;
 
 #[derive(Copy, Clone, Debug)]
 struct Result {
@@ -18,6 +24,12 @@ pub struct FastGame {
     table: Box<[Result]>,
 }
 
+impl Default for FastGame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FastGame {
     pub fn new() -> FastGame {
         FastGame {
@@ -25,41 +37,7 @@ impl FastGame {
         }
     }
     fn compute_left_move_table() -> Box<[Result]> {
-        let mut table = vec![
-            Result {
-                new_state: 0,
-                changed: false,
-                score: 0,
-            }; 
-            TABLE_SIZE as usize
-        ].into_boxed_slice();
-        let mut a: u32 = 0;
-
-        while a <= MAX_BLOCK_EXPONENT {
-            let mut b: u32 = 0;
-            while b <= MAX_BLOCK_EXPONENT {
-                let mut c: u32 = 0;
-                while c <= MAX_BLOCK_EXPONENT {
-                    let mut d: u32 = 0;
-                    while d <= MAX_BLOCK_EXPONENT {
-                        let row: u32 = (a << 15) | (b << 10) | (c << 5) | d;
-                        if row < TABLE_SIZE as u32 {
-                            // Safety check in case we exceed table size
-                            table[row as usize] = Self::compute_move_left(row);
-                        }
-                        else {
-                            println!("Error: row {} out of bounds", row);
-                            break;
-                        }
-                        d += 1;
-                    }
-                    c += 1;
-                }
-                b += 1;
-            }
-            a += 1;
-        }
-        table
+        (0..TABLE_SIZE).map(Self::compute_move_left).collect()
     }
 
     fn compute_move_left(row: u32) -> Result {
@@ -76,9 +54,9 @@ impl FastGame {
                 row_array[i] = 0;
                 continue;
             }
-            if row_array[target] == row_array[i] {
+            if row_array[target] == row_array[i] && row_array[i] < MAX_BLOCK_EXPONENT {
                 row_array[target] += 1;
-                score += 1 << row_array[target];
+                score = score.saturating_add(2u32.saturating_pow(row_array[target]));
                 row_array[i] = 0;
                 target += 1;
             } else {
@@ -98,13 +76,18 @@ impl FastGame {
         }
     }
 
+    // Whether the row only holds its 4 cells
+    pub fn is_valid_row(row: u32) -> bool {
+        row & !ROW_MASK == 0
+    }
+
     // Implementation of the game logic
 
     fn move_row_left(&self, row: u32) -> (u32, u32) {
-        let result = self.table[row as usize];
+        let result = self.table[(row & ROW_MASK) as usize];
 
         if !result.changed {
-            return (row, 0);
+            return (row & ROW_MASK, 0);
         }
 
         (result.new_state, result.score)
@@ -139,26 +122,26 @@ impl FastGame {
 
     #[inline]
     pub fn move_grid_left(&self, grid: &[u32; 4]) -> ([u32; 4], u32) {
-        let r0 = self.table[grid[0] as usize];
-        let r1 = self.table[grid[1] as usize];
-        let r2 = self.table[grid[2] as usize];
-        let r3 = self.table[grid[3] as usize];
+        let r0 = self.table[(grid[0] & ROW_MASK) as usize];
+        let r1 = self.table[(grid[1] & ROW_MASK) as usize];
+        let r2 = self.table[(grid[2] & ROW_MASK) as usize];
+        let r3 = self.table[(grid[3] & ROW_MASK) as usize];
         
         (
             [r0.new_state, r1.new_state, r2.new_state, r3.new_state],
-            r0.score + r1.score + r2.score + r3.score
+            r0.score.saturating_add(r1.score).saturating_add(r2.score).saturating_add(r3.score)
         )
     }
 
     #[inline]
     pub fn move_grid_right(&self, grid: &[u32; 4]) -> ([u32; 4], u32) {
         let mut new_grid = [0; 4];
-        let mut score = 0;
+        let mut score: u32 = 0;
 
         for i in 0..4 {
             let (new_row, row_score) = self.move_row_right(grid[i]);
             new_grid[i] = new_row;
-            score += row_score;
+            score = score.saturating_add(row_score);
         }
         (new_grid, score)
     }
@@ -180,13 +163,13 @@ impl FastGame {
 
     pub fn move_grid_up(&self, grid: &[u32; 4]) -> ([u32; 4], u32) {
         let mut new_grid = [0; 4];
-        let mut score = 0;
+        let mut score: u32 = 0;
 
         for i in 0..4 {
             let column = Self::extract_column(grid, i);
             let (new_column, column_score) = self.move_row_left(column);
             Self::update_column(&mut new_grid, i, new_column);
-            score += column_score;
+            score = score.saturating_add(column_score);
         }
 
         (new_grid, score)
@@ -194,13 +177,13 @@ impl FastGame {
 
     pub fn move_grid_down(&self, grid: &[u32; 4]) -> ([u32; 4], u32) {
         let mut new_grid = [0; 4];
-        let mut score = 0;
+        let mut score: u32 = 0;
 
         for i in 0..4 {
             let column = FastGame::extract_column(grid, i);
             let (new_column, column_score) = self.move_row_right(column);
             Self::update_column(&mut new_grid, i, new_column);
-            score += column_score;
+            score = score.saturating_add(column_score);
         }
 
         (new_grid, score)
@@ -208,7 +191,7 @@ impl FastGame {
     #[inline]
     fn can_go_left(&self, grid: &[u32; 4]) -> bool {
         for i in 0..4 {
-            if self.table[grid[i] as usize].changed {
+            if self.table[(grid[i] & ROW_MASK) as usize].changed {
                 return true;
             }
         }
@@ -268,6 +251,16 @@ impl FastGame {
         directions
     }
     #[inline]
+    pub fn can_move(&self, grid: &[u32; 4], direction: &game::Direction) -> bool {
+        match direction {
+            game::Direction::Left => self.can_go_left(grid),
+            game::Direction::Right => self.can_go_right(grid),
+            game::Direction::Up => self.can_go_up(grid),
+            game::Direction::Down => self.can_go_down(grid),
+            game::Direction::None => false,
+        }
+    }
+    #[inline]
     pub fn make_move(&self, grid: &[u32; 4], direction: &game::Direction) -> ([u32; 4], u32) {
         let (new_grid, score) = match direction {
             game::Direction::Left => self.move_grid_left(grid),
@@ -326,6 +319,52 @@ impl FastGame {
         return flat;
     }
 
+    pub fn from_flat_array(flat: [u8; 16]) -> [u32;4] {
+        let mut grid = [0; 4];
+        for i in 0..4 {
+            for j in 0..4 {
+                grid[i] |= (flat[i * 4 + j] as u32) << ((3-j) * 5);
+            }
+        }
+        return grid;
+    }
+
+    // The 8 symmetries of the grid (bit 0: mirror columns, bit 1: mirror rows, bit 2: transpose), the first one being the identity
+    pub fn symmetries(grid: [u32;4]) -> [[u32;4]; 8] {
+        let flat = Self::to_flat_array(grid);
+        core::array::from_fn(|symmetry| {
+            let mut transformed = [0; 16];
+            for i in 0..4 {
+                for j in 0..4 {
+                    let (mut row, mut col) = if symmetry & 4 != 0 { (j, i) } else { (i, j) };
+                    if symmetry & 1 != 0 {
+                        col = 3 - col;
+                    }
+                    if symmetry & 2 != 0 {
+                        row = 3 - row;
+                    }
+                    transformed[i * 4 + j] = flat[row * 4 + col];
+                }
+            }
+            Self::from_flat_array(transformed)
+        })
+    }
+
+    // Position (as in empty_list) on the transformed grid FastGame::symmetries(grid)[symmetry] of the cell at pos
+    pub fn symmetric_position(pos: (usize, usize), symmetry: usize) -> (usize, usize) {
+        // Mark every cell with its flat index and see where the symmetry moves the mark of pos
+        let marks = Self::from_flat_array(core::array::from_fn(|i| i as u8 + 1));
+        let transformed = Self::to_flat_array(Self::symmetries(marks)[symmetry]);
+        let source = pos.0 * 4 + 3 - pos.1;
+        let target = transformed.iter().position(|&mark| mark as usize == source + 1).unwrap();
+        (target / 4, 3 - target % 4)
+    }
+
+    // A representative shared by every grid equal up to a symmetry
+    pub fn canonical(grid: [u32;4]) -> [u32;4] {
+        return Self::symmetries(grid).into_iter().min().unwrap();
+    }
+
     pub fn play_move(&self, mut grid: [u32; 4], direction: game::Direction, rand: &Random) -> ([u32; 4],u32) {
         if direction == game::Direction::None {
             return (grid,0);
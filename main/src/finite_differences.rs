@@ -0,0 +1,15 @@
+// Helpers shared by the tests comparing analytic gradients with central differences
+use crate::neural_network::NeuralNetwork;
+
+// Step of the central differences, and tolerance of their comparison with the analytic gradients
+pub(crate) const EPSILON: f32 = 1e-3;
+
+pub(crate) fn assert_close(analytic: f32, numerical: f32, what: &str) {
+    let tolerance = 2e-3 + 1e-2 * analytic.abs().max(numerical.abs());
+    assert!((analytic - numerical).abs() <= tolerance, "{}: analytic {} numerical {}", what, analytic, numerical);
+}
+
+// The i-th parameter, every weight coming before the bias
+pub(crate) fn parameter(network: &mut NeuralNetwork, i: usize) -> &mut f32 {
+    network.weights.iter_mut().chain(network.bias.iter_mut()).nth(i).unwrap()
+}
@@ -0,0 +1,194 @@
+// Fitness of a game played by a neural agent, defined by the training config
+use crate::config::Config;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FitnessKind {
+    // Sum of the weighted terms of the config
+    Weighted,
+    Score,
+    // Value of the highest tile reached
+    MaxTile,
+    // ln(1 + score), which keeps a few lucky games from dominating the mean
+    LogScore,
+}
+
+impl std::str::FromStr for FitnessKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weighted" => Ok(FitnessKind::Weighted),
+            "score" => Ok(FitnessKind::Score),
+            "max_tile" => Ok(FitnessKind::MaxTile),
+            "log_score" => Ok(FitnessKind::LogScore),
+            _ => Err("expected weighted, score, max_tile or log_score".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for FitnessKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FitnessKind::Weighted => write!(f, "weighted"),
+            FitnessKind::Score => write!(f, "score"),
+            FitnessKind::MaxTile => write!(f, "max_tile"),
+            FitnessKind::LogScore => write!(f, "log_score"),
+        }
+    }
+}
+
+// Statistics of a whole game, summed over its moves where it makes sense
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GameStatistics {
+    pub move_number: i32,
+    // Exponent of the highest tile
+    pub max_tile: i32,
+    pub total_score: i32,
+    pub total_empty: i32,
+    pub total_smoothness: i32,
+    pub total_monotonicity: i32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FitnessConfig {
+    pub kind: FitnessKind,
+    // Weights of the terms of the weighted fitness, the per-move averages being used for the board terms
+    pub max_tile_weight: f32,
+    pub score_weight: f32,
+    pub empty_cells_weight: f32,
+    pub smoothness_weight: f32,
+    pub monotonicity_weight: f32,
+    pub moves_weight: f32,
+}
+
+impl Default for FitnessConfig {
+    fn default() -> Self {
+        FitnessConfig {
+            kind: FitnessKind::Weighted,
+            max_tile_weight: 10.0,
+            score_weight: 1.0,
+            empty_cells_weight: 0.5,
+            smoothness_weight: -0.1,
+            monotonicity_weight: 0.5,
+            moves_weight: -0.05,
+        }
+    }
+}
+
+impl FitnessConfig {
+    // Reads the fitness keys of a training config
+    pub fn from_config(config: &Config) -> Result<FitnessConfig, String> {
+        let default = FitnessConfig::default();
+        Ok(FitnessConfig {
+            kind: config.get("fitness", default.kind)?,
+            max_tile_weight: config.get("fitness_max_tile", default.max_tile_weight)?,
+            score_weight: config.get("fitness_score", default.score_weight)?,
+            empty_cells_weight: config.get("fitness_empty_cells", default.empty_cells_weight)?,
+            smoothness_weight: config.get("fitness_smoothness", default.smoothness_weight)?,
+            monotonicity_weight: config.get("fitness_monotonicity", default.monotonicity_weight)?,
+            moves_weight: config.get("fitness_moves", default.moves_weight)?,
+        })
+    }
+
+    // The fitness keys in the format read by from_config
+    pub fn to_config_string(self) -> String {
+        format!(
+            "fitness = {}\nfitness_max_tile = {}\nfitness_score = {}\nfitness_empty_cells = {}\n\
+             fitness_smoothness = {}\nfitness_monotonicity = {}\nfitness_moves = {}\n",
+            self.kind,
+            self.max_tile_weight,
+            self.score_weight,
+            self.empty_cells_weight,
+            self.smoothness_weight,
+            self.monotonicity_weight,
+            self.moves_weight
+        )
+    }
+
+    pub fn evaluate(&self, statistics: &GameStatistics) -> f32 {
+        match self.kind {
+            FitnessKind::Score => statistics.total_score as f32,
+            FitnessKind::MaxTile => (1u64 << statistics.max_tile.clamp(0, 63)) as f32,
+            FitnessKind::LogScore => (statistics.total_score.max(0) as f32).ln_1p(),
+            FitnessKind::Weighted => {
+                // A game lost before its first move has no per-move averages
+                let moves = statistics.move_number.max(1) as f32;
+                self.max_tile_weight * statistics.max_tile as f32
+                    + self.score_weight * statistics.total_score as f32
+                    + self.empty_cells_weight * statistics.total_empty as f32 / moves
+                    + self.smoothness_weight * statistics.total_smoothness as f32 / moves
+                    + self.monotonicity_weight * statistics.total_monotonicity as f32 / moves
+                    + self.moves_weight * statistics.move_number as f32
+            }
+        }
+    }
+}
+
+// Geometric mean which stays defined for zero or negative values: the mean is taken on sign(x) * ln(1 + |x|),
+// which grows with x for every x, and turned back into a value the same way. The offset being the same for every
+// list of values, a list which is better value by value always has a higher mean
+pub fn geometric_mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mean_logarithm = values.iter().map(|&x| x.signum() * x.abs().ln_1p()).sum::<f32>() / values.len() as f32;
+    mean_logarithm.signum() * mean_logarithm.abs().exp_m1()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometric_mean_is_monotone_and_finite() {
+        let lists: [&[f32]; 6] = [&[0.0, 100.0], &[-5.0, -1.0], &[-3.0, 0.0, 2.0], &[0.0, 0.0], &[1.0, 4.0, 16.0], &[-1000.0, 1000.0]];
+        for values in lists {
+            let mean = geometric_mean(values);
+            assert!(mean.is_finite(), "{:?}", values);
+            for i in 0..values.len() {
+                // Raising any value, even slightly above 0, raises the mean
+                for raise in [0.001, 1.0, 100.0] {
+                    let mut raised = values.to_vec();
+                    raised[i] += raise;
+                    assert!(geometric_mean(&raised) > mean, "{:?} against {:?}", raised, values);
+                }
+            }
+        }
+        // Positive values keep the usual geometric mean, up to the offset of 1
+        assert!((geometric_mean(&[0.0, 8.0]) - 2.0).abs() < 1e-5);
+        assert!((geometric_mean(&[-3.0, 3.0])).abs() < 1e-6);
+        assert_eq!(geometric_mean(&[]), 0.0);
+    }
+
+    #[test]
+    fn fitness_follows_the_config() {
+        let statistics = GameStatistics {
+            move_number: 4,
+            max_tile: 7,
+            total_score: 500,
+            total_empty: 20,
+            total_smoothness: -8,
+            total_monotonicity: 12,
+        };
+        let config = FitnessConfig {
+            kind: FitnessKind::Weighted,
+            max_tile_weight: 2.0,
+            score_weight: 0.5,
+            empty_cells_weight: 1.0,
+            smoothness_weight: 0.25,
+            monotonicity_weight: -1.0,
+            moves_weight: 3.0,
+        };
+        // 2 * 7 + 0.5 * 500 + 20 / 4 + 0.25 * -8 / 4 - 12 / 4 + 3 * 4
+        assert_eq!(config.evaluate(&statistics), 277.5);
+        let evaluate = |kind| FitnessConfig { kind, ..config }.evaluate(&statistics);
+        assert_eq!(evaluate(FitnessKind::Score), 500.0);
+        assert_eq!(evaluate(FitnessKind::MaxTile), 128.0);
+        assert!((evaluate(FitnessKind::LogScore) - 501f32.ln()).abs() < 1e-5);
+        // A game lost before its first move keeps a finite fitness
+        assert!(config.evaluate(&GameStatistics::default()).is_finite());
+
+        let loaded = FitnessConfig::from_config(&Config::parse(&config.to_config_string()).unwrap()).unwrap();
+        assert_eq!(loaded.to_config_string(), config.to_config_string());
+        assert!(FitnessConfig::from_config(&Config::parse("fitness = best\n").unwrap()).is_err());
+    }
+}
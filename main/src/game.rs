@@ -2,6 +2,7 @@ use std::fmt::Display;
 use std::fmt::Formatter;
 use std::fmt;
 use seeded_random::Random;
+use crate::fastgame::MAX_BLOCK_EXPONENT;
 
 const GRID_SIZE: usize = 4;
 
@@ -25,6 +26,22 @@ impl Clone for Direction {
         }
     }
 }
+// Order of the directions in the outputs of the networks
+pub const DIRECTIONS: [Direction; 4] = [Direction::Up, Direction::Down, Direction::Left, Direction::Right];
+
+impl Direction {
+    // Index of the direction in DIRECTIONS
+    pub fn index(&self) -> usize {
+        match self {
+            Self::Up => 0,
+            Self::Down => 1,
+            Self::Left => 2,
+            Self::Right => 3,
+            Self::None => panic!("Direction::None has no index"),
+        }
+    }
+}
+
 impl Display for Direction {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         let direction = match self {
@@ -51,9 +68,10 @@ fn move_left_single(row: &mut [u8; GRID_SIZE]) -> i32 {
             row[i] = 0;
             continue;
         }
-        if row[target as usize] == row[i] {
+        // Like in FastGame, tiles of the highest exponent do not merge, and scores saturate (at i32::MAX here)
+        if row[target as usize] == row[i] && (row[i] as u32) < MAX_BLOCK_EXPONENT {
             row[target as usize] += 1;
-            score += 1 << row[target as usize];
+            score = score.saturating_add(2i32.saturating_pow(row[target as usize] as u32));
             row[i] = 0;
             target += 1;
         } else {
@@ -67,34 +85,34 @@ fn move_left_single(row: &mut [u8; GRID_SIZE]) -> i32 {
     return score;
 }
 
-fn move_left(game_state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
+pub fn move_left(game_state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
     let mut score: i32 = 0;
     for row_chunk in game_state.chunks_exact_mut(GRID_SIZE) {
         let row_array: &mut [u8; GRID_SIZE] = row_chunk.try_into().unwrap();
-        score += move_left_single(row_array);
+        score = score.saturating_add(move_left_single(row_array));
     }
     return score;
 }
 
-fn move_right(game_state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
+pub fn move_right(game_state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
     let mut score: i32 = 0;
     for row_chunk in game_state.chunks_exact_mut(GRID_SIZE) {
         let row_array: &mut [u8; GRID_SIZE] = row_chunk.try_into().unwrap();
         row_array.reverse();
-        score += move_left_single(row_array);
+        score = score.saturating_add(move_left_single(row_array));
         row_array.reverse();
     }
     return score;
 }
 
-fn move_up(state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
+pub fn move_up(state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
     let mut score: i32 = 0;
     for col in 0..GRID_SIZE {
         let mut temp = [0; GRID_SIZE];
         for row in 0..GRID_SIZE {
             temp[row] = state[row * GRID_SIZE + col];
         }
-        score += move_left_single(&mut temp);
+        score = score.saturating_add(move_left_single(&mut temp));
         for row in 0..GRID_SIZE {
             state[row * GRID_SIZE + col] = temp[row];
         }
@@ -102,14 +120,14 @@ fn move_up(state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
     return score;
 }
 
-fn move_down(state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
+pub fn move_down(state: &mut [u8; GRID_SIZE * GRID_SIZE]) -> i32 {
     let mut score: i32 = 0;
     for col in 0..GRID_SIZE {
         let mut temp = [0; GRID_SIZE];
         for row in 0..GRID_SIZE {
             temp[row] = state[(GRID_SIZE - 1 - row) * GRID_SIZE + col];
         }
-        score += move_left_single(&mut temp);
+        score = score.saturating_add(move_left_single(&mut temp));
         for row in 0..GRID_SIZE {
             state[(GRID_SIZE - 1 - row) * GRID_SIZE + col] = temp[row];
         }
@@ -157,22 +175,6 @@ pub fn try_move(
     return score;
 }
 
-pub fn execute_move(
-    game_state: &mut [u8; GRID_SIZE * GRID_SIZE],
-    direction: Direction,
-    rand: &Random,
-) -> i32 {
-    let score = match direction {
-        Direction::Left => move_left(game_state),
-        Direction::Right => move_right(game_state),
-        Direction::Up => move_up(game_state),
-        Direction::Down => move_down(game_state),
-        Direction::None => -1,
-    };
-    add_block(game_state, rand);
-    return score;
-}
-
 pub fn add_block(game_state: &mut [u8; GRID_SIZE * GRID_SIZE], rand: &Random) {
     // Select which block is going to be placed (1 or 2 which corresponds to 2 or 4)
     let value: u8 = if rand.gen::<f32>() < 0.9 { 1 } else { 2 };
@@ -205,7 +207,7 @@ fn can_left_single(row: &[u8; GRID_SIZE]) -> bool {
             continue;
         }
         // If the two cells are the same, fusion is possible
-        if row[i] == row[i - 1] {
+        if row[i] == row[i - 1] && (row[i] as u32) < MAX_BLOCK_EXPONENT {
             return true;
         }
         // If the left cell is empty, moving is possible
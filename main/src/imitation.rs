@@ -0,0 +1,356 @@
+// Imitation learning: games played by a slow search (expectimax or MCTS) are recorded to a dataset, and a network
+// is fitted to the search's moves by backpropagation, which gives a fast policy distilled from the search
+use crate::config::Config;
+use crate::encoding::InputEncoding;
+use crate::fastgame::FastGame;
+use crate::game;
+use crate::mcts;
+use crate::minimax::{self, EvaluationWeights};
+use crate::neural_network::{Gradients, Initialization, NeuralNetwork};
+use crate::training::{self, Loss, Optimizer, OptimizerKind};
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use seeded_random::{Random, Seed};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const DATASET_HEADER: &str = "row0,row1,row2,row3,direction,up,down,left,right";
+
+// Search whose moves are recorded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Teacher {
+    Expectimax,
+    Mcts,
+}
+
+impl std::str::FromStr for Teacher {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "expectimax" => Ok(Teacher::Expectimax),
+            "mcts" => Ok(Teacher::Mcts),
+            _ => Err("expected expectimax or mcts".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImitationConfig {
+    // Dataset generation
+    pub teacher: Teacher,
+    pub games: usize,
+    pub search_depth: usize,
+    pub mcts_iterations: usize,
+    // Shape and initialization of new networks
+    pub hidden_layers: usize,
+    pub hidden_size: u32,
+    pub initialization: Initialization,
+    // Training
+    pub epochs: usize,
+    pub batch_size: usize,
+    // Cross-entropy fits the search's move, mse fits the search's values of the moves
+    pub loss: Loss,
+    pub optimizer: OptimizerKind,
+    pub learning_rate: f32,
+    pub momentum: f32,
+}
+
+impl Default for ImitationConfig {
+    fn default() -> Self {
+        ImitationConfig {
+            teacher: Teacher::Expectimax,
+            games: 20,
+            search_depth: 2,
+            mcts_iterations: 1000,
+            hidden_layers: 2,
+            hidden_size: 256,
+            initialization: Initialization::Uniform,
+            epochs: 10,
+            batch_size: 64,
+            loss: Loss::CrossEntropy,
+            optimizer: OptimizerKind::Sgd,
+            learning_rate: 0.01,
+            momentum: 0.0,
+        }
+    }
+}
+
+impl ImitationConfig {
+    pub fn load(path: &str) -> Result<ImitationConfig, String> {
+        let config = Config::load(path)?;
+        let default = ImitationConfig::default();
+        let imitation_config = ImitationConfig {
+            teacher: config.get("teacher", default.teacher)?,
+            games: config.get("games", default.games)?,
+            search_depth: config.get("search_depth", default.search_depth)?,
+            mcts_iterations: config.get("mcts_iterations", default.mcts_iterations)?,
+            hidden_layers: config.get("hidden_layers", default.hidden_layers)?,
+            hidden_size: config.get("hidden_size", default.hidden_size)?,
+            initialization: config.get("initialization", default.initialization)?,
+            epochs: config.get("epochs", default.epochs)?,
+            batch_size: config.get("batch_size", default.batch_size)?,
+            loss: config.get("loss", default.loss)?,
+            optimizer: config.get("optimizer", default.optimizer)?,
+            learning_rate: config.get("learning_rate", default.learning_rate)?,
+            momentum: config.get("momentum", default.momentum)?,
+        };
+        // Without iterations the MCTS has no move to record
+        if imitation_config.mcts_iterations == 0 {
+            return Err(format!("{}: mcts_iterations must be at least 1", path));
+        }
+        Ok(imitation_config)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub board: [u32; 4],
+    // Index in game::DIRECTIONS of the move played by the search
+    pub direction: usize,
+    // Search value of every move, f32::NEG_INFINITY for the impossible (or unexplored) ones
+    pub values: [f32; 4],
+}
+
+pub fn dataset_path(name: &str) -> String {
+    format!("datasets/{}.csv", name)
+}
+
+// Plays a full game with the teacher, returning one sample per move and the final score
+pub fn record_game(
+    fast: &FastGame,
+    config: &ImitationConfig,
+    weights: &EvaluationWeights,
+    mcts_config: mcts::MctsConfig,
+    seed: u64,
+) -> (Vec<Sample>, u32) {
+    let rand = Random::from_seed(Seed::unsafe_new(seed));
+    let mut game_state = [0; 4];
+    game_state = fast.add_random_block(game_state, &rand);
+    game_state = fast.add_random_block(game_state, &rand);
+    let mut game_score = 0;
+    let mut samples = Vec::new();
+    let mut tree = match config.teacher {
+        Teacher::Mcts => Some(mcts::MonteCarloTree::new(fast, game_state, mcts_config)),
+        Teacher::Expectimax => None,
+    };
+    while !fast.is_lost(&game_state) {
+        let (direction, values) = match tree.as_mut() {
+            Some(tree) => {
+                tree.grow_tree(fast, 0.0, config.mcts_iterations);
+                (tree.get_best_direction(), tree.get_move_values())
+            }
+            None => {
+                let values = minimax::expectimax_direction_values(fast, game_state, config.search_depth, weights);
+                let best = (0..4).max_by(|&a, &b| values[a].total_cmp(&values[b])).unwrap();
+                (game::DIRECTIONS[best].clone(), values)
+            }
+        };
+        samples.push(Sample { board: game_state, direction: direction.index(), values });
+        let (new_game_state, move_score) = fast.play_move(game_state, direction, &rand);
+        game_score += move_score;
+        game_state = new_game_state;
+        if let Some(tree) = tree.as_mut() {
+            tree.reroot(fast, move_score, game_state);
+        }
+    }
+    (samples, game_score)
+}
+
+// Records config.games games in parallel, the game i being played with the seed i
+pub fn generate_dataset(
+    fast: &FastGame,
+    config: &ImitationConfig,
+    weights: &EvaluationWeights,
+    mcts_config: mcts::MctsConfig,
+) -> Vec<Sample> {
+    let games: Vec<Vec<Sample>> = (0..config.games as u64)
+        .into_par_iter()
+        .map(|seed| {
+            let (samples, score) = record_game(fast, config, weights, mcts_config, seed);
+            println!("Game {} : score {}, {} moves", seed, score, samples.len());
+            samples
+        })
+        .collect();
+    games.concat()
+}
+
+pub fn save_dataset(path: &str, samples: &[Sample]) -> std::io::Result<()> {
+    if let Some(directory) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let mut contents = format!("{}\n", DATASET_HEADER);
+    for sample in samples {
+        let board: Vec<String> = sample.board.iter().map(|row| row.to_string()).collect();
+        let values: Vec<String> = sample.values.iter().map(|value| value.to_string()).collect();
+        contents += &format!("{},{},{}\n", board.join(","), sample.direction, values.join(","));
+    }
+    std::fs::write(path, contents)
+}
+
+pub fn load_dataset(path: &str) -> Result<Vec<Sample>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|error| format!("Could not read {} : {}", path, error))?;
+    parse_dataset(&contents, path)
+}
+
+// Reads the samples of a dataset written by save_dataset, name being used in the error messages
+pub fn parse_dataset(contents: &str, name: &str) -> Result<Vec<Sample>, String> {
+    let mut samples = Vec::new();
+    for (line_number, line) in contents.lines().enumerate().skip(1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = || format!("Line {} of {}: invalid sample \"{}\"", line_number + 1, name, line);
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 9 {
+            return Err(invalid());
+        }
+        let mut board = [0; 4];
+        for (row, field) in board.iter_mut().zip(&fields[..4]) {
+            *row = field.parse().map_err(|_| invalid())?;
+            if !FastGame::is_valid_row(*row) {
+                return Err(invalid());
+            }
+        }
+        let direction: usize = fields[4].parse().map_err(|_| invalid())?;
+        if direction >= 4 {
+            return Err(invalid());
+        }
+        let mut values = [0.0; 4];
+        for (value, field) in values.iter_mut().zip(&fields[5..]) {
+            *value = field.parse().map_err(|_| invalid())?;
+        }
+        samples.push(Sample { board, direction, values });
+    }
+    Ok(samples)
+}
+
+// Policy network with one output per direction, in game::DIRECTIONS order
+pub fn new_network(encoding: InputEncoding, config: &ImitationConfig) -> NeuralNetwork {
+    training::direction_network(encoding, config.hidden_layers, config.hidden_size, config.initialization)
+}
+
+// Targets of the network for a sample: the search's move for the cross-entropy, and for the squared error the
+// values of the possible moves relative to the best one (0 for the best move, -1 for a move worth nothing)
+fn targets(sample: &Sample, loss: Loss) -> [f32; 4] {
+    match loss {
+        Loss::CrossEntropy => {
+            let mut targets = [0.0; 4];
+            targets[sample.direction] = 1.0;
+            targets
+        }
+        Loss::MeanSquaredError => {
+            let best = sample.values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            sample.values.map(|value| {
+                if value == f32::NEG_INFINITY {
+                    f32::NAN
+                } else {
+                    (value - best) / best.abs().max(1.0)
+                }
+            })
+        }
+    }
+}
+
+// Adds the gradient of the loss between the network's outputs and the sample's targets, returns the loss and
+// whether the best possible move of the network is the search's
+fn add_sample_gradient(network: &NeuralNetwork, sample: &Sample, loss: Loss, gradients: &mut Gradients) -> (f32, bool) {
+    let mut outputs = [0.0; 4];
+    let targets = targets(sample, loss);
+    let loss = training::add_board_gradient(network, &sample.board, &targets, loss, &mut outputs, gradients);
+    let predicted = (0..4)
+        .filter(|&i| sample.values[i] > f32::NEG_INFINITY)
+        .max_by(|&a, &b| outputs[a].total_cmp(&outputs[b]));
+    (loss, predicted == Some(sample.direction))
+}
+
+// One pass over the samples in a random order, by mini-batches whose gradients are computed in parallel.
+// Returns the mean loss and the share of samples whose move the network predicted
+pub fn train_epoch(
+    network: &mut NeuralNetwork,
+    optimizer: &mut Optimizer,
+    samples: &[Sample],
+    config: &ImitationConfig,
+    epoch: u64,
+) -> (f32, f32) {
+    let mut order: Vec<usize> = (0..samples.len()).collect();
+    order.shuffle(&mut SmallRng::seed_from_u64(epoch));
+    let mut total_loss = 0.0;
+    let correct = AtomicUsize::new(0);
+    for batch in order.chunks(config.batch_size.max(1)) {
+        let (gradients, loss) = training::batch_gradients(network, batch, |&index, gradients| {
+            let (loss, predicted) = add_sample_gradient(network, &samples[index], config.loss, gradients);
+            if predicted {
+                correct.fetch_add(1, Ordering::Relaxed);
+            }
+            loss
+        });
+        total_loss += loss;
+        optimizer.step(network, &gradients, 1.0 / batch.len() as f32);
+    }
+    let count = samples.len().max(1) as f32;
+    (total_loss / count, correct.into_inner() as f32 / count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::CellEncoding;
+
+    fn expectimax_config() -> ImitationConfig {
+        ImitationConfig { search_depth: 1, hidden_layers: 1, hidden_size: 32, ..ImitationConfig::default() }
+    }
+
+    #[test]
+    fn recorded_games_round_trip_through_the_dataset() {
+        let fast = FastGame::new();
+        let (samples, score) = record_game(&fast, &expectimax_config(), &EvaluationWeights::default(), mcts::MctsConfig::default(), 3);
+        assert!(!samples.is_empty() && score > 0);
+        for sample in &samples {
+            // The recorded move is the best possible one
+            assert!(fast.can_move(&sample.board, &game::DIRECTIONS[sample.direction]));
+            assert!(sample.values.iter().all(|&value| value <= sample.values[sample.direction]));
+            for (direction, value) in game::DIRECTIONS.iter().zip(sample.values) {
+                assert_eq!(value == f32::NEG_INFINITY, !fast.can_move(&sample.board, direction));
+            }
+        }
+        let path = std::env::temp_dir().join(format!("dataset_round_trip_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        save_dataset(path, &samples).unwrap();
+        let loaded = load_dataset(path);
+        std::fs::remove_file(path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.len(), samples.len());
+        for (loaded, sample) in loaded.iter().zip(&samples) {
+            assert_eq!((loaded.board, loaded.direction, loaded.values), (sample.board, sample.direction, sample.values));
+        }
+    }
+
+    #[test]
+    fn invalid_samples_are_rejected() {
+        let line = |sample: &str| format!("{}\n{}\n", DATASET_HEADER, sample);
+        assert_eq!(parse_dataset(&line("0,0,0,33,2,1,2,3,-inf"), "valid").unwrap()[0].values[3], f32::NEG_INFINITY);
+        for sample in ["0,0,0,33,4,1,2,3,4", "0,0,0,33,2,1,2,3", "0,0,0,x,2,1,2,3,4", "0,0,0,1048576,2,1,2,3,4"] {
+            assert!(parse_dataset(&line(sample), "invalid").is_err(), "{}", sample);
+        }
+    }
+
+    #[test]
+    fn training_fits_the_search_moves() {
+        let fast = FastGame::new();
+        let config = ImitationConfig { optimizer: OptimizerKind::Adam, learning_rate: 0.005, batch_size: 16, ..expectimax_config() };
+        let (mut samples, _) = record_game(&fast, &config, &EvaluationWeights::default(), mcts::MctsConfig::default(), 5);
+        samples.truncate(60);
+        let encoding = InputEncoding { cells: CellEncoding::OneHot, symmetric: false };
+        let mut network = new_network(encoding, &config);
+        let mut optimizer = training::Optimizer::new(config.optimizer, config.learning_rate, config.momentum, &network);
+        let (first_loss, _) = train_epoch(&mut network, &mut optimizer, &samples, &config, 0);
+        let mut last = (first_loss, 0.0);
+        for epoch in 1..200 {
+            last = train_epoch(&mut network, &mut optimizer, &samples, &config, epoch);
+        }
+        let (loss, accuracy) = last;
+        assert!(loss < 0.2 * first_loss, "loss {} after {}", loss, first_loss);
+        assert!(accuracy > 0.95, "accuracy {}", accuracy);
+    }
+}
@@ -1,17 +1,13 @@
-mod fastgame;
-mod game;
-mod minimax;
-mod neural_network;
-mod population;
-mod renderer;
-mod mcts;
-use fastgame::FastGame;
+use main::fastgame::{self, FastGame};
+use main::{
+    alphazero, checkpoint, dqn, encoding, evolution, game, imitation, mcts, metrics, minimax, neural_network,
+    ntuple, population, renderer, training, GRID_SIZE,
+};
 use seeded_random::{Random, Seed};
 use std::path::Path;
 use rayon::prelude::*;
-const GRID_SIZE: usize = 4;
-
-const POPULATION_SIZE: usize = 2000;
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
 
 const SEED: u64 = 0;
 
@@ -22,8 +18,19 @@ const EXPECTIMAX_DEPTH: usize = 6;
 // Time limit for MCTS simulation in seconds
 const MCTS_MIN_TIME: f32 = 0.01;
 const MCTS_ITERATION_LIMIT: usize = 100;
+// Part of the tree written when exporting it : levels below the root, and most visited children kept per node
+const TREE_EXPORT_DEPTH: usize = 4;
+const TREE_EXPORT_CHILDREN: usize = 3;
+// Number of generations listed by the training report of a run
+const TRAINING_REPORT_ROWS: usize = 10;
 
 fn main() {
+    // "train --resume" resumes a training from its last checkpoint, "train" starts the training directly
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("train") {
+        train(args.iter().any(|arg| arg == "--resume"));
+        return;
+    }
     // Ask user to choose the mode
     println!("Choose a mode :");
     println!("1. Play");
@@ -35,13 +42,23 @@ fn main() {
     println!("7. Monte Carlo tree search");
     println!("8. Test MCTS optimization");
     println!("9. Test MCTS strength");
+    println!("11. Train AlphaZero MCTS (self-play)");
+    println!("12. AlphaZero MCTS");
+    println!("13. Train with evolution strategies (OpenAI-ES)");
+    println!("14. Tune the evaluation weights (CMA-ES)");
+    println!("15. Training report");
+    println!("16. Record an imitation dataset (expectimax or MCTS games)");
+    println!("17. Train a network by imitation");
+    println!("18. Train an n-tuple network (TD learning)");
+    println!("19. N-tuple network");
+    println!("20. Train a deep Q-network");
     let mut line = String::new();
     std::io::stdin().read_line(&mut line).unwrap();
     let line = line.trim();
     match line {
         "1" => play(),
         "2" => playfast(),
-        "3" => train(),
+        "3" => train(false),
         "4" => ai(),
         "5" => use_mini_expecti_max(true),
         "6" => use_mini_expecti_max(false),
@@ -49,6 +66,16 @@ fn main() {
         "8" => mcts_optimization_test(),
         "9" => mcts_strength_test(true),
         "10" => rollout_verification(),
+        "11" => train_alphazero(),
+        "12" => use_alphazero(),
+        "13" => train_evolution_strategies(),
+        "14" => tune_evaluation_weights(),
+        "15" => training_report(),
+        "16" => record_imitation_dataset(),
+        "17" => train_imitation(),
+        "18" => train_ntuple(),
+        "19" => use_ntuple(),
+        "20" => train_dqn(),
         _ => println!("Invalid mode"),
     }
 }
@@ -86,7 +113,9 @@ fn play() {
     }
 }
 
-fn train() {
+fn train(resume: bool) {
+    // When resuming, the GA config is the one saved in the checkpoint
+    let mut config = if resume { population::GaConfig::default() } else { ask_ga_config() };
     // Ask user for network name (if it exists load, else create)
     println!("Enter a network name :");
     let mut line = String::new();
@@ -94,29 +123,66 @@ fn train() {
     let line = line.trim();
     // Check if networks/line exists
     let path = format!("networks/{}.ntwk", line);
+    let checkpoint_directory = checkpoint::directory(line);
+    let name = line.to_string();
 
     // Load or create the population
     let mut gen_count: u64 = 1;
-    let mut population = if !Path::new(&path).exists() {
-        population::create_population(POPULATION_SIZE, 0)
+    let mut seed = SEED;
+    let mut history = Vec::new();
+    let mut best_ever: Option<(neural_network::NeuralNetwork, f32)> = None;
+    let mut best_validation: Option<(neural_network::NeuralNetwork, f32)> = None;
+    let mut population = if resume {
+        let state = match checkpoint::load(&checkpoint_directory) {
+            Ok(state) => state,
+            Err(error) => {
+                println!("Could not resume from {} : {}", checkpoint_directory, error);
+                return;
+            }
+        };
+        println!("Resuming at generation {} with {} agents", state.generation, state.networks.len());
+        config = state.config;
+        gen_count = state.generation;
+        seed = state.seed;
+        history = state.history;
+        best_ever = state.best_ever;
+        best_validation = state.best_validation;
+        let game_seed = state.game_seed;
+        state
+            .networks
+            .into_iter()
+            .map(|network| population::Agent::from(network, game_seed))
+            .collect()
+    } else if !Path::new(&path).exists() {
+        population::create_population(config.population_size, 0, ask_input_encoding())
     } else {
-        let (network, gen) = neural_network::NeuralNetwork::load(&path);
+        let (network, metadata) = match neural_network::NeuralNetwork::load(&path) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                println!("Could not load {} : {}", path, error);
+                return;
+            }
+        };
         // Print some info about the network
+        println!("Input encoding: {}", network.encoding());
         println!("Weights: {}", network.weights.len());
         println!("Biases: {}", network.bias.len());
-        gen_count = gen as u64;
+        gen_count = metadata.generation as u64;
         population::load_population(
-            POPULATION_SIZE,
-            gen as u64 * population::RUNS_PER_AGENT as u64,
+            config.population_size,
+            metadata.generation as u64 * population::RUNS_PER_AGENT as u64,
             network,
         )
     };
 
+    let validation_path = format!("networks/{}_validation.ntwk", name);
+    let fast = fastgame::FastGame::new();
+    let mut generation_start = std::time::Instant::now();
     loop {
         // Run the population
-        population::run_all(&mut population);
+        population::run_all(&mut population, &fast, &config.fitness);
         // Get the best agent
-        let mut best_score = 0.0;
+        let mut best_score = f32::NEG_INFINITY;
         let mut best_agent = 0;
         for i in 0..population.len() {
             if population[i].geometric_mean() >= best_score {
@@ -127,27 +193,113 @@ fn train() {
         let best_network = population[best_agent].neural_network.clone();
 
         // Save the best network
-        best_network.save(&path, gen_count as usize);
+        let metadata = neural_network::NetworkMetadata::new(gen_count as usize, best_score);
+        if let Err(error) = best_network.save(&path, &metadata) {
+            println!("Could not save {} : {}", path, error);
+        }
+
+        // Re-evaluate the best agents on the validation games, and keep the best of them separately when it beats
+        // every previous one, since the best agent on the training games is mostly the luckiest
+        let validation = population::validate_best(&mut population, &fast, &config);
+        if let Some((index, score)) = validation {
+            if best_validation.as_ref().is_none_or(|(_, best)| score > *best) {
+                let network = population[index].neural_network.clone();
+                let metadata = neural_network::NetworkMetadata::new(gen_count as usize, score);
+                if let Err(error) = network.save(&validation_path, &metadata) {
+                    println!("Could not save {} : {}", validation_path, error);
+                }
+                best_validation = Some((network, score));
+            }
+        }
+
+        // Log the generation's metrics (the wall time includes the creation of the generation)
+        let generation_metrics = metrics::GenerationMetrics::from_population(
+            gen_count,
+            &mut population,
+            &config,
+            generation_start.elapsed().as_secs_f32(),
+            validation.map(|(_, score)| score).unwrap_or(0.0),
+        );
+        generation_start = std::time::Instant::now();
+        let log_path = metrics::log_path(&name, config.metrics_format);
+        if let Err(error) = metrics::append(&log_path, config.metrics_format, &generation_metrics) {
+            println!("Could not write to {} : {}", log_path, error);
+        }
 
         // Print the best agent's score
         println!(
             "Generation {}: {}     Best block accross all games : {}",
             gen_count,
             best_score,
-            1 << population[best_agent].highest_tile
+            1u64 << population[best_agent].highest_tile
         );
-        // Create the next generation
-        population::clone_population(
-            &mut population,
-            best_network,
-            gen_count * population::RUNS_PER_AGENT as u64,
-            0.25,
-            0.5,
+        history.push((gen_count, best_score));
+        let recent: Vec<f32> = history
+            .iter()
+            .rev()
+            .take(metrics::SPARKLINE_WIDTH)
+            .rev()
+            .map(|(_, fitness)| *fitness)
+            .collect();
+        println!(
+            "Mean : {}     Median : {}     Progress : {}",
+            generation_metrics.mean_fitness,
+            generation_metrics.median_fitness,
+            metrics::sparkline(&recent)
         );
+        if let (Some((_, score)), Some((_, best))) = (validation, best_validation.as_ref()) {
+            println!("Validation : {}     Best by validation : {}", score, best);
+        }
+        if best_ever.as_ref().is_none_or(|(_, fitness)| best_score > *fitness) {
+            best_ever = Some((best_network, best_score));
+        }
+        // Create the next generation
+        population::next_generation(&mut population, &config, seed, gen_count);
+        if config.checkpoint_interval > 0 && gen_count.is_multiple_of(config.checkpoint_interval as u64) {
+            let best = best_ever.as_ref().map(|(network, fitness)| (network, *fitness));
+            let validated = best_validation.as_ref().map(|(network, score)| (network, *score));
+            if let Err(error) = checkpoint::save(
+                &checkpoint_directory,
+                gen_count + 1,
+                seed,
+                &config,
+                &population,
+                &history,
+                best,
+                validated,
+            ) {
+                println!("Could not save the checkpoint {} : {}", checkpoint_directory, error);
+            }
+        }
         gen_count += 1;
     }
 }
 
+fn training_report() {
+    // Ask user for the names of the runs to compare
+    println!("Enter the names of the trainings to compare, separated by spaces :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    for name in line.split_whitespace() {
+        let path = [metrics::MetricsFormat::Csv, metrics::MetricsFormat::JsonLines]
+            .iter()
+            .map(|&format| metrics::log_path(name, format))
+            .find(|path| Path::new(path).exists());
+        let Some(path) = path else {
+            println!("No log found for {}", name);
+            continue;
+        };
+        match metrics::load(&path) {
+            Ok(history) if !history.is_empty() => {
+                println!("{} ({} generations)", name, history.len());
+                println!("{}", metrics::summary_table(&history, TRAINING_REPORT_ROWS));
+            }
+            Ok(_) => println!("{} is empty", path),
+            Err(error) => println!("{}", error),
+        }
+    }
+}
+
 fn ai() {
     // Ask user for network name
     println!("Enter a network name :");
@@ -161,33 +313,42 @@ fn ai() {
         println!("Network not found");
         return;
     }
-    let (network, _) = neural_network::NeuralNetwork::load(&path);
+    let (network, _) = match neural_network::NeuralNetwork::load(&path) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            println!("Could not load {} : {}", path, error);
+            return;
+        }
+    };
     let mut agent = population::Agent::from(network, SEED);
+    let fast = fastgame::FastGame::new();
 
     let rand = Random::from_seed(Seed::unsafe_new(0));
-    game::add_block(&mut agent.game_state, &rand);
-    game::add_block(&mut agent.game_state, &rand);
-    renderer::render(agent.game_state);
+    agent.game_state = fast.add_random_block(agent.game_state, &rand);
+    agent.game_state = fast.add_random_block(agent.game_state, &rand);
+    renderer::render(FastGame::to_flat_array(agent.game_state));
     let mut total_score = 0;
     loop {
         // Wait for a bit
         std::thread::sleep(std::time::Duration::from_millis(100));
 
         // Get the direction from the neural network
-        let direction = agent.get_direction();
-        let score = game::try_move(&mut agent.game_state, direction, &rand);
-        if game::is_lost(&agent.game_state) {
-            renderer::render(agent.game_state);
+        let direction = agent.get_direction(&fast);
+        let (new_game_state, score) = fast.play_move(agent.game_state, direction, &rand);
+        agent.game_state = new_game_state;
+        if fast.is_lost(&agent.game_state) {
+            renderer::render(FastGame::to_flat_array(agent.game_state));
             println!("You lost !");
             break;
         }
-        renderer::render(agent.game_state);
+        renderer::render(FastGame::to_flat_array(agent.game_state));
         total_score += score;
         println!("Score: {}", total_score);
     }
 }
 
 fn use_mini_expecti_max(mini: bool) {
+    let weights = ask_config("an evaluation weights", minimax::EvaluationWeights::default(), minimax::EvaluationWeights::load);
     // Generate an empty grid
     let mut game_state = [0u32;4];
     // Compute the lookup table
@@ -204,9 +365,9 @@ fn use_mini_expecti_max(mini: bool) {
         // Get the best direction, play it, and add a random block
         let best_direction;
         if mini {
-            best_direction = minimax::get_best_direction_minimax(&fast, game_state, MINIMAX_DEPTH);
+            best_direction = minimax::get_best_direction_minimax(&fast, game_state, MINIMAX_DEPTH, &weights);
         } else {
-            best_direction = minimax::get_best_direction_expectimax(&fast, game_state, EXPECTIMAX_DEPTH);
+            best_direction = minimax::get_best_direction_expectimax(&fast, game_state, EXPECTIMAX_DEPTH, &weights);
         }
         if best_direction == game::Direction::None {
             println!("No possible move !");
@@ -266,7 +427,325 @@ fn playfast() {
     }
 }
 
+// Asked when creating a network, loaded networks keep the encoding they were trained with
+fn ask_input_encoding() -> encoding::InputEncoding {
+    println!("Enter an input encoding : scalar, one_hot or log_normalized, optionally prefixed by symmetric_ (leave empty for scalar) :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let line = line.trim();
+    if line.is_empty() {
+        return encoding::InputEncoding::default();
+    }
+    match line.parse() {
+        Ok(encoding) => encoding,
+        Err(error) => {
+            println!("{}, using scalar", error);
+            encoding::InputEncoding::default()
+        }
+    }
+}
+
+// Asks for the name of a config file in configs/ (if it exists load it, else use the default config)
+fn ask_config<T>(kind: &str, default: T, load: fn(&str) -> Result<T, String>) -> T {
+    println!("Enter {} config name (leave empty for default) :", kind);
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let line = line.trim();
+    if line.is_empty() {
+        return default;
+    }
+    let path = format!("configs/{}.cfg", line);
+    match load(&path) {
+        Ok(config) => config,
+        Err(error) => {
+            println!("{}, using the default config", error);
+            default
+        }
+    }
+}
+
+fn ask_ga_config() -> population::GaConfig {
+    ask_config("a GA", population::GaConfig::default(), population::GaConfig::load)
+}
+
+fn ask_mcts_config() -> mcts::MctsConfig {
+    ask_mcts_config_or(mcts::MctsConfig::default())
+}
+
+fn ask_mcts_config_or(default: mcts::MctsConfig) -> mcts::MctsConfig {
+    ask_config("an MCTS", default, mcts::MctsConfig::load)
+}
+
+// The network replaces the rollouts, so the search relies on PUCT and plays the most visited move
+fn alphazero_default_config() -> mcts::MctsConfig {
+    mcts::MctsConfig {
+        selection: mcts::SelectionFormula::Puct,
+        final_move: mcts::FinalMoveRule::MaxVisits,
+        exploration_constant: 1.5,
+        ..mcts::MctsConfig::default()
+    }
+}
+
+// Self-play explores with noise at the root and samples its first moves from the visits
+fn alphazero_training_config() -> mcts::MctsConfig {
+    mcts::MctsConfig {
+        root_noise_weight: 0.25,
+        root_noise_alpha: 0.5,
+        temperature_moves: 30,
+        temperature: 1.0,
+        ..alphazero_default_config()
+    }
+}
+
+fn train_alphazero() {
+    let config = ask_mcts_config_or(alphazero_training_config());
+    // Ask user for network name (if it exists load, else create)
+    println!("Enter a network name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let line = line.trim();
+    let path = format!("networks/{}.ntwk", line);
+
+    let mut gen_count: u64 = 1;
+    let mut network = if !Path::new(&path).exists() {
+        alphazero::new_network(ask_input_encoding())
+    } else {
+        let (network, metadata) = match neural_network::NeuralNetwork::load(&path) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                println!("Could not load {} : {}", path, error);
+                return;
+            }
+        };
+        gen_count = metadata.generation as u64 + 1;
+        network
+    };
+    let fast = fastgame::FastGame::new();
+    let mut replay = Vec::new();
+    let mut optimizer = alphazero::new_optimizer(&network);
+    loop {
+        let (average_score, loss) =
+            alphazero::train_generation(&fast, &mut network, &mut optimizer, config, &mut replay, gen_count);
+        if let Err(error) = network.save(&path, &neural_network::NetworkMetadata::new(gen_count as usize, average_score)) {
+            println!("Could not save {} : {}", path, error);
+        }
+        println!(
+            "Generation {}: average score {}     Loss : {}     Samples : {}",
+            gen_count, average_score, loss, replay.len()
+        );
+        gen_count += 1;
+    }
+}
+
+fn train_evolution_strategies() {
+    let config = ask_config("an ES", evolution::EsConfig::default(), evolution::EsConfig::load);
+    // Ask user for network name (if it exists load, else create)
+    println!("Enter a network name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let line = line.trim();
+    let path = format!("networks/{}.ntwk", line);
+
+    let mut gen_count: u64 = 1;
+    let network = if !Path::new(&path).exists() {
+        population::Agent::new(SEED, ask_input_encoding()).neural_network
+    } else {
+        let (network, metadata) = match neural_network::NeuralNetwork::load(&path) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                println!("Could not load {} : {}", path, error);
+                return;
+            }
+        };
+        gen_count = metadata.generation as u64 + 1;
+        network
+    };
+    let mut strategy = evolution::OpenAiEs::new(network, config);
+    loop {
+        let (mean_fitness, best_fitness) = strategy.step(SEED, gen_count);
+        let metadata = neural_network::NetworkMetadata::new(gen_count as usize, mean_fitness);
+        if let Err(error) = strategy.mean.save(&path, &metadata) {
+            println!("Could not save {} : {}", path, error);
+        }
+        println!(
+            "Generation {}: mean fitness {}     Best perturbation : {}",
+            gen_count, mean_fitness, best_fitness
+        );
+        gen_count += 1;
+    }
+}
+
+fn record_imitation_dataset() {
+    let config = ask_config("an imitation", imitation::ImitationConfig::default(), imitation::ImitationConfig::load);
+    let (weights, mcts_config) = match config.teacher {
+        imitation::Teacher::Expectimax => (
+            ask_config("an evaluation weights", minimax::EvaluationWeights::default(), minimax::EvaluationWeights::load),
+            mcts::MctsConfig::default(),
+        ),
+        imitation::Teacher::Mcts => (minimax::EvaluationWeights::default(), ask_mcts_config()),
+    };
+    println!("Enter a dataset name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let path = imitation::dataset_path(line.trim());
+
+    let fast = fastgame::FastGame::new();
+    let samples = imitation::generate_dataset(&fast, &config, &weights, mcts_config);
+    match imitation::save_dataset(&path, &samples) {
+        Ok(()) => println!("Saved {} samples to {}", samples.len(), path),
+        Err(error) => println!("Could not save {} : {}", path, error),
+    }
+}
+
+fn train_imitation() {
+    let config = ask_config("an imitation", imitation::ImitationConfig::default(), imitation::ImitationConfig::load);
+    println!("Enter a dataset name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let dataset_path = imitation::dataset_path(line.trim());
+    let samples = match imitation::load_dataset(&dataset_path) {
+        Ok(samples) => samples,
+        Err(error) => {
+            println!("{}", error);
+            return;
+        }
+    };
+    println!("{} samples", samples.len());
+    // Ask user for network name (if it exists load, else create)
+    println!("Enter a network name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let path = format!("networks/{}.ntwk", line.trim());
+
+    let mut epoch: u64 = 1;
+    let mut network = if !Path::new(&path).exists() {
+        imitation::new_network(ask_input_encoding(), &config)
+    } else {
+        let (network, metadata) = match neural_network::NeuralNetwork::load(&path) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                println!("Could not load {} : {}", path, error);
+                return;
+            }
+        };
+        epoch = metadata.generation as u64 + 1;
+        network
+    };
+    let mut optimizer = training::Optimizer::new(config.optimizer, config.learning_rate, config.momentum, &network);
+    for _ in 0..config.epochs {
+        let (loss, accuracy) = imitation::train_epoch(&mut network, &mut optimizer, &samples, &config, epoch);
+        // The fitness saved along with the network is its accuracy
+        let metadata = neural_network::NetworkMetadata::new(epoch as usize, accuracy);
+        if let Err(error) = network.save(&path, &metadata) {
+            println!("Could not save {} : {}", path, error);
+        }
+        println!("Epoch {}: loss {}     Accuracy : {:.1}%", epoch, loss, accuracy * 100.0);
+        epoch += 1;
+    }
+}
+
+fn tune_evaluation_weights() {
+    let config = ask_config("a CMA-ES", evolution::CmaConfig::default(), evolution::CmaConfig::load);
+    // Ask user for the name of the weights file (if it exists start from it, else from the default weights)
+    println!("Enter an evaluation weights name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let line = line.trim();
+    let path = format!("configs/{}.cfg", line);
+    let initial_weights = if Path::new(&path).exists() {
+        match minimax::EvaluationWeights::load(&path) {
+            Ok(weights) => weights,
+            Err(error) => {
+                println!("{}", error);
+                return;
+            }
+        }
+    } else {
+        minimax::EvaluationWeights::default()
+    };
+
+    let fast = fastgame::FastGame::new();
+    let mean = initial_weights.to_array().iter().map(|&weight| weight as f64).collect();
+    let mut strategy = evolution::CmaEs::new(mean, config.initial_step, config.population_size);
+    let mut rng = SmallRng::seed_from_u64(SEED);
+    let mut gen_count: u64 = 1;
+    loop {
+        let candidates = strategy.ask(&mut rng);
+        let fitness: Vec<f64> = candidates
+            .par_iter()
+            .map(|candidate| {
+                let weights = minimax::EvaluationWeights::from_array(core::array::from_fn(|i| candidate[i] as f32));
+                evolution::evaluation_weights_fitness(&fast, &weights, &config, gen_count)
+            })
+            .collect();
+        strategy.tell(&candidates, &fitness);
+        let weights = minimax::EvaluationWeights::from_array(core::array::from_fn(|i| strategy.mean[i] as f32));
+        if let Err(error) = weights.save(&path) {
+            println!("Could not save {} : {}", path, error);
+        }
+        let best_fitness = fitness.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        println!(
+            "Generation {}: best score {}     Step size : {}     Mean : {:?}",
+            gen_count, best_fitness, strategy.step_size, weights
+        );
+        gen_count += 1;
+    }
+}
+
+fn use_alphazero() {
+    let config = ask_mcts_config_or(alphazero_default_config());
+    // Ask user for network name
+    println!("Enter a network name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let line = line.trim();
+    let path = format!("networks/{}.ntwk", line);
+    if !Path::new(&path).exists() {
+        println!("Network not found");
+        return;
+    }
+    let (network, _) = match neural_network::NeuralNetwork::load(&path) {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            println!("Could not load {} : {}", path, error);
+            return;
+        }
+    };
+    let fast = fastgame::FastGame::new();
+    let rand = Random::from_seed(Seed::unsafe_new(SEED));
+    let mut game_state = [0;4];
+    game_state = fast.add_random_block(game_state, &rand);
+    game_state = fast.add_random_block(game_state, &rand);
+    let mut game_score = 0;
+    renderer::render(FastGame::to_flat_array(game_state));
+    let mut mcts = mcts::MonteCarloTree::from_network(&fast, game_state, config, network);
+    loop {
+        mcts.grow_tree(&fast, MCTS_MIN_TIME, MCTS_ITERATION_LIMIT);
+        let best_direction = mcts.get_best_direction();
+        let (new_game_state, move_score) = fast.play_move(game_state, best_direction.clone(), &rand);
+        game_score += move_score;
+        game_state = new_game_state;
+        renderer::render(FastGame::to_flat_array(game_state));
+        println!("Score: {:?}", game_score);
+        if fast.is_lost(&game_state) {
+            println!("You lost !");
+            break;
+        }
+        mcts.get_info(&best_direction);
+        mcts.reroot(&fast, move_score, game_state);
+    }
+}
+
 fn use_mcts(){
+    let config = ask_mcts_config();
+    // Ask user for a directory to export the trees to (one DOT and one JSON file per move)
+    println!("Enter a directory to export the trees to (leave empty to skip) :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let export_directory = line.trim().to_string();
+    if !export_directory.is_empty() {
+        std::fs::create_dir_all(&export_directory).unwrap();
+    }
     time_graph::enable_data_collection(true);
     let fast = fastgame::FastGame::new();
     let rand = Random::from_seed(Seed::unsafe_new(SEED));
@@ -275,12 +754,24 @@ fn use_mcts(){
     game_state = fast.add_random_block(game_state, &rand);
     //game_state = [163840,229376,327680,427008];
     let mut game_score = 0;
+    let mut move_number = 0;
     renderer::render(FastGame::to_flat_array(game_state));
     println!("Score: {:?}", game_score);
     let start_time = std::time::Instant::now();
-    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state);
+    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state, config);
+    let mut remaining_time = config.game_time;
     loop {
-        mcts.grow_tree(&fast, MCTS_MIN_TIME, MCTS_ITERATION_LIMIT);
+        if config.game_time > 0.0 {
+            remaining_time -= mcts.grow_tree_timed(&fast, remaining_time);
+        } else {
+            mcts.grow_tree(&fast, MCTS_MIN_TIME, MCTS_ITERATION_LIMIT);
+        }
+        if !export_directory.is_empty() {
+            let path = format!("{}/move_{}", export_directory, move_number);
+            std::fs::write(format!("{}.dot", path), mcts.export_dot(TREE_EXPORT_DEPTH, TREE_EXPORT_CHILDREN)).unwrap();
+            std::fs::write(format!("{}.json", path), mcts.export_json(TREE_EXPORT_DEPTH, TREE_EXPORT_CHILDREN)).unwrap();
+        }
+        move_number += 1;
         let best_direction = mcts.get_best_direction();
         let (new_game_state, move_score) = fast.play_move(game_state, best_direction.clone(), &rand);
         game_score += move_score;
@@ -296,6 +787,9 @@ fn use_mcts(){
         renderer::render(FastGame::to_flat_array(game_state));
         println!("Score: {:?}", game_score);
         println!("Time spent since the begining of the game : {:?}", std::time::Instant::now() - start_time);
+        if config.game_time > 0.0 {
+            println!("Time left on the clock : {:.3}s", remaining_time.max(0.0));
+        }
         mcts.get_info(&best_direction);
         mcts.reroot(&fast, move_score, game_state);
     }
@@ -303,6 +797,7 @@ fn use_mcts(){
 
 fn mcts_optimization_test(){
     // Test performance by running a few tests in a standard situation, to assess time per iteration in a controled way
+    let config = ask_mcts_config();
     time_graph::enable_data_collection(true);
     let fast = fastgame::FastGame::new();
     let rand = Random::from_seed(Seed::unsafe_new(SEED));
@@ -314,7 +809,7 @@ fn mcts_optimization_test(){
     renderer::render(FastGame::to_flat_array(game_state));
     println!("Score: {:?}", game_score);
     let start_time = std::time::Instant::now();
-    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state);
+    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state, config);
     mcts.grow_tree(&fast, 5.0, usize::max_value());
     let best_direction = mcts.get_best_direction();
     let (new_game_state, move_score) = fast.play_move(game_state, best_direction.clone(), &rand);
@@ -331,6 +826,7 @@ fn mcts_optimization_test(){
 
 fn mcts_strength_test(parallel:bool) {
     // Test the strenght of the mcts implementation by running it accros different seeds and with different time limits
+    let config = ask_mcts_config();
     let fast = fastgame::FastGame::new();
     let seeds:[usize;200] = core::array::from_fn(|i| i + 1);
     let iteration_limits:Vec<usize> = vec![1000];
@@ -345,9 +841,14 @@ fn mcts_strength_test(parallel:bool) {
                     game_state = fast.add_random_block(game_state, &rand);
                     game_state = fast.add_random_block(game_state, &rand);
                     let mut game_score = 0;
-                    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state);
+                    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state, config);
+                    let mut remaining_time = config.game_time;
                     loop {
-                        mcts.grow_tree(&fast, 0.0, limit);
+                        if config.game_time > 0.0 {
+                            remaining_time -= mcts.grow_tree_timed(&fast, remaining_time);
+                        } else {
+                            mcts.grow_tree(&fast, 0.0, limit);
+                        }
                         let best_direction = mcts.get_best_direction();
                         let (new_game_state, move_score) = fast.play_move(game_state, best_direction.clone(), &rand);
                         game_score += move_score;
@@ -376,9 +877,14 @@ fn mcts_strength_test(parallel:bool) {
                     game_state = fast.add_random_block(game_state, &rand);
                     //game_state = [163840,229376,327680,427008];
                     let mut game_score = 0;
-                    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state);
+                    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state, config);
+                    let mut remaining_time = config.game_time;
                     loop {
-                        mcts.grow_tree(&fast, 0.0, limit);
+                        if config.game_time > 0.0 {
+                            remaining_time -= mcts.grow_tree_timed(&fast, remaining_time);
+                        } else {
+                            mcts.grow_tree(&fast, 0.0, limit);
+                        }
                         let best_direction = mcts.get_best_direction();
                         let (new_game_state, move_score) = fast.play_move(game_state, best_direction.clone(), &rand);
                         game_score += move_score;
@@ -396,12 +902,17 @@ fn mcts_strength_test(parallel:bool) {
             average_scores[limit_index] = (seed_average_scores.iter().sum::<f32>() / (seeds.len() * iteration_count) as f32).exp()
         }
     }
+    println!("Chance sampling  : {:?}", config.chance_sampling);
+    if config.game_time > 0.0 {
+        println!("Time per game    : {}s", config.game_time);
+    }
     println!("Iteration limits : {:?}", iteration_limits);
     println!("Average score    : {:?}", average_scores);
 }
 
 fn rollout_verification() {
     // Test rollouts
+    let config = ask_mcts_config();
     time_graph::enable_data_collection(true);
     let fast = fastgame::FastGame::new();
     let rand = Random::from_seed(Seed::unsafe_new(SEED));
@@ -413,7 +924,7 @@ fn rollout_verification() {
     renderer::render(FastGame::to_flat_array(game_state));
     println!("Score: {:?}", game_score);
     let start_time = std::time::Instant::now();
-    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state);
+    let mut mcts = mcts::MonteCarloTree::new(&fast, game_state, config);
     mcts.grow_tree(&fast, 5.0, 1);
     let best_direction = mcts.get_best_direction();
     let (new_game_state, move_score) = fast.play_move(game_state, best_direction.clone(), &rand);
@@ -445,3 +956,168 @@ fn rollout_verification() {
         };
     }
 }*/
+
+fn train_ntuple() {
+    let config = ask_config("an n-tuple", ntuple::NTupleConfig::default(), ntuple::NTupleConfig::load);
+    // Ask user for network name (if it exists load, else create)
+    println!("Enter a network name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let path = format!("networks/{}.ntup", line.trim());
+
+    // The network's n-th game is played with the seed n, so that a resumed training plays new games
+    let mut game_count: u64 = 0;
+    let mut network = if !Path::new(&path).exists() {
+        match ntuple::NTupleNetwork::new(config.tuples.tuples(), config.max_exponent) {
+            Ok(network) => network,
+            Err(error) => {
+                println!("Could not create the network : {}", error);
+                return;
+            }
+        }
+    } else {
+        let (network, metadata) = match ntuple::NTupleNetwork::load(&path) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                println!("Could not load {} : {}", path, error);
+                return;
+            }
+        };
+        game_count = metadata.generation as u64;
+        network
+    };
+    let fast = fastgame::FastGame::new();
+    let interval = config.report_interval.max(1);
+    let mut total_score: u64 = 0;
+    let mut best_tile = 0;
+    let mut reached_2048 = 0;
+    let mut interval_games = 0;
+    for game_index in 0..config.games {
+        let (score, max_tile) = network.train_game(&fast, &config, game_count);
+        game_count += 1;
+        total_score += score as u64;
+        best_tile = best_tile.max(max_tile);
+        if max_tile >= 11 {
+            reached_2048 += 1;
+        }
+        interval_games += 1;
+        if interval_games == interval || game_index + 1 == config.games {
+            // The fitness saved along with the network is the mean score of the last games
+            let mean_score = total_score as f32 / interval_games as f32;
+            let metadata = neural_network::NetworkMetadata::new(game_count as usize, mean_score);
+            if let Err(error) = network.save(&path, &metadata) {
+                println!("Could not save {} : {}", path, error);
+            }
+            println!(
+                "Games {}: mean score {}     Max tile : {}     2048 reached : {:.1}%",
+                game_count,
+                mean_score,
+                1u64 << best_tile,
+                reached_2048 as f32 * 100.0 / interval_games as f32
+            );
+            total_score = 0;
+            best_tile = 0;
+            reached_2048 = 0;
+            interval_games = 0;
+        }
+    }
+}
+
+fn use_ntuple() {
+    let config = ask_config("an n-tuple", ntuple::NTupleConfig::default(), ntuple::NTupleConfig::load);
+    println!("Enter a network name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let path = format!("networks/{}.ntup", line.trim());
+    let network = match ntuple::NTupleNetwork::load(&path) {
+        Ok((network, _)) => network,
+        Err(error) => {
+            println!("Could not load {} : {}", path, error);
+            return;
+        }
+    };
+    let fast = fastgame::FastGame::new();
+    let rand = Random::from_seed(Seed::unsafe_new(SEED));
+    let mut game_state = [0u32; 4];
+    game_state = fast.add_random_block(game_state, &rand);
+    game_state = fast.add_random_block(game_state, &rand);
+    let mut score = 0;
+    renderer::render(FastGame::to_flat_array(game_state));
+    loop {
+        let direction = if config.search_depth == 0 {
+            network.get_best_direction(&fast, &game_state)
+        } else {
+            let leaf = minimax::LeafEvaluator::NTuple(&network);
+            minimax::get_best_direction_expectimax_with(&fast, game_state, config.search_depth, leaf)
+        };
+        if direction == game::Direction::None {
+            println!("Final score : {}", score);
+            println!("You lost !");
+            break;
+        }
+        let (new_game_state, move_score) = fast.play_move(game_state, direction, &rand);
+        game_state = new_game_state;
+        score += move_score;
+        renderer::render(FastGame::to_flat_array(game_state));
+        println!("Score: {}", score);
+    }
+}
+
+fn train_dqn() {
+    let config = ask_config("a DQN", dqn::DqnConfig::default(), dqn::DqnConfig::load);
+    // Ask user for network name (if it exists load, else create)
+    println!("Enter a network name :");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).unwrap();
+    let path = format!("networks/{}.ntwk", line.trim());
+
+    // The network's n-th episode is played with the seed n, and the exploration decreases with the episodes
+    let mut episode: u64 = 0;
+    let network = if !Path::new(&path).exists() {
+        training::direction_network(ask_input_encoding(), config.hidden_layers, config.hidden_size, config.initialization)
+    } else {
+        let (network, metadata) = match neural_network::NeuralNetwork::load(&path) {
+            Ok(loaded) => loaded,
+            Err(error) => {
+                println!("Could not load {} : {}", path, error);
+                return;
+            }
+        };
+        episode = metadata.generation as u64;
+        network
+    };
+    let fast = fastgame::FastGame::new();
+    let mut trainer = dqn::DqnTrainer::new(network, config, SEED + episode);
+    let interval = config.report_interval.max(1);
+    let mut total_score: u64 = 0;
+    let mut total_loss = 0.0;
+    let mut best_tile = 0;
+    let mut interval_episodes = 0;
+    for episode_index in 0..config.episodes {
+        let (score, max_tile, loss) = trainer.play_episode(&fast, episode);
+        episode += 1;
+        total_score += score as u64;
+        total_loss += loss;
+        best_tile = best_tile.max(max_tile);
+        interval_episodes += 1;
+        if interval_episodes == interval || episode_index + 1 == config.episodes {
+            // The fitness saved along with the network is the mean score of the last episodes
+            let mean_score = total_score as f32 / interval_episodes as f32;
+            let metadata = neural_network::NetworkMetadata::new(episode as usize, mean_score);
+            if let Err(error) = trainer.network.save(&path, &metadata) {
+                println!("Could not save {} : {}", path, error);
+            }
+            println!(
+                "Episodes {}: mean score {}     Max tile : {}     Loss : {}",
+                episode,
+                mean_score,
+                1u64 << best_tile,
+                total_loss / interval_episodes as f32
+            );
+            total_score = 0;
+            total_loss = 0.0;
+            best_tile = 0;
+            interval_episodes = 0;
+        }
+    }
+}
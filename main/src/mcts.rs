@@ -1,5 +1,10 @@
 use crate::{fastgame};
+use crate::alphazero;
+use crate::config::Config;
+use crate::encoding;
+use crate::evolution;
 use crate::game::{self};
+use crate::neural_network::NeuralNetwork;
 use rand::Rng;
 use rand::rngs::SmallRng;
 use rand::SeedableRng;
@@ -29,15 +34,33 @@ enum TypeInfo {
 #[derive(Clone)]
 struct SpawnInfo {
     move_made: game::Direction,
-    two_block_spawns_left: Vec<(usize,usize)>,
-    four_block_spawns_left: Vec<(usize,usize)>,
+    // Positions of the spawns not expanded yet, each with the number of spawns its child stands for
+    // (more than 1 with ChanceSampling::Grouped)
+    two_block_spawns_left: Vec<((usize,usize),usize)>,
+    four_block_spawns_left: Vec<((usize,usize),usize)>,
     total_value: f32,
     total_squares: f32,
+    total_powers: f64,
+    prior: f32,
 }
 #[derive(Clone)]
 struct MoveInfo {
     actions_left: Vec<game::Direction>,
     probability: f32,
+    // Policy of the network over the legal moves, computed when the first child is created
+    priors: Option<[f32;4]>,
+}
+
+// Everything exported about a node when dumping the tree
+struct NodeExport {
+    board: [u8;16],
+    is_spawn_node: bool,
+    move_made: Option<game::Direction>,
+    spawn: Option<(usize,usize,u32)>,
+    visit_count: usize,
+    mean: Option<f32>,
+    variance: Option<f32>,
+    probability: Option<f32>,
 }
 
 use std::cell::RefCell;
@@ -45,14 +68,181 @@ pub struct MonteCarloTree {
     nodes: RefCell<Vec<Node>>,
     generation_iteration_count: usize,
     inherited_node_count: usize,
+    config: MctsConfig,
+    // When present, gives the priors of the moves and replaces the rollouts by its value estimate
+    network: Option<NeuralNetwork>,
+}
+
+// Formula used to score the children of a move node during selection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelectionFormula {
+    Ucb1,
+    // UCB1 with an additional term proportional to the variance of the child's rollouts
+    Ucb1Tuned,
+    // UCB1 where the value of a child is the power mean of its rollouts instead of the arithmetic mean
+    PowerMean,
+    // AlphaZero-style selection, weighting the exploration by the prior of each move
+    Puct,
+}
+
+// How the children of a spawn (chance) node are created and visited
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChanceSampling {
+    // Every empty cell gets a child for both a 2 and a 4
+    Enumerate,
+    // Double progressive widening: a new spawn, sampled from the true distribution, is only added
+    // while the node has less than widening_constant * visits^widening_exponent children
+    ProgressiveWidening,
+    // Like Enumerate, but spawns giving the same grid up to a symmetry share a single child
+    Grouped,
+}
+
+// Rule used to pick the move to play once the tree has been grown
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FinalMoveRule {
+    MaxVisits,
+    MaxMean,
+    MaxSum,
+    // The move with both high visits and a high mean (best worst rank between the two)
+    RobustMax,
+}
+
+impl std::str::FromStr for SelectionFormula {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ucb1" => Ok(SelectionFormula::Ucb1),
+            "ucb1_tuned" => Ok(SelectionFormula::Ucb1Tuned),
+            "power_mean" => Ok(SelectionFormula::PowerMean),
+            "puct" => Ok(SelectionFormula::Puct),
+            _ => Err("expected ucb1, ucb1_tuned, power_mean or puct".to_string()),
+        }
+    }
+}
+
+impl std::str::FromStr for ChanceSampling {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enumerate" => Ok(ChanceSampling::Enumerate),
+            "progressive_widening" => Ok(ChanceSampling::ProgressiveWidening),
+            "grouped" => Ok(ChanceSampling::Grouped),
+            _ => Err("expected enumerate, progressive_widening or grouped".to_string()),
+        }
+    }
+}
+
+impl std::str::FromStr for FinalMoveRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "max_visits" => Ok(FinalMoveRule::MaxVisits),
+            "max_mean" => Ok(FinalMoveRule::MaxMean),
+            "max_sum" => Ok(FinalMoveRule::MaxSum),
+            "robust_max" => Ok(FinalMoveRule::RobustMax),
+            _ => Err("expected max_visits, max_mean, max_sum or robust_max".to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MctsConfig {
+    pub selection: SelectionFormula,
+    pub final_move: FinalMoveRule,
+    pub exploration_constant: f32,
+    pub variance_constant: f32,
+    pub power_mean_parameter: f32,
+    pub min_max_normalization: bool,
+    pub chance_sampling: ChanceSampling,
+    pub widening_constant: f32,
+    pub widening_exponent: f32,
+    // Time control for a whole game, disabled when game_time is 0
+    pub game_time: f32,
+    // Each move gets the remaining time divided by time_horizon
+    pub time_horizon: f32,
+    // Critical moves can use up to critical_time_factor times that allocation
+    pub critical_time_factor: f32,
+    // A move is critical when the two best moves' means are within this relative gap...
+    pub close_value_threshold: f32,
+    // ...or when the board has at most this many empty cells
+    pub full_board_empty_cells: usize,
+    // The search stops early once a move has this share of the root's visits
+    pub dominance_share: f32,
+    // With a network, the root's priors are mixed with Dirichlet(root_noise_alpha) noise of weight root_noise_weight,
+    // disabled when root_noise_weight is 0
+    pub root_noise_weight: f32,
+    pub root_noise_alpha: f32,
+    // In self-play, the first temperature_moves moves are sampled in proportion to visit_count^(1/temperature)
+    // instead of following final_move
+    pub temperature_moves: usize,
+    pub temperature: f32,
 }
 
 const EXPLORATION_CONSTANT:f32 = 5.5;
-//const POWER_MEAN_PARAMETER:f32 = 2.0;
+const POWER_MEAN_PARAMETER:f32 = 2.0;
 const VARIANCE_CONSTANT:f32 = 0.2;
+// With a time control, the tree is grown by chunks of iterations between two checks of the clock
+const TIME_CONTROL_CHUNK:usize = 64;
+// Part of a move's allocation always spent before stopping early on a dominant move
+const MIN_TIME_SHARE:f32 = 0.25;
+
+impl Default for MctsConfig {
+    fn default() -> Self {
+        MctsConfig {
+            selection: SelectionFormula::Ucb1Tuned,
+            final_move: FinalMoveRule::MaxSum,
+            exploration_constant: EXPLORATION_CONSTANT,
+            variance_constant: VARIANCE_CONSTANT,
+            power_mean_parameter: POWER_MEAN_PARAMETER,
+            min_max_normalization: true,
+            chance_sampling: ChanceSampling::Enumerate,
+            widening_constant: 1.0,
+            widening_exponent: 0.5,
+            game_time: 0.0,
+            time_horizon: 300.0,
+            critical_time_factor: 3.0,
+            close_value_threshold: 0.02,
+            full_board_empty_cells: 3,
+            dominance_share: 0.8,
+            root_noise_weight: 0.0,
+            root_noise_alpha: 0.5,
+            temperature_moves: 0,
+            temperature: 1.0,
+        }
+    }
+}
+
+impl MctsConfig {
+    pub fn load(path: &str) -> Result<MctsConfig, String> {
+        let config = Config::load(path)?;
+        let default = MctsConfig::default();
+        Ok(MctsConfig {
+            selection: config.get("selection", default.selection)?,
+            final_move: config.get("final_move", default.final_move)?,
+            exploration_constant: config.get("exploration_constant", default.exploration_constant)?,
+            variance_constant: config.get("variance_constant", default.variance_constant)?,
+            power_mean_parameter: config.get("power_mean_parameter", default.power_mean_parameter)?,
+            min_max_normalization: config.get("min_max_normalization", default.min_max_normalization)?,
+            chance_sampling: config.get("chance_sampling", default.chance_sampling)?,
+            widening_constant: config.get("widening_constant", default.widening_constant)?,
+            widening_exponent: config.get("widening_exponent", default.widening_exponent)?,
+            game_time: config.get("game_time", default.game_time)?,
+            time_horizon: config.get("time_horizon", default.time_horizon)?,
+            critical_time_factor: config.get("critical_time_factor", default.critical_time_factor)?,
+            close_value_threshold: config.get("close_value_threshold", default.close_value_threshold)?,
+            full_board_empty_cells: config.get("full_board_empty_cells", default.full_board_empty_cells)?,
+            dominance_share: config.get("dominance_share", default.dominance_share)?,
+            root_noise_weight: config.get("root_noise_weight", default.root_noise_weight)?,
+            root_noise_alpha: config.get("root_noise_alpha", default.root_noise_alpha)?,
+            temperature_moves: config.get("temperature_moves", default.temperature_moves)?,
+            temperature: config.get("temperature", default.temperature)?,
+        })
+    }
+}
+
 impl MonteCarloTree {
     #[time_graph::instrument]
-    pub fn new(fast: &fastgame::FastGame, root_state:[u32;4]) -> Self {
+    pub fn new(fast: &fastgame::FastGame, root_state:[u32;4], config: MctsConfig) -> Self {
         let possible_directions = fast.get_possible_directions(&root_state);
         let rootnode = Node{
             game_state: root_state,
@@ -60,35 +250,80 @@ impl MonteCarloTree {
             visit_count: 0,
             is_terminal: possible_directions.is_empty(),
             children_indices: Vec::new(),
-            specific_information: TypeInfo::Move(MoveInfo { actions_left: possible_directions, probability: 1.0 }),
+            specific_information: TypeInfo::Move(MoveInfo { actions_left: possible_directions, probability: 1.0, priors: None }),
             move_number: 0,
             score: 0,
         };
-        Self { nodes: RefCell::new(vec![rootnode]), generation_iteration_count: 0, inherited_node_count: 0}
+        Self { nodes: RefCell::new(vec![rootnode]), generation_iteration_count: 0, inherited_node_count: 0, config, network: None}
+    }
+
+    pub fn from_network(fast: &fastgame::FastGame, root_state:[u32;4], config: MctsConfig, network: NeuralNetwork) -> Self {
+        let mut tree = Self::new(fast, root_state, config);
+        tree.network = Some(network);
+        tree
     }
 
     #[time_graph::instrument]
-    fn exploration_function(&self) -> f32 {
-        let root = self.nodes.borrow()[0].clone();
-        return (root.score as f32 + 1.0).log2();
+    fn exploration_function(nodes: &[Node]) -> f32 {
+        return (nodes[0].score as f32 + 1.0).log2();
     }
 
+    // Index of the move node of the new root, and the symmetry (as in FastGame::symmetries) turning its board into
+    // the new root's. With ChanceSampling::Grouped, a spawn only has a child for one spawn of its group, so a node
+    // whose board is a symmetry of the new root's is used when no node has exactly its board
     #[time_graph::instrument]
-    pub fn find_new_root(&self, new_root_state: [u32;4]) -> Option<usize> {
-        for (index, node) in self.nodes.borrow().iter().enumerate() {
-            if node.game_state == new_root_state && match node.specific_information{ TypeInfo::Move(_) => true, _ => false} {
-                return Some(index);
+    pub fn find_new_root(&self, new_root_state: [u32;4]) -> Option<(usize,usize)> {
+        let nodes = self.nodes.borrow();
+        let is_move_node = |node: &Node| matches!(node.specific_information, TypeInfo::Move(_));
+        if let Some(index) = nodes.iter().position(|node| node.game_state == new_root_state && is_move_node(node)) {
+            return Some((index, 0));
+        }
+        if self.config.chance_sampling == ChanceSampling::Grouped {
+            let canonical_state = fastgame::FastGame::canonical(new_root_state);
+            for (index, node) in nodes.iter().enumerate() {
+                if is_move_node(node) && fastgame::FastGame::canonical(node.game_state) == canonical_state {
+                    let symmetry = fastgame::FastGame::symmetries(node.game_state).iter()
+                        .position(|&state| state == new_root_state)
+                        .unwrap();
+                    return Some((index, symmetry));
+                }
             }
         }
         // If the new root is not found as a child of the old root's children, return None
         return None;
     }
 
+    // Applies the symmetry to a node's board and to the moves and spawns it holds
+    fn transform_node(node: &mut Node, symmetry: usize) {
+        let transform_direction = |direction: &game::Direction| {
+            game::DIRECTIONS[encoding::symmetric_direction(direction.index(), symmetry)].clone()
+        };
+        node.game_state = fastgame::FastGame::symmetries(node.game_state)[symmetry];
+        match &mut node.specific_information {
+            TypeInfo::Move(move_info) => {
+                move_info.actions_left = move_info.actions_left.iter().map(transform_direction).collect();
+                if let Some(priors) = move_info.priors {
+                    let mut transformed_priors = [0.0;4];
+                    for (direction, prior) in priors.into_iter().enumerate() {
+                        transformed_priors[encoding::symmetric_direction(direction, symmetry)] = prior;
+                    }
+                    move_info.priors = Some(transformed_priors);
+                }
+            },
+            TypeInfo::Spawn(spawn_info) => {
+                spawn_info.move_made = transform_direction(&spawn_info.move_made);
+                for (coords, _) in spawn_info.two_block_spawns_left.iter_mut().chain(spawn_info.four_block_spawns_left.iter_mut()) {
+                    *coords = fastgame::FastGame::symmetric_position(*coords, symmetry);
+                }
+            },
+        }
+    }
+
     #[time_graph::instrument]
     pub fn reroot(&mut self,fast: &fastgame::FastGame, gained_score: u32, new_root_state: [u32;4]) {
         let mut new_nodes: Vec<Node> = Vec::new();
         let mut new_tree_old_indices = Vec::new();
-        if let Some(new_root_old_index) = self.find_new_root(new_root_state) {
+        if let Some((new_root_old_index, symmetry)) = self.find_new_root(new_root_state) {
             let old_nodes = self.nodes.borrow();
             // First, traverse trough every node in the new tree (from the new root)
             let mut stack = Vec::new();
@@ -123,6 +358,9 @@ impl MonteCarloTree {
                 let mut new_node = old_node.clone();
                 new_node.parent_index = new_parent;
                 new_node.children_indices = new_children;
+                if symmetry != 0 {
+                    Self::transform_node(&mut new_node, symmetry);
+                }
                 new_nodes.push(new_node);
             }
         }
@@ -136,102 +374,200 @@ impl MonteCarloTree {
                 visit_count: 0,
                 is_terminal: possible_directions.is_empty(),
                 children_indices: Vec::new(),
-                specific_information: TypeInfo::Move(MoveInfo { actions_left: possible_directions, probability: 1.0 }),
+                specific_information: TypeInfo::Move(MoveInfo { actions_left: possible_directions, probability: 1.0, priors: None }),
                 move_number: old_root.move_number + 1,
                 score: old_root.score + gained_score,
             };
             new_nodes.push(new_node);
         }
+        // An inherited root already has its priors, computed when it was not the root
+        if let TypeInfo::Move(MoveInfo { priors: Some(priors), actions_left, .. }) = &new_nodes[0].specific_information {
+            let mut noisy_priors = *priors;
+            let legal_moves:Vec<usize> = actions_left.iter().map(|direction| direction.index())
+                .chain(new_nodes[0].children_indices.iter().filter_map(|&child_index| match &new_nodes[child_index].specific_information {
+                    TypeInfo::Spawn(spawn_info) => Some(spawn_info.move_made.index()),
+                    TypeInfo::Move(_) => None,
+                }))
+                .collect();
+            self.add_root_noise(&mut noisy_priors, &legal_moves, &mut SmallRng::from_rng(&mut rand::rng()));
+            for child_index in new_nodes[0].children_indices.clone() {
+                if let TypeInfo::Spawn(spawn_info) = &mut new_nodes[child_index].specific_information {
+                    spawn_info.prior = noisy_priors[spawn_info.move_made.index()];
+                }
+            }
+            if let TypeInfo::Move(move_info) = &mut new_nodes[0].specific_information {
+                move_info.priors = Some(noisy_priors);
+            }
+        }
         self.nodes = RefCell::new(new_nodes);
         self.generation_iteration_count = 0;
         self.inherited_node_count = new_tree_old_indices.len();
     }
 
-    #[time_graph::instrument]
-    fn selection(&self, node_index: usize, rng: &mut SmallRng) -> usize {
-        let node = &self.nodes.borrow()[node_index];
-        let mut children_scores:Vec<f32> = Vec::with_capacity(node.children_indices.len());
-        match &node.specific_information {
+    // Mixes Dirichlet noise into the priors of the legal moves, so that self-play explores moves the policy neglects
+    fn add_root_noise(&self, priors: &mut [f32;4], legal_moves: &[usize], rng: &mut SmallRng) {
+        let weight = self.config.root_noise_weight;
+        if weight <= 0.0 || legal_moves.is_empty() {
+            return;
+        }
+        let gammas:Vec<f32> = legal_moves.iter().map(|_| Self::gamma_sample(self.config.root_noise_alpha, rng)).collect();
+        let total:f32 = gammas.iter().sum();
+        for (&direction, gamma) in legal_moves.iter().zip(gammas) {
+            let noise = if total > 0.0 {gamma / total} else {1.0 / legal_moves.len() as f32};
+            priors[direction] = (1.0 - weight) * priors[direction] + weight * noise;
+        }
+    }
+
+    // Gamma(shape, 1) sample (Marsaglia and Tsang's method, boosted for shapes below 1)
+    fn gamma_sample(shape: f32, rng: &mut SmallRng) -> f32 {
+        if shape < 1.0 {
+            let uniform:f32 = rng.random_range(f32::MIN_POSITIVE..1.0);
+            return Self::gamma_sample(shape + 1.0, rng) * uniform.powf(1.0 / shape);
+        }
+        let d = shape - 1.0 / 3.0;
+        let c = 1.0 / (9.0 * d).sqrt();
+        loop {
+            let x = evolution::gaussian(rng) as f32;
+            let v = (1.0 + c * x).powi(3);
+            if v <= 0.0 {
+                continue;
+            }
+            let uniform:f32 = rng.random_range(f32::MIN_POSITIVE..1.0);
+            if uniform.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+                return d * v;
+            }
+        }
+    }
+
+    // Value estimate, variance, visit count and prior of a move node's (spawn) child
+    fn child_statistics(&self, child: &Node) -> (f32,f32,f32,f32) {
+        match &child.specific_information {
             TypeInfo::Spawn(spawn_info) => {
-                if node.is_terminal || (!spawn_info.two_block_spawns_left.is_empty() && !spawn_info.four_block_spawns_left.is_empty()) || node.children_indices.is_empty() {
-                    return node_index;
-                }
-                // Calculation of children scores (probability/visitcount)
-                for child_index in &node.children_indices {
-                    let child = &self.nodes.borrow()[*child_index];
-                    if child.visit_count == 0 {
-                        panic!("Spawn's child visitcount is 0");
-                    }
-                    match &child.specific_information {
-                        TypeInfo::Move(move_info) => {children_scores.push(move_info.probability/child.visit_count as f32)},
-                        TypeInfo::Spawn(_) => unreachable!("Spawn is a child of spawn"),
-                    }
-                }
+                let visit_count = child.visit_count as f32;
+                let mean = spawn_info.total_value / visit_count;
+                let variance = (spawn_info.total_squares / visit_count) - mean.powf(2.0);
+                let value = match self.config.selection {
+                    SelectionFormula::PowerMean => {
+                        let p = self.config.power_mean_parameter as f64;
+                        (spawn_info.total_powers / visit_count as f64).powf(1.0 / p) as f32
+                    },
+                    _ => mean,
+                };
+                (value, variance, visit_count, spawn_info.prior)
             },
-            TypeInfo::Move(move_info) => {
-                if node.is_terminal || !move_info.actions_left.is_empty() {
-                    return node_index;
-                }
-                let exploration_factor = EXPLORATION_CONSTANT * (node.visit_count as f32).ln().sqrt();
-                let children_scores_squares_visitcounts:Vec<(f32,f32,f32)> = node.children_indices.iter().map(|child_index|{
-                    let child = &self.nodes.borrow()[*child_index];
-                    match &child.specific_information {
-                        TypeInfo::Spawn(spawn_info) => (spawn_info.total_value,spawn_info.total_squares,child.visit_count as f32),
-                        TypeInfo::Move(_) => unreachable!("Move is a child of move"),
+            TypeInfo::Move(_) => unreachable!("Move is a child of move"),
+        }
+    }
+
+    // Keeps track of every child sharing the highest score
+    fn keep_best(best_children: &mut Vec<usize>, best_score: &mut f32, child_index_index: usize, score: f32) {
+        if score > *best_score {
+            *best_score = score;
+            best_children.clear();
+            best_children.push(child_index_index);
+        } else if score == *best_score {
+            best_children.push(child_index_index);
+        }
+    }
+
+    // Descends from the root to the node to expand, storing the visited nodes in path
+    #[time_graph::instrument]
+    fn selection(&self, path: &mut Vec<usize>, rng: &mut SmallRng) -> usize {
+        let nodes = self.nodes.borrow();
+        let mut best_children: Vec<usize> = Vec::with_capacity(4);
+        let mut node_index = 0;
+        path.clear();
+        loop {
+            path.push(node_index);
+            let node = &nodes[node_index];
+            if node.is_terminal || node.children_indices.is_empty() {
+                return node_index;
+            }
+            best_children.clear();
+            let mut best_score = f32::NEG_INFINITY;
+            match &node.specific_information {
+                TypeInfo::Spawn(spawn_info) => {
+                    if self.config.chance_sampling == ChanceSampling::ProgressiveWidening {
+                        let spawns_left = spawn_info.two_block_spawns_left.len() + spawn_info.four_block_spawns_left.len();
+                        let allowed_children_count = (self.config.widening_constant * (node.visit_count as f32).powf(self.config.widening_exponent)).ceil() as usize;
+                        if spawns_left > 0 && node.children_indices.len() < allowed_children_count {
+                            return node_index;
                         }
-                })
-                .collect();
-                let children_average_scores:Vec<f32> = children_scores_squares_visitcounts.iter().map(|(score,_,visit_count)| (score / visit_count)).collect();
-                let children_visitcounts:Vec<f32> = children_scores_squares_visitcounts.iter().map(|(_,_,visit_count)| *visit_count).collect();
-                // Normalize the children average scores with local min-max normalization
-                let min_child_score:f32 = *children_average_scores.iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-                let max_child_score:f32 = *children_average_scores.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
-                let children_score_variance:f32 = (max_child_score - min_child_score).max(1.0); // Add 1 to avoid division by 0
-                let children_normalized_scores:Vec<f32> = children_average_scores.iter().map(|average_score|{
-                    (average_score - min_child_score) / children_score_variance
-                })
-                .collect();
-                let children_normalized_variance:Vec<f32> = children_scores_squares_visitcounts.iter().map(|(totalscore,totalsquares,visitcount)| {
-                    let raw_var:f32 = (totalsquares/visitcount) - (totalscore/visitcount).powf(2.0);
-                    raw_var / children_score_variance.powf(2.0)
-                }).collect();
-                // Get the children's uct scores with their normalized scores
-                children_scores = children_visitcounts.iter().enumerate().map(|(index, child_visit_count)| {
-                    children_normalized_scores[index] +  (exploration_factor + VARIANCE_CONSTANT * children_normalized_variance[index]) / child_visit_count.sqrt()
-                })
-                .collect();
-                /*/ Without min-max normalization :
-                children_scores = node.children_indices.iter()
-                    .map(|child_index| {
-                        let child = &self.nodes.borrow()[*child_index];
-                        let (avg_score, visit_count) = match &child.specific_information {
-                            TypeInfo::Spawn(spawn_info) => (spawn_info.total_value / child.visit_count as f32, child.visit_count as f32),
-                            TypeInfo::Move(_) => unreachable!(),
+                        // Sample one of the existing children from the spawn distribution
+                        let child_probability = |child_index: usize| match &nodes[child_index].specific_information {
+                            TypeInfo::Move(move_info) => move_info.probability,
+                            TypeInfo::Spawn(_) => unreachable!("Spawn is a child of spawn"),
                         };
-                        avg_score + self.exploration_function() * exploration_factor / visit_count.sqrt()
-                    })
-                    .collect();*/
-            }
-        };
-        let highest_score_children = children_scores.iter()
-            .enumerate()
-            .fold(
-                (Vec::new(), f32::NEG_INFINITY),
-                |(mut indices, current_max), (i, &val)| {
-                    if val > current_max {
-                        (vec![i], val) // New max: reset indices
-                    } else if val == current_max {
-                        indices.push(i);
-                        (indices, current_max) // Same max: add index
-                    } else {
-                        (indices, current_max) // Smaller: ignore
+                        let total_probability:f32 = node.children_indices.iter().map(|&child_index| child_probability(child_index)).sum();
+                        let mut remaining_probability = rng.random_range(0.0..total_probability);
+                        node_index = *node.children_indices.iter()
+                            .find(|&&child_index| {
+                                remaining_probability -= child_probability(child_index);
+                                remaining_probability < 0.0
+                            })
+                            .unwrap_or(node.children_indices.last().unwrap());
+                        continue;
+                    }
+                    if !spawn_info.two_block_spawns_left.is_empty() && !spawn_info.four_block_spawns_left.is_empty() {
+                        return node_index;
+                    }
+                    // Calculation of children scores (probability/visitcount)
+                    for (child_index_index, &child_index) in node.children_indices.iter().enumerate() {
+                        let child = &nodes[child_index];
+                        if child.visit_count == 0 {
+                            panic!("Spawn's child visitcount is 0");
+                        }
+                        match &child.specific_information {
+                            TypeInfo::Move(move_info) => {
+                                Self::keep_best(&mut best_children, &mut best_score, child_index_index, move_info.probability/child.visit_count as f32);
+                            },
+                            TypeInfo::Spawn(_) => unreachable!("Spawn is a child of spawn"),
+                        }
                     }
                 },
-            )
-            .0;
-        let random_index = rng.random_range(0..highest_score_children.len());
-        let child_index_index: usize =  highest_score_children[random_index];
-        return self.selection(node.children_indices[child_index_index], rng);
+                TypeInfo::Move(move_info) => {
+                    if !move_info.actions_left.is_empty() {
+                        return node_index;
+                    }
+                    // A move node has at most one child per direction
+                    let mut children_statistics = [(0.0,0.0,0.0,0.0);4];
+                    for (child_index_index, &child_index) in node.children_indices.iter().enumerate() {
+                        children_statistics[child_index_index] = self.child_statistics(&nodes[child_index]);
+                    }
+                    let children_statistics = &children_statistics[..node.children_indices.len()];
+                    // Normalize the children values with local min-max normalization
+                    let (min_child_value, children_value_range, exploration_scale) = if self.config.min_max_normalization {
+                        let min_child_value:f32 = children_statistics.iter().map(|statistics| statistics.0).fold(f32::INFINITY, f32::min);
+                        let max_child_value:f32 = children_statistics.iter().map(|statistics| statistics.0).fold(f32::NEG_INFINITY, f32::max);
+                        (min_child_value, (max_child_value - min_child_value).max(1.0), 1.0) // Add 1 to avoid division by 0
+                    } else {
+                        // Without normalization, the exploration has to grow with the scale of the scores
+                        (0.0, 1.0, Self::exploration_function(&nodes))
+                    };
+                    let exploration_constant = exploration_scale * self.config.exploration_constant;
+                    let parent_visit_count = node.visit_count as f32;
+                    // Get the children's scores with their normalized values
+                    for (child_index_index, &(value, variance, visit_count, prior)) in children_statistics.iter().enumerate() {
+                        let normalized_value = (value - min_child_value) / children_value_range;
+                        let score = match self.config.selection {
+                            SelectionFormula::Ucb1 | SelectionFormula::PowerMean => {
+                                normalized_value + exploration_constant * (parent_visit_count.ln() / visit_count).sqrt()
+                            },
+                            SelectionFormula::Ucb1Tuned => {
+                                let normalized_variance = variance / children_value_range.powf(2.0);
+                                normalized_value + (exploration_constant * parent_visit_count.ln().sqrt() + self.config.variance_constant * normalized_variance) / visit_count.sqrt()
+                            },
+                            SelectionFormula::Puct => {
+                                normalized_value + exploration_constant * prior * parent_visit_count.sqrt() / (1.0 + visit_count)
+                            },
+                        };
+                        Self::keep_best(&mut best_children, &mut best_score, child_index_index, score);
+                    }
+                }
+            };
+            let random_index = rng.random_range(0..best_children.len());
+            node_index = node.children_indices[best_children[random_index]];
+        }
     }
     #[time_graph::instrument]
     fn expansion(&mut self, fast: &fastgame::FastGame, node_index: usize, rng:&mut SmallRng) -> usize {
@@ -248,7 +584,7 @@ impl MonteCarloTree {
                     let value;
                     let twos = &mut spawn_info.two_block_spawns_left;
                     let fours = &mut spawn_info.four_block_spawns_left;
-                    let coords = match (twos.is_empty(), fours.is_empty()) {
+                    let (coords, group_size) = match (twos.is_empty(), fours.is_empty()) {
                         (false, true) => {
                             value = 1;
                             twos.pop().unwrap()
@@ -258,7 +594,14 @@ impl MonteCarloTree {
                             fours.pop().unwrap()
                         },
                         (false, false) => {
-                            if rng.random_bool(0.9) {
+                            let two_probability = match self.config.chance_sampling {
+                                // Sample the remaining spawns from the true distribution
+                                ChanceSampling::ProgressiveWidening => {
+                                    0.9 * twos.len() as f64 / (0.9 * twos.len() as f64 + 0.1 * fours.len() as f64)
+                                },
+                                _ => 0.9,
+                            };
+                            if rng.random_bool(two_probability) {
                                 value = 1;
                                 twos.pop().unwrap()
                             } else {
@@ -268,10 +611,11 @@ impl MonteCarloTree {
                         },
                         _ => unreachable!("Both value's block spawn are empty but node is not terminal")
                     };
-                    (coords,value)
+                    (coords,value,group_size)
                 };
-                let new_child_probability = if new_spawn.1 == 1 {0.9} else {0.1};
                 let new_child_state = fast.place_block(node.game_state, new_spawn.0, new_spawn.1);
+                // The child stands for every spawn of its group
+                let new_child_probability = if new_spawn.1 == 1 {0.9} else {0.1} * new_spawn.2 as f32;
                 let mut new_child_actions_left = fast.get_possible_directions(&new_child_state);
                 new_child_actions_left.shuffle(rng);
                 new_child = Node {
@@ -282,7 +626,8 @@ impl MonteCarloTree {
                     children_indices: Vec::new(),
                     specific_information: TypeInfo::Move(MoveInfo {
                         actions_left: new_child_actions_left,
-                        probability: new_child_probability
+                        probability: new_child_probability,
+                        priors: None,
                     }),
                     move_number: node.move_number,
                     score: node.score,
@@ -290,10 +635,44 @@ impl MonteCarloTree {
             },
             TypeInfo::Move(ref mut move_info) => {
                 let new_child_direction = &move_info.actions_left.pop().unwrap();
+                let new_child_prior = match &self.network {
+                    Some(network) => {
+                        if move_info.priors.is_none() {
+                            // Renormalize the policy over the legal moves, which are all left before the first expansion
+                            let (policy, _) = alphazero::policy_value(network, node.game_state);
+                            let mut priors = [0.0;4];
+                            for direction in move_info.actions_left.iter().chain(std::iter::once(new_child_direction)) {
+                                priors[direction.index()] = policy[direction.index()];
+                            }
+                            let total:f32 = priors.iter().sum();
+                            let mut priors = priors.map(|prior| prior / total.max(f32::MIN_POSITIVE));
+                            if node_index == 0 {
+                                let legal_moves:Vec<usize> = move_info.actions_left.iter().chain(std::iter::once(new_child_direction))
+                                    .map(|direction| direction.index())
+                                    .collect();
+                                self.add_root_noise(&mut priors, &legal_moves, rng);
+                            }
+                            move_info.priors = Some(priors);
+                        }
+                        move_info.priors.unwrap()[new_child_direction.index()]
+                    },
+                    // Without a policy, every legal move gets the same prior
+                    None => 1.0 / (move_info.actions_left.len() + 1 + node.children_indices.len()) as f32,
+                };
                 let (new_child_state, move_score) = fast.make_move(&node.game_state, new_child_direction);
-                let mut new_child_two_spawns = fastgame::FastGame::empty_list(&new_child_state);
+                let (mut new_child_two_spawns, mut new_child_four_spawns) = match self.config.chance_sampling {
+                    ChanceSampling::Grouped => (
+                        Self::distinct_spawns(fast, new_child_state, 1),
+                        Self::distinct_spawns(fast, new_child_state, 2),
+                    ),
+                    _ => {
+                        let spawns: Vec<_> = fastgame::FastGame::empty_list(&new_child_state).into_iter()
+                            .map(|coords| (coords, 1))
+                            .collect();
+                        (spawns.clone(), spawns)
+                    },
+                };
                 new_child_two_spawns.shuffle(rng);
-                let mut new_child_four_spawns = fastgame::FastGame::empty_list(&new_child_state);
                 new_child_four_spawns.shuffle(rng);
                 new_child = Node {
                     game_state: new_child_state,
@@ -307,6 +686,8 @@ impl MonteCarloTree {
                         four_block_spawns_left: new_child_four_spawns,
                         total_value: 0.0,
                         total_squares: 0.0,
+                        total_powers: 0.0,
+                        prior: new_child_prior,
                     }),
                     move_number: node.move_number + 1,
                     score: node.score + move_score,
@@ -320,6 +701,41 @@ impl MonteCarloTree {
         return new_child_index;
     }
 
+    // One spawn position per group of spawns giving the same grid up to a symmetry, with the size of the group
+    fn distinct_spawns(fast: &fastgame::FastGame, game_state: [u32;4], exponent: u32) -> Vec<((usize,usize),usize)> {
+        let mut seen_states: Vec<[u32;4]> = Vec::new();
+        let mut spawns: Vec<((usize,usize),usize)> = Vec::new();
+        for coords in fastgame::FastGame::empty_list(&game_state) {
+            let canonical_state = fastgame::FastGame::canonical(fast.place_block(game_state, coords, exponent));
+            match seen_states.iter().position(|&state| state == canonical_state) {
+                Some(group) => spawns[group].1 += 1,
+                None => {
+                    seen_states.push(canonical_state);
+                    spawns.push((coords, 1));
+                },
+            }
+        }
+        spawns
+    }
+
+    // Replaces the rollout by the score predicted by the network
+    #[time_graph::instrument]
+    fn network_evaluation(&self, fast: &fastgame::FastGame, network: &NeuralNetwork, node_index: usize, rng: &mut SmallRng) -> ([u32;4],usize,u32) {
+        let node = &self.nodes.borrow()[node_index];
+        let mut game_state = node.game_state;
+        if let TypeInfo::Spawn(_) = node.specific_information {
+            let empty_list = fastgame::FastGame::empty_list(&game_state);
+            let exponent = if rng.random_bool(0.9) {1} else {2};
+            let coords = empty_list[rng.random_range(0..empty_list.len())];
+            game_state = fast.place_block(game_state, coords, exponent);
+        }
+        if fast.is_lost(&game_state) {
+            return (game_state, node.move_number, node.score);
+        }
+        let (_, value) = alphazero::policy_value(network, game_state);
+        return (game_state, node.move_number, (node.score as f32 + value.max(0.0)) as u32);
+    }
+
     #[time_graph::instrument]
     fn random_simulation(&self, fast: &fastgame::FastGame, node_index: usize, rng: &mut SmallRng) -> ([u32;4],usize,u32) {
         let node = &self.nodes.borrow()[node_index];
@@ -489,24 +905,23 @@ impl MonteCarloTree {
         return (game_state,move_number,score);
     }
 
+    // Updates every node of the path taken during the iteration with the rollout result
     #[time_graph::instrument]
-    fn backpropagation(&mut self, node_index: usize, (game_state, move_count, score): ([u32;4],usize,u32)) {
-        let parent_index = {
-            let mut nodes = self.nodes.borrow_mut();
+    fn backpropagation(&mut self, path: &[usize], (_game_state, _move_count, score): ([u32;4],usize,u32)) {
+        let mut nodes = self.nodes.borrow_mut();
+        let computed_score = score as f32;
+        let powered_score = match self.config.selection {
+            SelectionFormula::PowerMean => (computed_score as f64).powf(self.config.power_mean_parameter as f64),
+            _ => 0.0,
+        };
+        for &node_index in path {
             let node = &mut nodes[node_index];
             node.visit_count += 1;
-            match node.specific_information {
-                TypeInfo::Spawn(ref mut spawn_info) => {
-                    let computed_score = score as f32;
-                    spawn_info.total_value += computed_score;
-                    spawn_info.total_squares += computed_score.powf(2.0)
-                },
-                _ => (),
-            };
-            node.parent_index
-        };
-        if let Some(parent_index) = parent_index {
-            self.backpropagation(parent_index, (game_state, move_count, score));
+            if let TypeInfo::Spawn(ref mut spawn_info) = node.specific_information {
+                spawn_info.total_value += computed_score;
+                spawn_info.total_squares += computed_score.powf(2.0);
+                spawn_info.total_powers += powered_score;
+            }
         }
     }
 
@@ -516,16 +931,241 @@ impl MonteCarloTree {
         let start_time = std::time::Instant::now();
         let start_iteration_count = self.generation_iteration_count;
         let mut iterations = 0;
+        let mut path = Vec::new();
         while Instant::now() - start_time < time_limit || self.generation_iteration_count - start_iteration_count + iterations < iteration_limit {
-            let selected_node_index = self.selection(0, &mut rng);
-            let chosen_node_index = self.expansion(&fast, selected_node_index, &mut rng);
-            let rollout_info = self.random_simulation(&fast, chosen_node_index, &mut rng);
-            self.backpropagation(chosen_node_index, rollout_info);
+            let selected_node_index = self.selection(&mut path, &mut rng);
+            let chosen_node_index = self.expansion(fast, selected_node_index, &mut rng);
+            if chosen_node_index != selected_node_index {
+                path.push(chosen_node_index);
+            }
+            let rollout_info = match &self.network {
+                Some(network) => self.network_evaluation(fast, network, chosen_node_index, &mut rng),
+                None => self.random_simulation(fast, chosen_node_index, &mut rng),
+            };
+            self.backpropagation(&path, rollout_info);
             iterations += 1;
         }
         self.generation_iteration_count += iterations;
     }
 
+    // Grows the tree for one move of a game played in config.game_time seconds, returns the time spent
+    pub fn grow_tree_timed(&mut self, fast: &fastgame::FastGame, remaining_time: f32) -> f32 {
+        let start_time = Instant::now();
+        let (root_state, legal_move_count) = {
+            let nodes = self.nodes.borrow();
+            let root = &nodes[0];
+            let action_count = match &root.specific_information {
+                TypeInfo::Move(move_info) => move_info.actions_left.len() + root.children_indices.len(),
+                TypeInfo::Spawn(_) => unreachable!("The root is a spawn"),
+            };
+            (root.game_state, action_count)
+        };
+        // Nothing to think about with a single legal move
+        if legal_move_count <= 1 {
+            self.grow_tree(fast, 0.0, 1);
+            return start_time.elapsed().as_secs_f32();
+        }
+        let base_time = remaining_time.max(0.0) / self.config.time_horizon;
+        let full_board = fastgame::FastGame::empty_list(&root_state).len() <= self.config.full_board_empty_cells;
+        loop {
+            self.grow_tree(fast, 0.0, TIME_CONTROL_CHUNK);
+            let elapsed = start_time.elapsed().as_secs_f32();
+            let (best_visit_share, close_values) = self.root_criticality();
+            let allowed_time = if full_board || close_values {
+                base_time * self.config.critical_time_factor
+            } else {
+                base_time
+            };
+            if elapsed >= allowed_time.min(remaining_time) {
+                break;
+            }
+            if best_visit_share >= self.config.dominance_share && elapsed >= MIN_TIME_SHARE * base_time {
+                break;
+            }
+        }
+        start_time.elapsed().as_secs_f32()
+    }
+
+    // Visit share of the most visited move, and whether the two best moves have close means
+    fn root_criticality(&self) -> (f32, bool) {
+        let nodes = self.nodes.borrow();
+        let root = &nodes[0];
+        let mut visit_counts = Vec::with_capacity(4);
+        let mut means = Vec::with_capacity(4);
+        for child_index in &root.children_indices {
+            let child = &nodes[*child_index];
+            if let TypeInfo::Spawn(spawn_info) = &child.specific_information {
+                visit_counts.push(child.visit_count as f32);
+                means.push(spawn_info.total_value / child.visit_count.max(1) as f32);
+            }
+        }
+        let total_visit_count:f32 = visit_counts.iter().sum();
+        let best_visit_share = visit_counts.iter().cloned().fold(0.0, f32::max) / total_visit_count.max(1.0);
+        means.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        let close_values = means.len() >= 2 && (means[0] - means[1]) <= self.config.close_value_threshold * means[0].abs();
+        (best_visit_share, close_values)
+    }
+
+    // Indices and depths of the exported nodes: the max_children most visited children of every node, up to max_depth
+    fn exported_nodes(&self, max_depth: usize, max_children: usize) -> Vec<(usize, usize)> {
+        let nodes = self.nodes.borrow();
+        let mut exported = Vec::new();
+        let mut stack = vec![(0, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            exported.push((index, depth));
+            if depth == max_depth {
+                continue;
+            }
+            let mut children = nodes[index].children_indices.clone();
+            children.sort_by(|a, b| nodes[*b].visit_count.cmp(&nodes[*a].visit_count));
+            for &child_index in children.iter().take(max_children).rev() {
+                stack.push((child_index, depth + 1));
+            }
+        }
+        exported
+    }
+
+    fn node_export(&self, index: usize) -> NodeExport {
+        let nodes = self.nodes.borrow();
+        let node = &nodes[index];
+        let mut export = NodeExport {
+            board: fastgame::FastGame::to_flat_array(node.game_state),
+            is_spawn_node: false,
+            move_made: None,
+            spawn: None,
+            visit_count: node.visit_count,
+            mean: None,
+            variance: None,
+            probability: None,
+        };
+        match &node.specific_information {
+            TypeInfo::Spawn(spawn_info) => {
+                export.is_spawn_node = true;
+                export.move_made = Some(spawn_info.move_made.clone());
+                if node.visit_count > 0 {
+                    let mean = spawn_info.total_value / node.visit_count as f32;
+                    export.mean = Some(mean);
+                    export.variance = Some(spawn_info.total_squares / node.visit_count as f32 - mean.powf(2.0));
+                }
+            },
+            TypeInfo::Move(move_info) => {
+                export.probability = Some(move_info.probability);
+                // The spawn is the only cell that was empty before and is not anymore
+                if let Some(parent_index) = node.parent_index {
+                    let parent_board = fastgame::FastGame::to_flat_array(nodes[parent_index].game_state);
+                    export.spawn = (0..16)
+                        .find(|&cell| parent_board[cell] == 0 && export.board[cell] != 0)
+                        .map(|cell| (cell / 4, cell % 4, 1u32 << export.board[cell]));
+                }
+                // Move nodes hold no value themselves, so use the one of their children
+                let (children_value, children_visit_count) = node.children_indices.iter()
+                    .fold((0.0, 0), |(value, visit_count), child_index| {
+                        match &nodes[*child_index].specific_information {
+                            TypeInfo::Spawn(spawn_info) => (value + spawn_info.total_value, visit_count + nodes[*child_index].visit_count),
+                            TypeInfo::Move(_) => unreachable!("Move is a child of move"),
+                        }
+                    });
+                if children_visit_count > 0 {
+                    export.mean = Some(children_value / children_visit_count as f32);
+                }
+            },
+        }
+        export
+    }
+
+    // Graphviz DOT export of the top of the tree, move nodes are boxes and spawn nodes are ellipses
+    pub fn export_dot(&self, max_depth: usize, max_children: usize) -> String {
+        let exported = self.exported_nodes(max_depth, max_children);
+        let nodes = self.nodes.borrow();
+        let mut dot = String::from("digraph mcts {\n    node [fontname=\"monospace\"];\n");
+        for &(index, _) in &exported {
+            let export = self.node_export(index);
+            let mut label = String::new();
+            for row in export.board.chunks_exact(4) {
+                let cells: Vec<String> = row.iter()
+                    .map(|&exponent| if exponent == 0 { format!("{:>6}", ".") } else { format!("{:>6}", 1u32 << exponent) })
+                    .collect();
+                label.push_str(&cells.join(""));
+                label.push_str("\\l");
+            }
+            label.push_str(&format!("visits: {}\\l", export.visit_count));
+            if let Some(mean) = export.mean {
+                label.push_str(&format!("mean: {:.1}\\l", mean));
+            }
+            if let Some(variance) = export.variance {
+                label.push_str(&format!("variance: {:.1}\\l", variance));
+            }
+            if let Some(probability) = export.probability {
+                label.push_str(&format!("probability: {}\\l", probability));
+            }
+            let shape = if export.is_spawn_node { "ellipse" } else { "box" };
+            dot.push_str(&format!("    n{} [shape={}, label=\"{}\"];\n", index, shape, label));
+            if let Some(parent_index) = nodes[index].parent_index {
+                if index != exported[0].0 {
+                    let edge_label = match (&export.move_made, export.spawn) {
+                        (Some(direction), _) => direction.to_string(),
+                        (None, Some((row, column, value))) => format!("{} at ({}, {})", value, row, column),
+                        (None, None) => String::new(),
+                    };
+                    dot.push_str(&format!("    n{} -> n{} [label=\"{}\"];\n", parent_index, index, edge_label));
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // JSON export of the top of the tree, as nested objects
+    pub fn export_json(&self, max_depth: usize, max_children: usize) -> String {
+        let exported = self.exported_nodes(max_depth, max_children);
+        let mut json = String::new();
+        self.write_json_node(&mut json, &exported, 0);
+        json.push('\n');
+        json
+    }
+
+    fn write_json_node(&self, json: &mut String, exported: &[(usize, usize)], position: usize) {
+        let (index, depth) = exported[position];
+        let export = self.node_export(index);
+        let optional_number = |value: Option<f32>| match value {
+            Some(value) if value.is_finite() => value.to_string(),
+            _ => "null".to_string(),
+        };
+        json.push_str(&format!("{{\"index\":{},\"type\":\"{}\",", index, if export.is_spawn_node { "spawn" } else { "move" }));
+        json.push_str(&format!("\"board\":{:?},", export.board.map(|exponent| if exponent == 0 { 0 } else { 1u32 << exponent })));
+        match &export.move_made {
+            Some(direction) => json.push_str(&format!("\"move\":\"{}\",", direction)),
+            None => json.push_str("\"move\":null,"),
+        }
+        match export.spawn {
+            Some((row, column, value)) => json.push_str(&format!("\"spawn\":{{\"row\":{},\"column\":{},\"value\":{}}},", row, column, value)),
+            None => json.push_str("\"spawn\":null,"),
+        }
+        json.push_str(&format!(
+            "\"visits\":{},\"mean\":{},\"variance\":{},\"probability\":{},\"children\":[",
+            export.visit_count,
+            optional_number(export.mean),
+            optional_number(export.variance),
+            optional_number(export.probability),
+        ));
+        // The children of a node directly follow it in the exported list, one level deeper
+        let mut first_child = true;
+        for child_position in position + 1..exported.len() {
+            let child_depth = exported[child_position].1;
+            if child_depth <= depth {
+                break;
+            }
+            if child_depth == depth + 1 {
+                if !first_child {
+                    json.push(',');
+                }
+                first_child = false;
+                self.write_json_node(json, exported, child_position);
+            }
+        }
+        json.push_str("]}");
+    }
+
     pub fn get_info(&self, best_direction: &game::Direction) {
         let nodes = self.nodes.borrow();
         let node_count = nodes.len();
@@ -551,20 +1191,288 @@ impl MonteCarloTree {
         println!("Nodes inherited: {}", self.inherited_node_count);
     }
 
+    // Share of the root's visits given to each move, in game::DIRECTIONS order
+    pub fn get_visit_distribution(&self) -> [f32;4] {
+        let nodes = self.nodes.borrow();
+        let mut distribution = [0.0;4];
+        for child_index in &nodes[0].children_indices {
+            if let TypeInfo::Spawn(spawn_info) = &nodes[*child_index].specific_information {
+                distribution[spawn_info.move_made.index()] = nodes[*child_index].visit_count as f32;
+            }
+        }
+        let total:f32 = distribution.iter().sum();
+        if total > 0.0 {
+            distribution = distribution.map(|visit_count| visit_count / total);
+        }
+        distribution
+    }
+
+    // Mean value of the root's moves in game::DIRECTIONS order, f32::NEG_INFINITY for the unexplored ones
+    pub fn get_move_values(&self) -> [f32;4] {
+        let nodes = self.nodes.borrow();
+        let mut values = [f32::NEG_INFINITY;4];
+        for child_index in &nodes[0].children_indices {
+            let child = &nodes[*child_index];
+            if let TypeInfo::Spawn(spawn_info) = &child.specific_information {
+                if child.visit_count > 0 {
+                    values[spawn_info.move_made.index()] = spawn_info.total_value / child.visit_count as f32;
+                }
+            }
+        }
+        values
+    }
+
     #[time_graph::instrument]
     pub fn get_best_direction(&self) -> game::Direction {
-        return self.nodes.borrow()[0].children_indices.iter()
+        let nodes = self.nodes.borrow();
+        // (visit count, total value, direction) of every move from the root
+        let children:Vec<(f32,f32,game::Direction)> = nodes[0].children_indices.iter()
             .map(|child_index| {
-                let child = &self.nodes.borrow()[*child_index];
+                let child = &nodes[*child_index];
                 match &child.specific_information {
                     TypeInfo::Spawn(spawn_info) => {
-                        (spawn_info.total_value, spawn_info.move_made.clone())
+                        (child.visit_count.max(1) as f32, spawn_info.total_value, spawn_info.move_made.clone())
                     },
                     _ => unreachable!(),
                 }
             })
-            .max_by(|a,b| a.0.partial_cmp(&b.0).expect("Could not order moves"))
-            .map(|(_, direction)| direction)
-            .unwrap_or(game::Direction::None);
+            .collect();
+        let best_index = match self.config.final_move {
+            FinalMoveRule::MaxVisits => Self::index_of_max(children.iter().map(|child| child.0)),
+            FinalMoveRule::MaxMean => Self::index_of_max(children.iter().map(|child| child.1 / child.0)),
+            FinalMoveRule::MaxSum => Self::index_of_max(children.iter().map(|child| child.1)),
+            FinalMoveRule::RobustMax => {
+                // Rank of a child = number of children strictly better than it
+                let visit_ranks:Vec<usize> = children.iter().map(|child| children.iter().filter(|other| other.0 > child.0).count()).collect();
+                let mean_ranks:Vec<usize> = children.iter().map(|child| children.iter().filter(|other| other.1 / other.0 > child.1 / child.0).count()).collect();
+                // Keep the child with the best worst rank, the most visited one in case of a tie
+                Self::index_of_max((0..children.len()).map(|index| {
+                    -(visit_ranks[index].max(mean_ranks[index]) as f32) + children[index].0 / (nodes[0].visit_count as f32 + 1.0)
+                }))
+            },
+        };
+        return best_index.map(|index| children[index].2.clone()).unwrap_or(game::Direction::None);
+    }
+
+    fn index_of_max(values: impl Iterator<Item = f32>) -> Option<usize> {
+        values.enumerate()
+            .max_by(|a,b| a.1.partial_cmp(&b.1).expect("Could not order moves"))
+            .map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Tree whose root has one expanded leaf per move, from (move, visit count, total value, total squares, prior)
+    fn tree_with_moves(config: MctsConfig, moves: &[(game::Direction, usize, f32, f32, f32)]) -> MonteCarloTree {
+        let fast = fastgame::FastGame::new();
+        let tree = MonteCarloTree::new(&fast, [0;4], config);
+        {
+            let mut nodes = tree.nodes.borrow_mut();
+            nodes[0].is_terminal = false;
+            nodes[0].visit_count = moves.iter().map(|statistics| statistics.1).sum();
+            for (direction, visit_count, total_value, total_squares, prior) in moves {
+                let child = Node {
+                    game_state: [0;4],
+                    parent_index: Some(0),
+                    visit_count: *visit_count,
+                    is_terminal: false,
+                    children_indices: Vec::new(),
+                    specific_information: TypeInfo::Spawn(SpawnInfo {
+                        move_made: direction.clone(),
+                        two_block_spawns_left: Vec::new(),
+                        four_block_spawns_left: Vec::new(),
+                        total_value: *total_value,
+                        total_squares: *total_squares,
+                        total_powers: *total_squares as f64,
+                        prior: *prior,
+                    }),
+                    move_number: 1,
+                    score: 0,
+                };
+                let child_index = nodes.len();
+                nodes[0].children_indices.push(child_index);
+                nodes.push(child);
+            }
+        }
+        tree
+    }
+
+    fn selected_move(tree: &MonteCarloTree) -> game::Direction {
+        let child_index = tree.selection(&mut Vec::new(), &mut SmallRng::seed_from_u64(0));
+        match &tree.nodes.borrow()[child_index].specific_information {
+            TypeInfo::Spawn(spawn_info) => spawn_info.move_made.clone(),
+            TypeInfo::Move(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn selection_formulas_weigh_the_statistics_differently() {
+        use game::Direction::{Left, Up};
+        let config = |selection| MctsConfig { selection, power_mean_parameter: 2.0, ..MctsConfig::default() };
+        // Up always scored 10, Left scored 0 or 19: a lower mean but a higher variance and power mean
+        let moves = [(Up, 10, 100.0, 1000.0, 0.5), (Left, 10, 95.0, 1805.0, 0.5)];
+        assert_eq!(selected_move(&tree_with_moves(config(SelectionFormula::Ucb1), &moves)), Up);
+        assert_eq!(selected_move(&tree_with_moves(config(SelectionFormula::Ucb1Tuned), &moves)), Left);
+        assert_eq!(selected_move(&tree_with_moves(config(SelectionFormula::PowerMean), &moves)), Left);
+        assert_eq!(selected_move(&tree_with_moves(config(SelectionFormula::Puct), &moves)), Up);
+        // With equal means, UCB1 explores the least visited move while PUCT follows the prior
+        let moves = [(Up, 20, 200.0, 2000.0, 0.9), (Left, 5, 50.0, 500.0, 0.1)];
+        assert_eq!(selected_move(&tree_with_moves(config(SelectionFormula::Ucb1), &moves)), Left);
+        assert_eq!(selected_move(&tree_with_moves(config(SelectionFormula::Puct), &moves)), Up);
+    }
+
+    #[test]
+    fn final_move_rules_pick_their_move() {
+        use game::Direction::{Left, Right, Up};
+        // Up is the most visited, Left has the best mean, Right the best sum and the best worst rank
+        let moves = [(Up, 10, 50.0, 0.0, 0.0), (Left, 4, 40.0, 0.0, 0.0), (Right, 8, 56.0, 0.0, 0.0)];
+        let rules = [
+            (FinalMoveRule::MaxVisits, Up),
+            (FinalMoveRule::MaxMean, Left),
+            (FinalMoveRule::MaxSum, Right),
+            (FinalMoveRule::RobustMax, Right),
+        ];
+        for (final_move, expected) in rules {
+            let tree = tree_with_moves(MctsConfig { final_move, ..MctsConfig::default() }, &moves);
+            assert_eq!(tree.get_best_direction(), expected, "{:?}", final_move);
+        }
+    }
+
+    #[test]
+    fn critical_roots_are_detected() {
+        use game::Direction::{Left, Up};
+        let config = MctsConfig { close_value_threshold: 0.02, ..MctsConfig::default() };
+        // Means of 100 and 99, within 2% of each other
+        let (best_visit_share, close_values) = tree_with_moves(config, &[(Up, 30, 3000.0, 0.0, 0.0), (Left, 10, 990.0, 0.0, 0.0)]).root_criticality();
+        assert_eq!((best_visit_share, close_values), (0.75, true));
+        let (_, close_values) = tree_with_moves(config, &[(Up, 30, 3000.0, 0.0, 0.0), (Left, 10, 900.0, 0.0, 0.0)]).root_criticality();
+        assert!(!close_values);
+    }
+
+    #[test]
+    fn timed_searches_follow_their_allocation() {
+        let fast = fastgame::FastGame::new();
+        let root_state = fast.place_block(fast.place_block([0;4], (0, 3), 1), (2, 1), 2);
+        // Without time left, a single chunk of iterations is run
+        let mut tree = MonteCarloTree::new(&fast, root_state, MctsConfig::default());
+        tree.grow_tree_timed(&fast, 0.0);
+        assert_eq!(tree.nodes.borrow()[0].visit_count, TIME_CONTROL_CHUNK);
+        // A single legal move (up) is played without thinking, whatever the time left
+        let only_up = fastgame::FastGame::from_flat_array([0, 0, 0, 0, 1, 2, 1, 2, 2, 1, 2, 1, 1, 2, 1, 2]);
+        assert_eq!(fast.get_possible_directions(&only_up), vec![game::Direction::Up]);
+        let mut tree = MonteCarloTree::new(&fast, only_up, MctsConfig::default());
+        assert!(tree.grow_tree_timed(&fast, 100.0) < 1.0);
+        assert_eq!(tree.nodes.borrow()[0].visit_count, 1);
+        // A move always dominating stops the search after MIN_TIME_SHARE of the 0.4s allocation
+        let config = MctsConfig { time_horizon: 10.0, dominance_share: 0.0, ..MctsConfig::default() };
+        let mut tree = MonteCarloTree::new(&fast, root_state, config);
+        let spent = tree.grow_tree_timed(&fast, 4.0);
+        assert!((MIN_TIME_SHARE * 0.4..0.4).contains(&spent), "spent {}", spent);
+    }
+
+    // Far deeper than a recursive descent could go on a test thread's stack
+    #[test]
+    fn deep_paths_are_selected_and_backpropagated_iteratively() {
+        const DEPTH: usize = 200_000;
+        let fast = fastgame::FastGame::new();
+        let mut tree = MonteCarloTree::new(&fast, [0;4], MctsConfig::default());
+        {
+            let mut nodes = tree.nodes.borrow_mut();
+            nodes[0].is_terminal = false;
+            nodes[0].visit_count = 1;
+            for depth in 1..=DEPTH {
+                let specific_information = if depth % 2 == 1 {
+                    TypeInfo::Spawn(SpawnInfo {
+                        move_made: game::Direction::Up,
+                        two_block_spawns_left: Vec::new(),
+                        four_block_spawns_left: Vec::new(),
+                        total_value: 0.0,
+                        total_squares: 0.0,
+                        total_powers: 0.0,
+                        prior: 1.0,
+                    })
+                } else {
+                    TypeInfo::Move(MoveInfo { actions_left: Vec::new(), probability: 0.9, priors: None })
+                };
+                nodes.push(Node {
+                    game_state: [0;4],
+                    parent_index: Some(depth - 1),
+                    visit_count: 1,
+                    is_terminal: false,
+                    children_indices: Vec::new(),
+                    specific_information,
+                    move_number: depth,
+                    score: 0,
+                });
+                nodes[depth - 1].children_indices.push(depth);
+            }
+        }
+        let mut path = Vec::new();
+        assert_eq!(tree.selection(&mut path, &mut SmallRng::seed_from_u64(0)), DEPTH);
+        assert_eq!(path, (0..=DEPTH).collect::<Vec<_>>());
+        tree.backpropagation(&path, ([0;4], 0, 3));
+        let nodes = tree.nodes.borrow();
+        assert!(nodes.iter().all(|node| node.visit_count == 2));
+        for node in nodes.iter() {
+            if let TypeInfo::Spawn(spawn_info) = &node.specific_information {
+                assert_eq!((spawn_info.total_value, spawn_info.total_squares), (3.0, 9.0));
+            }
+        }
+    }
+
+    // Every iteration goes through the root and ends at a node it created, or at a terminal one
+    #[test]
+    fn grown_trees_count_every_visit_once() {
+        let fast = fastgame::FastGame::new();
+        let root_state = fast.place_block(fast.place_block([0;4], (0, 3), 1), (2, 1), 2);
+        for chance_sampling in [ChanceSampling::Enumerate, ChanceSampling::Grouped] {
+            let mut tree = MonteCarloTree::new(&fast, root_state, MctsConfig { chance_sampling, ..MctsConfig::default() });
+            tree.grow_tree(&fast, 0.0, 3000);
+            let nodes = tree.nodes.borrow();
+            assert_eq!(nodes[0].visit_count, 3000);
+            for (index, node) in nodes.iter().enumerate() {
+                let children_visits: usize = node.children_indices.iter().map(|&child_index| nodes[child_index].visit_count).sum();
+                let own_visits = if index == 0 {0} else {1};
+                if node.is_terminal {
+                    assert!(node.children_indices.is_empty() && node.visit_count >= 1);
+                } else {
+                    assert_eq!(node.visit_count, children_visits + own_visits, "node {}", index);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gamma_samples_have_the_right_mean_and_variance() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        // Gamma(shape, 1) has mean and variance equal to its shape
+        for shape in [0.3, 0.5, 1.0, 2.5] {
+            let samples:Vec<f32> = (0..20_000).map(|_| MonteCarloTree::gamma_sample(shape, &mut rng)).collect();
+            let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+            let variance = samples.iter().map(|sample| (sample - mean).powi(2)).sum::<f32>() / samples.len() as f32;
+            assert!(samples.iter().all(|&sample| sample >= 0.0 && sample.is_finite()));
+            assert!((mean - shape).abs() < 0.05 * shape.max(1.0), "shape {}: mean {}", shape, mean);
+            assert!((variance - shape).abs() < 0.15 * shape.max(1.0), "shape {}: variance {}", shape, variance);
+        }
+    }
+
+    #[test]
+    fn root_noise_keeps_a_distribution_over_the_legal_moves() {
+        let fast = fastgame::FastGame::new();
+        let config = MctsConfig { root_noise_weight: 0.25, ..MctsConfig::default() };
+        let tree = MonteCarloTree::new(&fast, [0;4], config);
+        let mut rng = SmallRng::seed_from_u64(2);
+        let priors = [0.1, 0.0, 0.6, 0.3];
+        let mut noisy_priors = priors;
+        tree.add_root_noise(&mut noisy_priors, &[0, 2, 3], &mut rng);
+        assert!((noisy_priors.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        assert_eq!(noisy_priors[1], 0.0);
+        assert_ne!(noisy_priors, priors);
+        for direction in [0, 2, 3] {
+            assert!(noisy_priors[direction] >= 0.75 * priors[direction]);
+        }
     }
 }
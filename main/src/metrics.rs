@@ -0,0 +1,299 @@
+// Per-generation training metrics, appended to a CSV or JSON lines log so that runs can be compared afterwards
+use crate::fastgame::MAX_BLOCK_EXPONENT;
+use crate::population::{Agent, GaConfig};
+use std::io::Write;
+
+// Tiles counted in the distribution of the agents' highest tiles, 2^1 up to the highest tile of FastGame
+const TILE_EXPONENTS: usize = MAX_BLOCK_EXPONENT as usize;
+// Characters of the sparklines, from the lowest to the highest value
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+// Longer runs are averaged over buckets of generations to fit in this many characters
+pub const SPARKLINE_WIDTH: usize = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetricsFormat {
+    Csv,
+    JsonLines,
+}
+
+impl std::str::FromStr for MetricsFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(MetricsFormat::Csv),
+            "jsonl" => Ok(MetricsFormat::JsonLines),
+            _ => Err("expected csv or jsonl".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for MetricsFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MetricsFormat::Csv => write!(f, "csv"),
+            MetricsFormat::JsonLines => write!(f, "jsonl"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GenerationMetrics {
+    pub generation: u64,
+    // Fitness of the best single game
+    pub best_fitness: f32,
+    // Statistics of the agents' geometric means
+    pub mean_fitness: f32,
+    pub median_fitness: f32,
+    pub best_geometric_mean: f32,
+    // Number of agents whose highest tile is 2^(i + 1)
+    pub max_tiles: [usize; TILE_EXPONENTS],
+    pub mutation_rate: f32,
+    pub mutation_strength: f32,
+    // Seconds spent on the generation
+    pub wall_time: f32,
+    // Best validation score of the generation's candidates, 0 without validation
+    pub validation_fitness: f32,
+}
+
+// Numeric columns of the logs, in order
+const COLUMNS: [&str; 9] = [
+    "generation",
+    "best_fitness",
+    "mean_fitness",
+    "median_fitness",
+    "best_geometric_mean",
+    "mutation_rate",
+    "mutation_strength",
+    "wall_time",
+    "validation_fitness",
+];
+
+impl GenerationMetrics {
+    // Metrics of a population that has just been run
+    pub fn from_population(
+        generation: u64,
+        agents: &mut [Agent],
+        config: &GaConfig,
+        wall_time: f32,
+        validation_fitness: f32,
+    ) -> GenerationMetrics {
+        let mut geometric_means: Vec<f32> = agents.iter_mut().map(|agent| agent.geometric_mean()).collect();
+        geometric_means.sort_by(|a, b| a.total_cmp(b));
+        let mut max_tiles = [0; TILE_EXPONENTS];
+        for agent in agents.iter() {
+            let exponent = (agent.highest_tile as usize).clamp(1, TILE_EXPONENTS);
+            max_tiles[exponent - 1] += 1;
+        }
+        GenerationMetrics {
+            generation,
+            best_fitness: agents.iter().map(|agent| agent.best_run()).fold(f32::NEG_INFINITY, f32::max),
+            mean_fitness: geometric_means.iter().sum::<f32>() / geometric_means.len().max(1) as f32,
+            median_fitness: geometric_means.get(geometric_means.len() / 2).cloned().unwrap_or(0.0),
+            best_geometric_mean: geometric_means.last().cloned().unwrap_or(0.0),
+            max_tiles,
+            mutation_rate: config.mutation_rate,
+            mutation_strength: config.mutation_strength,
+            wall_time,
+            validation_fitness,
+        }
+    }
+
+    // Values of the COLUMNS, as written in the logs
+    fn values(&self) -> [String; 9] {
+        [
+            self.generation.to_string(),
+            self.best_fitness.to_string(),
+            self.mean_fitness.to_string(),
+            self.median_fitness.to_string(),
+            self.best_geometric_mean.to_string(),
+            self.mutation_rate.to_string(),
+            self.mutation_strength.to_string(),
+            self.wall_time.to_string(),
+            self.validation_fitness.to_string(),
+        ]
+    }
+
+    fn from_values(values: [f64; 9]) -> GenerationMetrics {
+        GenerationMetrics {
+            generation: values[0] as u64,
+            best_fitness: values[1] as f32,
+            mean_fitness: values[2] as f32,
+            median_fitness: values[3] as f32,
+            best_geometric_mean: values[4] as f32,
+            mutation_rate: values[5] as f32,
+            mutation_strength: values[6] as f32,
+            wall_time: values[7] as f32,
+            validation_fitness: values[8] as f32,
+            ..GenerationMetrics::default()
+        }
+    }
+
+    // Non empty entries of the distribution, such as "256:12 512:3"
+    fn max_tiles_string(&self) -> String {
+        (0..TILE_EXPONENTS)
+            .filter(|&i| self.max_tiles[i] > 0)
+            .map(|i| format!("{}:{}", 1u32 << (i + 1), self.max_tiles[i]))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    fn to_csv(&self) -> String {
+        format!("{},{}", self.values().join(","), self.max_tiles_string())
+    }
+
+    // JSON has no NaN or infinities, they are written as null
+    fn to_json(&self) -> String {
+        let values: Vec<String> = COLUMNS
+            .iter()
+            .zip(self.values())
+            .map(|(column, value)| {
+                let is_finite = value.parse::<f64>().is_ok_and(f64::is_finite);
+                format!("\"{}\":{}", column, if is_finite { value.as_str() } else { "null" })
+            })
+            .collect();
+        let max_tiles: Vec<String> = (0..TILE_EXPONENTS)
+            .filter(|&i| self.max_tiles[i] > 0)
+            .map(|i| format!("\"{}\":{}", 1u32 << (i + 1), self.max_tiles[i]))
+            .collect();
+        format!("{{{},\"max_tiles\":{{{}}}}}", values.join(","), max_tiles.join(","))
+    }
+}
+
+pub fn log_path(name: &str, format: MetricsFormat) -> String {
+    format!("logs/{}.{}", name, format)
+}
+
+// Appends the metrics to the log, which is created (with a header for CSV) if needed
+pub fn append(path: &str, format: MetricsFormat, metrics: &GenerationMetrics) -> std::io::Result<()> {
+    if let Some(directory) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let is_new = !std::path::Path::new(path).exists();
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    match format {
+        MetricsFormat::Csv => {
+            if is_new {
+                writeln!(file, "{},max_tiles", COLUMNS.join(","))?;
+            }
+            writeln!(file, "{}", metrics.to_csv())
+        }
+        MetricsFormat::JsonLines => writeln!(file, "{}", metrics.to_json()),
+    }
+}
+
+// Reads the numeric columns of a log written by append, the format being given by the extension.
+// Columns absent from logs written before they existed are read as 0, and null JSON values as NaN
+pub fn load(path: &str) -> Result<Vec<GenerationMetrics>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|error| format!("Could not read {} : {}", path, error))?;
+    let json = path.ends_with(".jsonl");
+    let header: Vec<&str> = if json { Vec::new() } else { contents.lines().next().unwrap_or("").split(',').collect() };
+    let mut history = Vec::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || (!json && line_number == 0) {
+            continue;
+        }
+        let mut values = [0.0; 9];
+        for (i, column) in COLUMNS.iter().enumerate() {
+            let value = if json {
+                // The numeric fields are flat, the value ends at the next comma
+                match line.split_once(&format!("\"{}\":", column)) {
+                    Some((_, rest)) => rest.split([',', '}']).next(),
+                    None => continue,
+                }
+            } else {
+                match header.iter().position(|name| name == column) {
+                    Some(position) => line.split(',').nth(position),
+                    None => continue,
+                }
+            };
+            values[i] = value
+                .and_then(|value| match value.trim() {
+                    "null" if json => Some(f64::NAN),
+                    value => value.parse().ok(),
+                })
+                .ok_or(format!("Line {} of {}: missing or invalid {}", line_number + 1, path, column))?;
+        }
+        history.push(GenerationMetrics::from_values(values));
+    }
+    Ok(history)
+}
+
+// One character per value (or per bucket of values beyond SPARKLINE_WIDTH values), scaled between the lowest
+// and the highest value
+pub fn sparkline(values: &[f32]) -> String {
+    let values: Vec<f32> = values
+        .chunks(values.len().div_ceil(SPARKLINE_WIDTH).max(1))
+        .map(|bucket| bucket.iter().sum::<f32>() / bucket.len() as f32)
+        .collect();
+    let lowest = values.iter().cloned().fold(f32::INFINITY, f32::min);
+    let highest = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    values
+        .iter()
+        .map(|&value| {
+            let position = if highest > lowest { (value - lowest) / (highest - lowest) } else { 0.5 };
+            SPARKS[((position * (SPARKS.len() - 1) as f32).round() as usize).min(SPARKS.len() - 1)]
+        })
+        .collect()
+}
+
+// Summary of a run: one row every few generations, then sparklines of the whole run
+pub fn summary_table(history: &[GenerationMetrics], rows: usize) -> String {
+    let mut table = format!(
+        "{:>10} {:>14} {:>14} {:>14} {:>14} {:>14} {:>10}\n",
+        "generation", "best game", "mean", "median", "best agent", "validation", "time (s)"
+    );
+    let step = history.len().div_ceil(rows.max(1)).max(1);
+    for (i, metrics) in history.iter().enumerate() {
+        if i % step == 0 || i == history.len() - 1 {
+            table += &format!(
+                "{:>10} {:>14.1} {:>14.1} {:>14.1} {:>14.1} {:>14.1} {:>10.2}\n",
+                metrics.generation,
+                metrics.best_fitness,
+                metrics.mean_fitness,
+                metrics.median_fitness,
+                metrics.best_geometric_mean,
+                metrics.validation_fitness,
+                metrics.wall_time
+            );
+        }
+    }
+    let best_agents: Vec<f32> = history.iter().map(|metrics| metrics.best_geometric_mean).collect();
+    let means: Vec<f32> = history.iter().map(|metrics| metrics.mean_fitness).collect();
+    table += &format!("best agent {}\n", sparkline(&best_agents));
+    table += &format!("mean       {}\n", sparkline(&means));
+    table += &format!(
+        "total time {:.1} s\n",
+        history.iter().map(|metrics| metrics.wall_time).sum::<f32>()
+    );
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_values_are_valid_json() {
+        let metrics = GenerationMetrics {
+            generation: 3,
+            best_fitness: f32::NEG_INFINITY,
+            mean_fitness: f32::NAN,
+            median_fitness: f32::INFINITY,
+            best_geometric_mean: 12.5,
+            ..GenerationMetrics::default()
+        };
+        let json = metrics.to_json();
+        assert!(json.starts_with("{\"generation\":3,\"best_fitness\":null,\"mean_fitness\":null,\"median_fitness\":null,"));
+        assert!(json.contains("\"best_geometric_mean\":12.5,"));
+        assert!(!json.contains("inf") && !json.contains("NaN"));
+
+        let path = std::env::temp_dir().join(format!("metrics_{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, format!("{}\n", json)).unwrap();
+        let history = load(path);
+        std::fs::remove_file(path).unwrap();
+        let loaded = &history.unwrap()[0];
+        assert!(loaded.best_fitness.is_nan() && loaded.mean_fitness.is_nan());
+        assert_eq!(loaded.best_geometric_mean, 12.5);
+    }
+}
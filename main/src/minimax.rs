@@ -5,6 +5,30 @@ use std::collections::HashMap;
 use rand::rngs::SmallRng;
 use rand::SeedableRng;
 use rand::Rng;
+use crate::config::Config;
+use crate::ntuple::NTupleNetwork;
+#[cfg(feature = "node-count")]
+use std::cell::Cell;
+
+#[cfg(feature = "node-count")]
+thread_local! {
+    // Nodes visited by minimax and expectimax on this thread, read by the benchmarks
+    static NODES: Cell<u64> = const { Cell::new(0) };
+}
+
+// Counts a searched node, only when built with the node-count feature so that normal searches do not pay for it
+#[inline(always)]
+fn count_node() {
+    #[cfg(feature = "node-count")]
+    NODES.with(|nodes| nodes.set(nodes.get() + 1));
+}
+
+// Returns the number of nodes searched on the calling thread since the last call, and resets it.
+// Only the benchmarks call it
+#[cfg(feature = "node-count")]
+pub fn take_node_count() -> u64 {
+    NODES.with(|nodes| nodes.replace(0))
+}
 
 struct TTEntryMini {
         depth: usize,
@@ -23,7 +47,80 @@ enum NodeType {
     Upperbound,
 }
 
-pub fn get_best_direction_minimax(game: &FastGame, grid: [u32; 4], search_depth: usize) -> game::Direction {
+// Coefficients of the terms of evaluate, which can be tuned by CMA-ES
+#[derive(Clone, Copy, Debug)]
+pub struct EvaluationWeights {
+    pub empty_cells: f32,
+    pub big_values: f32,
+    pub smoothness: f32,
+    pub monotonicity: f32,
+}
+
+impl Default for EvaluationWeights {
+    fn default() -> Self {
+        EvaluationWeights {
+            empty_cells: 10.0,
+            big_values: 10.0,
+            smoothness: -1.0,
+            monotonicity: 0.0,
+        }
+    }
+}
+
+impl EvaluationWeights {
+    pub fn load(path: &str) -> Result<EvaluationWeights, String> {
+        let config = Config::load(path)?;
+        let default = EvaluationWeights::default();
+        Ok(EvaluationWeights {
+            empty_cells: config.get("empty_cells", default.empty_cells)?,
+            big_values: config.get("big_values", default.big_values)?,
+            smoothness: config.get("smoothness", default.smoothness)?,
+            monotonicity: config.get("monotonicity", default.monotonicity)?,
+        })
+    }
+
+    // Writes the weights in the config format read by load
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let contents = format!(
+            "empty_cells = {}\nbig_values = {}\nsmoothness = {}\nmonotonicity = {}\n",
+            self.empty_cells, self.big_values, self.smoothness, self.monotonicity
+        );
+        std::fs::write(path, contents)
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        [self.empty_cells, self.big_values, self.smoothness, self.monotonicity]
+    }
+
+    pub fn from_array(weights: [f32; 4]) -> EvaluationWeights {
+        EvaluationWeights {
+            empty_cells: weights[0],
+            big_values: weights[1],
+            smoothness: weights[2],
+            monotonicity: weights[3],
+        }
+    }
+}
+
+// Evaluation of the leaves of expectimax
+#[derive(Clone, Copy)]
+pub enum LeafEvaluator<'a> {
+    Heuristic(&'a EvaluationWeights),
+    // Value of an afterstate, or of its best move for a grid before the move
+    NTuple(&'a NTupleNetwork),
+}
+
+impl LeafEvaluator<'_> {
+    fn evaluate(&self, game: &FastGame, grid: [u32; 4], is_player: bool) -> f32 {
+        match self {
+            LeafEvaluator::Heuristic(weights) => evaluate(grid, weights),
+            LeafEvaluator::NTuple(network) if is_player => network.state_value(game, &grid),
+            LeafEvaluator::NTuple(network) => network.value(&grid),
+        }
+    }
+}
+
+pub fn get_best_direction_minimax(game: &FastGame, grid: [u32; 4], search_depth: usize, weights: &EvaluationWeights) -> game::Direction {
     // Returns the direction with the best minimax evaluation
     let best_direction = game.get_possible_directions(&grid)
         .par_iter()
@@ -39,6 +136,7 @@ pub fn get_best_direction_minimax(game: &FastGame, grid: [u32; 4], search_depth:
                 f32::NEG_INFINITY,
                 f32::INFINITY,
                 &mut tt,
+                weights,
             );
             (direction, score)
         })
@@ -49,7 +147,11 @@ pub fn get_best_direction_minimax(game: &FastGame, grid: [u32; 4], search_depth:
 }
 
 
-pub fn get_best_direction_expectimax(game: &FastGame, grid: [u32; 4], search_depth: usize) -> game::Direction {
+pub fn get_best_direction_expectimax(game: &FastGame, grid: [u32; 4], search_depth: usize, weights: &EvaluationWeights) -> game::Direction {
+    get_best_direction_expectimax_with(game, grid, search_depth, LeafEvaluator::Heuristic(weights))
+}
+
+pub fn get_best_direction_expectimax_with(game: &FastGame, grid: [u32; 4], search_depth: usize, leaf: LeafEvaluator) -> game::Direction {
     // Returns the direction with the best expectimax evaluation
     let best_direction = game.get_possible_directions(&grid)
         .par_iter()
@@ -64,6 +166,7 @@ pub fn get_best_direction_expectimax(game: &FastGame, grid: [u32; 4], search_dep
                 false,
                 &mut tt,
                 0,
+                leaf,
             );
             (direction, score)
         })
@@ -73,13 +176,29 @@ pub fn get_best_direction_expectimax(game: &FastGame, grid: [u32; 4], search_dep
         return best_direction;
 }
 
-pub fn evaluate(grid: [u32; 4]) -> f32 {
+// Expectimax evaluation of every move in game::DIRECTIONS order, f32::NEG_INFINITY for the impossible ones
+pub fn expectimax_direction_values(game: &FastGame, grid: [u32; 4], search_depth: usize, weights: &EvaluationWeights) -> [f32; 4] {
+    let values: Vec<f32> = game::DIRECTIONS
+        .par_iter()
+        .map(|direction| {
+            if !game.can_move(&grid, direction) {
+                return f32::NEG_INFINITY;
+            }
+            let mut tt = HashMap::new();
+            let (new_grid, _) = game.make_move(&grid, direction);
+            expectimax(game, new_grid, search_depth, false, &mut tt, 0, LeafEvaluator::Heuristic(weights))
+        })
+        .collect();
+    [values[0], values[1], values[2], values[3]]
+}
+
+pub fn evaluate(grid: [u32; 4], weights: &EvaluationWeights) -> f32 {
     let flat_grid = FastGame::to_flat_array(grid);
 
-    let big_values_infl:f32 = flat_grid.iter().map(|&value| {(1 << value) as f32}).sum();
+    let big_values_infl:f32 = flat_grid.iter().map(|&value| {(1u64 << value) as f32}).sum();
 
     // Monotonicity: measure how aligned tiles are in a single direction
-    let _monotonicity_horizontal = 
+    let monotonicity_horizontal = 
         (0..4).map(|row| {
             let start = row * 4;
             let row_values = &flat_grid[start..start+4];
@@ -88,7 +207,7 @@ pub fn evaluate(grid: [u32; 4]) -> f32 {
                 .sum::<f32>()
         }).sum::<f32>();
 
-    let _monotonicity_vertical = 
+    let monotonicity_vertical = 
         (0..4).map(|col| {
             let column_values = [
                 flat_grid[col],
@@ -126,10 +245,10 @@ pub fn evaluate(grid: [u32; 4]) -> f32 {
 
     // Empty cells bonus
     let empty_cells_bonus = FastGame::empty_list(&grid).len() as f32;
-    return 10.0*empty_cells_bonus
-           + 10.0*big_values_infl
-           - 1.0*smoothness_vertical
-           - 1.0*smoothness_horizontal;
+    return weights.empty_cells*empty_cells_bonus
+           + weights.big_values*big_values_infl
+           + weights.smoothness*(smoothness_vertical + smoothness_horizontal)
+           + weights.monotonicity*(monotonicity_vertical + monotonicity_horizontal);
 }
 fn _evaluate_rollout(fast:&FastGame, grid: [u32;4]) -> f32 {
     // Try to evaluate by doing a single rollout from the starting grid
@@ -179,6 +298,7 @@ pub fn _basile_heuristique(grid: [u32; 4]) -> f32 {
     return total_reward;
 }
 
+#[allow(clippy::too_many_arguments)]
 fn minimax(
     game: &FastGame,
     grid: [u32; 4],
@@ -187,8 +307,10 @@ fn minimax(
     mut alpha: f32,
     mut beta: f32,
     tt: &mut HashMap<[u32; 4], TTEntryMini>,
+    weights: &EvaluationWeights,
 ) -> f32 {
     // Returns the minimax value of the board with a grid that has been moved in the direction but no block added
+    count_node();
 
     // Check if the grid is in the transposition table
     if let Some(entry) = tt.get(&grid) {
@@ -209,10 +331,10 @@ fn minimax(
 
     // If node is final, return its evaluation
     if game.is_lost(&grid){
-        return evaluate(grid) + f32::NEG_INFINITY;
+        return evaluate(grid, weights) + f32::NEG_INFINITY;
     }
     if depth == 0 {
-        return evaluate(grid);
+        return evaluate(grid, weights);
     }
 
     let mut value;
@@ -223,7 +345,7 @@ fn minimax(
         value = f32::NEG_INFINITY;
         for direction in game.get_possible_directions(&grid) {
             let (new_grid, _score) = game.make_move(&grid, &direction);
-            value = value.max(minimax(game, new_grid, depth - 1, false, alpha, beta, tt));
+            value = value.max(minimax(game, new_grid, depth - 1, false, alpha, beta, tt, weights));
             alpha = alpha.max(value);
             if alpha >= beta {
                 // Beta cutoff
@@ -237,7 +359,7 @@ fn minimax(
         for empty in FastGame::empty_list(&grid) {
             // Spawn a 2
             let new_grid = game.place_block(grid, empty, 1);
-            value = value.min(minimax(game, new_grid, depth - 1, true, alpha, beta, tt));
+            value = value.min(minimax(game, new_grid, depth - 1, true, alpha, beta, tt, weights));
             beta = beta.min(value);
             if beta <= alpha {
                 // Alpha cutoff
@@ -245,7 +367,7 @@ fn minimax(
             }
             // Spawn a 4
             let new_grid = game.place_block(grid, empty, 2);
-            value = value.min(minimax(game, new_grid, depth - 1, true, alpha, beta, tt));
+            value = value.min(minimax(game, new_grid, depth - 1, true, alpha, beta, tt, weights));
             let beta = beta.min(value);
             if beta <= alpha {
                 // Alpha cutoff
@@ -275,7 +397,9 @@ fn expectimax(
     is_player: bool,
     tt: &mut HashMap<[u32; 4], TTEntryExpecti>,
     branch_score: u32,
+    leaf: LeafEvaluator,
 ) -> f32 {
+    count_node();
     if let Some(entry) = tt.get(&grid) {
         if entry.depth >= depth {    
             return entry.value;
@@ -287,7 +411,7 @@ fn expectimax(
         return -1000.0;
     }
     if depth <= 0 {
-        return evaluate(grid) + branch_score as f32;
+        return leaf.evaluate(game, grid, is_player) + branch_score as f32;
     }
     let value:f32;
     if is_player {
@@ -296,7 +420,7 @@ fn expectimax(
             .iter()  // Use Rayon's parallel iterator
             .map(|direction| {
                 let (new_grid, score) = game.make_move(&grid, &direction);
-                expectimax(game, new_grid, depth - 1, false, tt, branch_score + score)
+                expectimax(game, new_grid, depth - 1, false, tt, branch_score + score, leaf)
             })
             .max_by(|a, b| a.partial_cmp(b).unwrap())
             .unwrap()
@@ -308,9 +432,9 @@ fn expectimax(
         value = empty_cells.iter()
             .flat_map(|&empty| [
                 // Probability of 2 spawn (90%)
-                expectimax(game, game.place_block(grid, empty, 1), depth - 1, true, tt, branch_score) * 0.9,
+                expectimax(game, game.place_block(grid, empty, 1), depth - 1, true, tt, branch_score, leaf) * 0.9,
                 // Probability of 4 spawn (10%)
-                expectimax(game, game.place_block(grid, empty, 2), depth - 1, true, tt, branch_score) * 0.1
+                expectimax(game, game.place_block(grid, empty, 2), depth - 1, true, tt, branch_score, leaf) * 0.1
             ])
             .sum::<f32>() / (total_cells * 2) as f32
     }
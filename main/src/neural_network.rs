@@ -2,35 +2,264 @@ use std::io::{Read, Write};
 
 use rand::Rng;
 
+use crate::encoding::{CellEncoding, InputEncoding};
+
+// Binary network files start with MAGIC and FORMAT_VERSION, and end with a CRC32 of their content
+const MAGIC: &[u8; 4] = b"NTWK";
+// Version 1 stored a hidden and an output activation, version 2 stores one activation per layer,
+// version 3 adds the input encoding (older files use the default one)
+const FORMAT_VERSION: u16 = 3;
+
+// Training information stored along with the network
+#[derive(Clone, Debug)]
+pub struct NetworkMetadata {
+    pub generation: usize,
+    pub fitness: f32,
+    // Unix timestamp of the save, in seconds
+    pub saved_at: u64,
+}
+
+impl NetworkMetadata {
+    pub fn new(generation: usize, fitness: f32) -> NetworkMetadata {
+        let saved_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        NetworkMetadata { generation, fitness, saved_at }
+    }
+}
+
+#[derive(Debug)]
+pub enum NetworkError {
+    Io(std::io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Truncated,
+    TrailingData,
+    InvalidLayers,
+    InvalidActivation(usize),
+    InvalidEncoding(usize),
+    InvalidTuples,
+    // Line number (starting at 1) and content of an unparsable line of a legacy file
+    InvalidLine(usize, String),
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NetworkError::Io(error) => write!(f, "{}", error),
+            NetworkError::InvalidMagic => write!(f, "not a network file"),
+            NetworkError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            NetworkError::ChecksumMismatch => write!(f, "checksum mismatch, the file is corrupted"),
+            NetworkError::Truncated => write!(f, "the file is truncated"),
+            NetworkError::TrailingData => write!(f, "unexpected data after the network"),
+            NetworkError::InvalidLayers => write!(f, "invalid layer sizes"),
+            NetworkError::InvalidActivation(index) => write!(f, "unknown activation function {}", index),
+            NetworkError::InvalidEncoding(index) => write!(f, "unknown input encoding {}", index),
+            NetworkError::InvalidTuples => write!(f, "invalid n-tuples"),
+            NetworkError::InvalidLine(line, content) => write!(f, "invalid value \"{}\" at line {}", content, line),
+        }
+    }
+}
+
+// Little endian reader over the bytes of a network file
+pub(crate) struct ByteReader<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) position: usize,
+}
+
+impl ByteReader<'_> {
+    fn read<const N: usize>(&mut self) -> Result<[u8; N], NetworkError> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + N)
+            .ok_or(NetworkError::Truncated)?;
+        self.position += N;
+        Ok(bytes.try_into().unwrap())
+    }
+    pub(crate) fn read_u8(&mut self) -> Result<u8, NetworkError> {
+        Ok(self.read::<1>()?[0])
+    }
+    pub(crate) fn read_u16(&mut self) -> Result<u16, NetworkError> {
+        Ok(u16::from_le_bytes(self.read()?))
+    }
+    pub(crate) fn read_u32(&mut self) -> Result<u32, NetworkError> {
+        Ok(u32::from_le_bytes(self.read()?))
+    }
+    pub(crate) fn read_u64(&mut self) -> Result<u64, NetworkError> {
+        Ok(u64::from_le_bytes(self.read()?))
+    }
+    pub(crate) fn read_f32(&mut self) -> Result<f32, NetworkError> {
+        Ok(f32::from_le_bytes(self.read()?))
+    }
+    pub(crate) fn remaining(&self) -> usize {
+        self.bytes.len().saturating_sub(self.position)
+    }
+}
+
+fn parse_line<T: std::str::FromStr>(contents: &[&str], index: usize) -> Result<T, NetworkError> {
+    let line = contents.get(index).ok_or(NetworkError::Truncated)?;
+    line.trim()
+        .parse()
+        .map_err(|_| NetworkError::InvalidLine(index + 1, line.to_string()))
+}
+
+// CRC-32 (IEEE), as used by zip and png
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Number of output neurons computed together in the batched forward pass
+const ROW_BLOCK_SIZE: usize = 16;
+
+// Scratch memory of the batched forward pass, holding the activations of two consecutive layers
+// (and the encoded board when evaluating one)
+#[derive(Default)]
+pub struct ForwardBuffers {
+    current: Vec<f32>,
+    next: Vec<f32>,
+    inputs: Vec<f32>,
+}
+
+// Inner product written with 8 independent accumulators so that it gets vectorized
+#[inline]
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let mut sums = [0.0f32; 8];
+    let a_chunks = a.chunks_exact(8);
+    let b_chunks = b.chunks_exact(8);
+    let remainder: f32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+    for (a_chunk, b_chunk) in a_chunks.zip(b_chunks) {
+        for i in 0..8 {
+            sums[i] += a_chunk[i] * b_chunk[i];
+        }
+    }
+    sums.iter().sum::<f32>() + remainder
+}
+
+// How the weights of a new network are drawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Initialization {
+    // Weights and bias drawn uniformly from the given ranges
+    Uniform,
+    // Uniform in +-sqrt(6 / (fan_in + fan_out)), suited to sigmoid, tanh and linear layers, with bias at 0
+    Xavier,
+    // Uniform in +-sqrt(6 / fan_in), suited to the ReLU family, with bias at 0
+    He,
+}
+
+impl std::str::FromStr for Initialization {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(Initialization::Uniform),
+            "xavier" => Ok(Initialization::Xavier),
+            "he" => Ok(Initialization::He),
+            _ => Err("expected uniform, xavier or he".to_string()),
+        }
+    }
+}
+
+// Gradient of a loss with respect to every weight and bias, laid out like them
+#[derive(Clone)]
+pub struct Gradients {
+    pub weights: Vec<f32>,
+    pub bias: Vec<f32>,
+}
+
+impl Gradients {
+    pub fn zeros(network: &NeuralNetwork) -> Gradients {
+        Gradients {
+            weights: vec![0.0; network.weights.len()],
+            bias: vec![0.0; network.bias.len()],
+        }
+    }
+
+    pub fn add(&mut self, other: &Gradients) {
+        for (gradient, other) in self.weights.iter_mut().zip(&other.weights) {
+            *gradient += other;
+        }
+        for (gradient, other) in self.bias.iter_mut().zip(&other.bias) {
+            *gradient += other;
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct NeuralNetwork {
     pub weights: Vec<f32>,
     pub bias: Vec<f32>,
     layers: Vec<u32>,
-    activation_func_hidden: fn(f32) -> f32,
-    activation_func_output: fn(f32) -> f32,
+    // Activation of every layer but the input one
+    activations: Vec<Activation>,
+    encoding: InputEncoding,
 }
 
 impl NeuralNetwork {
     pub fn new(
         layers: Vec<u32>,
-        activation_func_hidden: usize,
-        activation_func_output: usize,
+        activations: Vec<Activation>,
+        encoding: InputEncoding,
+        initial_weight_range: (f32, f32),
+        initial_bias_range: (f32, f32),
+    ) -> NeuralNetwork {
+        Self::initialized(layers, activations, encoding, Initialization::Uniform, initial_weight_range, initial_bias_range)
+    }
+
+    // Network whose weights are drawn with the initialization, the ranges only being used by Initialization::Uniform
+    pub fn initialized(
+        layers: Vec<u32>,
+        activations: Vec<Activation>,
+        encoding: InputEncoding,
+        initialization: Initialization,
         initial_weight_range: (f32, f32),
         initial_bias_range: (f32, f32),
     ) -> NeuralNetwork {
+        if activations.len() != layers.len() - 1 {
+            panic!("There must be one activation per layer, the input layer excepted");
+        }
+        if layers[0] as usize != encoding.input_size() {
+            panic!("The input layer does not match the input encoding");
+        }
         let mut weights = Vec::new();
         for i in 0..layers.len() - 1 {
+            let (fan_in, fan_out) = (layers[i] as f32, layers[i + 1] as f32);
+            let weight_range = match initialization {
+                Initialization::Uniform => initial_weight_range,
+                Initialization::Xavier => {
+                    let limit = (6.0 / (fan_in + fan_out)).sqrt();
+                    (-limit, limit)
+                }
+                Initialization::He => {
+                    let limit = (6.0 / fan_in).sqrt();
+                    (-limit, limit)
+                }
+            };
             for _ in 0..layers[i] * layers[i + 1] {
                 weights
-                    .push(rand::rng().random_range(initial_weight_range.0..initial_weight_range.1));
+                    .push(rand::rng().random_range(weight_range.0..weight_range.1));
             }
         }
 
         let mut bias = Vec::new();
         for i in 1..layers.len() {
             for _ in 0..layers[i] {
-                bias.push(rand::rng().random_range(initial_bias_range.0..initial_bias_range.1));
+                bias.push(match initialization {
+                    Initialization::Uniform => rand::rng().random_range(initial_bias_range.0..initial_bias_range.1),
+                    Initialization::Xavier | Initialization::He => 0.0,
+                });
             }
         }
 
@@ -38,156 +267,450 @@ impl NeuralNetwork {
             weights,
             bias,
             layers,
-            activation_func_hidden: ACTIVATION_FUNCTIONS[activation_func_hidden],
-            activation_func_output: ACTIVATION_FUNCTIONS[activation_func_output],
+            activations,
+            encoding,
+        }
+    }
+
+    // Reads a network saved by save, or in the legacy text format (one value per line)
+    pub fn load(path: &str) -> Result<(NeuralNetwork, NetworkMetadata), NetworkError> {
+        let mut bytes = Vec::new();
+        std::fs::File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(NetworkError::Io)?;
+        Self::from_file_bytes(&bytes)
+    }
+
+    // Reads the content of a network file, in either format
+    pub fn from_file_bytes(bytes: &[u8]) -> Result<(NeuralNetwork, NetworkMetadata), NetworkError> {
+        if bytes.starts_with(MAGIC) {
+            Self::from_bytes(bytes)
+        } else {
+            Self::from_legacy_text(bytes)
         }
     }
 
-    pub fn load(path: &str) -> (NeuralNetwork, usize) {
-        let mut file = std::fs::File::open(path).unwrap();
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
+    fn from_bytes(bytes: &[u8]) -> Result<(NeuralNetwork, NetworkMetadata), NetworkError> {
+        let mut reader = ByteReader { bytes, position: MAGIC.len() };
+        let version = reader.read_u16()?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(NetworkError::UnsupportedVersion(version));
+        }
+        let layers_len = reader.read_u32()? as usize;
+        let mut layers = Vec::new();
+        for _ in 0..layers_len.min(bytes.len()) {
+            layers.push(reader.read_u32()?);
+        }
+        let activations = if version == 1 {
+            let activation_func_hidden = reader.read_u8()? as usize;
+            let activation_func_output = reader.read_u8()? as usize;
+            Self::hidden_output_activations(layers.len(), activation_func_hidden, activation_func_output)?
+        } else {
+            let mut activations = Vec::new();
+            for _ in 1..layers.len() {
+                activations.push(Self::activation_function(reader.read_u8()? as usize)?);
+            }
+            activations
+        };
+        let encoding = if version >= 3 {
+            let cells = reader.read_u8()? as usize;
+            InputEncoding {
+                cells: CellEncoding::from_index(cells).ok_or(NetworkError::InvalidEncoding(cells))?,
+                symmetric: reader.read_u8()? != 0,
+            }
+        } else {
+            InputEncoding::default()
+        };
+        let metadata = NetworkMetadata {
+            generation: reader.read_u64()? as usize,
+            fitness: reader.read_f32()?,
+            saved_at: reader.read_u64()?,
+        };
+        let (weight_len, bias_len) = Self::parameter_counts(&layers)?;
+        if layers[0] as usize != encoding.input_size() {
+            return Err(NetworkError::InvalidLayers);
+        }
+        // Check the size before allocating anything, then the checksum which covers everything before it
+        let expected_remaining = weight_len
+            .checked_add(bias_len)
+            .and_then(|length| length.checked_mul(4))
+            .and_then(|length| length.checked_add(4))
+            .ok_or(NetworkError::InvalidLayers)?;
+        if reader.remaining() < expected_remaining {
+            return Err(NetworkError::Truncated);
+        }
+        if reader.remaining() > expected_remaining {
+            return Err(NetworkError::TrailingData);
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        // Fuzzing skips the checksum, which mutated files would almost never match, to reach the parsing after it
+        if !cfg!(fuzzing) && crc32(content) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(NetworkError::ChecksumMismatch);
+        }
+        let mut weights = Vec::with_capacity(weight_len);
+        for _ in 0..weight_len {
+            weights.push(reader.read_f32()?);
+        }
+        let mut bias = Vec::with_capacity(bias_len);
+        for _ in 0..bias_len {
+            bias.push(reader.read_f32()?);
+        }
+        Ok((
+            NeuralNetwork {
+                weights,
+                bias,
+                layers,
+                activations,
+                encoding,
+            },
+            metadata,
+        ))
+    }
+
+    fn from_legacy_text(bytes: &[u8]) -> Result<(NeuralNetwork, NetworkMetadata), NetworkError> {
+        let contents = std::str::from_utf8(bytes).map_err(|_| NetworkError::InvalidMagic)?;
         let contents: Vec<&str> = contents.split("\n").collect();
-        let generation: usize = contents[0].parse().unwrap();
-        let layers_len = contents[1].parse().unwrap();
+        let generation: usize = parse_line(&contents, 0)?;
+        let layers_len: usize = parse_line(&contents, 1)?;
         let mut layers = Vec::new();
-        for i in 0..layers_len {
-            layers.push(contents[i + 2].parse().unwrap());
+        for i in 0..layers_len.min(contents.len()) {
+            layers.push(parse_line(&contents, i + 2)?);
+        }
+        if layers.len() != layers_len {
+            return Err(NetworkError::Truncated);
         }
-        let activation_func_hidden: usize = contents[layers_len + 2].parse().unwrap();
-        let activation_func_output: usize = contents[layers_len + 3].parse().unwrap();
+        let activation_func_hidden: usize = parse_line(&contents, layers_len + 2)?;
+        let activation_func_output: usize = parse_line(&contents, layers_len + 3)?;
 
         // Calculate the number of weights and bias
-        let mut weight_len = 0;
-        for i in 0..layers.len() - 1 {
-            weight_len += (layers[i] * layers[i + 1]) as usize;
-        }
-        let mut bias_len = 0;
-        for i in 1..layers.len() {
-            bias_len += layers[i] as usize;
+        let (weight_len, bias_len) = Self::parameter_counts(&layers)?;
+        if contents.len() - (layers_len + 4) < weight_len.saturating_add(bias_len) {
+            return Err(NetworkError::Truncated);
         }
 
-        let mut weights = Vec::new();
+        let mut weights = Vec::with_capacity(weight_len);
         for i in 0..weight_len {
-            weights.push(contents[layers_len + 4 + i].parse().unwrap());
+            weights.push(parse_line(&contents, layers_len + 4 + i)?);
         }
 
-        let mut bias = Vec::new();
+        let mut bias = Vec::with_capacity(bias_len);
         for i in 0..bias_len {
-            bias.push(contents[layers_len + 4 + weight_len + i].parse().unwrap());
+            bias.push(parse_line(&contents, layers_len + 4 + weight_len + i)?);
         }
 
-        (
+        let activations = Self::hidden_output_activations(layers.len(), activation_func_hidden, activation_func_output)?;
+        let encoding = InputEncoding::default();
+        if layers[0] as usize != encoding.input_size() {
+            return Err(NetworkError::InvalidLayers);
+        }
+        Ok((
             NeuralNetwork {
                 weights,
                 bias,
                 layers,
-                activation_func_hidden: ACTIVATION_FUNCTIONS[activation_func_hidden],
-                activation_func_output: ACTIVATION_FUNCTIONS[activation_func_output],
+                activations,
+                encoding,
             },
-            generation,
-        )
-    }
-
-    pub fn save(&self, path: &str, generation: usize) {
-        let mut file = std::fs::File::create(path).unwrap();
-        // First save all metadata (generation, layers, weight_range, bias_range)
-        file.write_all(generation.to_string().as_bytes()).unwrap();
-        file.write_all("\n".as_bytes()).unwrap();
-        file.write_all(self.layers.len().to_string().as_bytes())
-            .unwrap();
-        file.write_all("\n".as_bytes()).unwrap();
-        for i in 0..self.layers.len() {
-            file.write_all(self.layers[i].to_string().as_bytes())
-                .unwrap();
-            file.write_all("\n".as_bytes()).unwrap();
-        }
-        file.write_all(
-            ACTIVATION_FUNCTIONS
-                .iter()
-                .position(|&x| std::ptr::fn_addr_eq(x, self.activation_func_hidden))
-                .unwrap()
-                .to_string()
-                .as_bytes(),
-        )
-        .unwrap();
-        file.write_all("\n".as_bytes()).unwrap();
-        file.write_all(
-            ACTIVATION_FUNCTIONS
-                .iter()
-                .position(|&x| std::ptr::fn_addr_eq(x, self.activation_func_output))
-                .unwrap()
-                .to_string()
-                .as_bytes(),
-        )
-        .unwrap();
-        file.write_all("\n".as_bytes()).unwrap();
+            NetworkMetadata { generation, fitness: 0.0, saved_at: 0 },
+        ))
+    }
+
+    // Number of weights and bias of a network with these layers
+    fn parameter_counts(layers: &[u32]) -> Result<(usize, usize), NetworkError> {
+        if layers.len() < 2 || layers.contains(&0) {
+            return Err(NetworkError::InvalidLayers);
+        }
+        let mut weight_len: usize = 0;
+        for i in 0..layers.len() - 1 {
+            weight_len = (layers[i] as usize)
+                .checked_mul(layers[i + 1] as usize)
+                .and_then(|count| weight_len.checked_add(count))
+                .ok_or(NetworkError::InvalidLayers)?;
+        }
+        let bias_len = layers[1..].iter().map(|&layer| layer as usize).sum();
+        Ok((weight_len, bias_len))
+    }
+
+    fn activation_function(index: usize) -> Result<Activation, NetworkError> {
+        Activation::from_index(index).ok_or(NetworkError::InvalidActivation(index))
+    }
+
+    // Older files only had a hidden and an output activation. They used to be applied to every neuron
+    // but the last of each layer and to the last one, they are now applied to whole layers
+    fn hidden_output_activations(
+        layers_len: usize,
+        activation_func_hidden: usize,
+        activation_func_output: usize,
+    ) -> Result<Vec<Activation>, NetworkError> {
+        let mut activations = vec![Self::activation_function(activation_func_hidden)?; layers_len.saturating_sub(2)];
+        activations.push(Self::activation_function(activation_func_output)?);
+        Ok(activations)
+    }
+
+    pub fn save(&self, path: &str, metadata: &NetworkMetadata) -> std::io::Result<()> {
+        let bytes = self.to_bytes(metadata);
+        // Write to a temporary file first so that an interrupted save never leaves a truncated network
+        let temporary_path = format!("{}.tmp", path);
+        std::fs::File::create(&temporary_path)?.write_all(&bytes)?;
+        std::fs::rename(&temporary_path, path)
+    }
+
+    // Content of the network file written by save
+    fn to_bytes(&self, metadata: &NetworkMetadata) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(64 + 4 * (self.weights.len() + self.bias.len()));
+        // Header : magic, version, layers, activations, input encoding and metadata
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.layers.len() as u32).to_le_bytes());
+        for layer in &self.layers {
+            bytes.extend_from_slice(&layer.to_le_bytes());
+        }
+        for activation in &self.activations {
+            bytes.push(activation.index() as u8);
+        }
+        bytes.push(self.encoding.cells.index() as u8);
+        bytes.push(self.encoding.symmetric as u8);
+        bytes.extend_from_slice(&(metadata.generation as u64).to_le_bytes());
+        bytes.extend_from_slice(&metadata.fitness.to_le_bytes());
+        bytes.extend_from_slice(&metadata.saved_at.to_le_bytes());
 
         // Save the weights and bias
-        let mut weights = String::new();
-        for i in 0..self.weights.len() {
-            weights.push_str(&self.weights[i].to_string());
-            weights.push_str("\n");
+        for weight in &self.weights {
+            bytes.extend_from_slice(&weight.to_le_bytes());
+        }
+        for bias in &self.bias {
+            bytes.extend_from_slice(&bias.to_le_bytes());
         }
-        file.write_all(weights.as_bytes()).unwrap();
+        bytes.extend_from_slice(&crc32(&bytes).to_le_bytes());
+        bytes
+    }
 
-        let mut bias = String::new();
-        for i in 0..self.bias.len() {
-            bias.push_str(&self.bias[i].to_string());
-            bias.push_str("\n");
+    pub fn encoding(&self) -> InputEncoding {
+        self.encoding
+    }
+
+    // Encodes the packed grid with the network's encoding and writes the resulting outputs
+    pub fn evaluate_board(&self, grid: &[u32; 4], buffers: &mut ForwardBuffers, outputs: &mut [f32]) {
+        self.evaluate_boards(std::slice::from_ref(grid), buffers, outputs);
+    }
+
+    // Same as evaluate_board for several grids in a single batched forward pass. outputs is split into one chunk
+    // per grid, in the same order
+    pub fn evaluate_boards(&self, grids: &[[u32; 4]], buffers: &mut ForwardBuffers, outputs: &mut [f32]) {
+        if grids.is_empty() {
+            return;
+        }
+        let mut inputs = std::mem::take(&mut buffers.inputs);
+        inputs.clear();
+        for grid in grids {
+            self.encoding.encode(grid, &mut inputs);
         }
-        file.write_all(bias.as_bytes()).unwrap();
+        let samples = self.encoding.samples();
+        let sample_outputs = self.feed_forward_batch(&inputs, grids.len() * samples, buffers);
+        let board_outputs_len = sample_outputs.len() / grids.len();
+        for (board_sample_outputs, board_outputs) in
+            sample_outputs.chunks_exact(board_outputs_len).zip(outputs.chunks_exact_mut(outputs.len() / grids.len()))
+        {
+            self.encoding.combine(board_sample_outputs, board_outputs);
+        }
+        buffers.inputs = inputs;
     }
 
-    pub fn feed_forward(&self, inputs: Vec<f32>) -> Vec<f32> {
+    // Forward pass of batch_size inputs stored one after the other, returns the outputs stored the same way.
+    // The buffers are only reallocated when they are too small, so reusing them avoids any allocation
+    pub fn feed_forward_batch<'a>(&self, inputs: &[f32], batch_size: usize, buffers: &'a mut ForwardBuffers) -> &'a [f32] {
+        // First check if the number of inputs is equal to the number of neurons in the input layer
+        if inputs.len() != self.layers[0] as usize * batch_size {
+            panic!("The number of inputs is not equal to the number of neurons in the input layer");
+        }
+        let largest_layer = *self.layers.iter().max().unwrap() as usize;
+        if buffers.current.len() < largest_layer * batch_size {
+            buffers.current.resize(largest_layer * batch_size, 0.0);
+            buffers.next.resize(largest_layer * batch_size, 0.0);
+        }
+        buffers.current[..inputs.len()].copy_from_slice(inputs);
         let mut weight_index = 0;
         let mut bias_index = 0;
-        let mut current_layer = inputs.clone();
         for i in 0..self.layers.len() - 1 {
-            // First check if the number of inputs is equal to the number of neurons in the current layer
-            if current_layer.len() != self.layers[i] as usize {
-                panic!(
-                    "The number of inputs is not equal to the number of neurons in the input layer"
-                );
-            }
-
-            let mut outputs = Vec::new();
-            for j in 0..self.layers[i + 1] {
-                let mut sum: f32 = 0.0;
-                for k in 0..self.layers[i] {
-                    sum += self.weights[weight_index] * current_layer[k as usize];
-                    weight_index += 1;
+            let input_len = self.layers[i] as usize;
+            let output_len = self.layers[i + 1] as usize;
+            // Row j of the layer's weights holds the weights of the output neuron j
+            let weights = &self.weights[weight_index..weight_index + input_len * output_len];
+            let bias = &self.bias[bias_index..bias_index + output_len];
+            let current = &buffers.current[..input_len * batch_size];
+            let next = &mut buffers.next[..output_len * batch_size];
+            // Go through the output neurons by blocks, so that their weights stay in cache for the whole batch
+            for block_start in (0..output_len).step_by(ROW_BLOCK_SIZE) {
+                let block_end = (block_start + ROW_BLOCK_SIZE).min(output_len);
+                for sample in 0..batch_size {
+                    let sample_inputs = &current[sample * input_len..(sample + 1) * input_len];
+                    for j in block_start..block_end {
+                        next[sample * output_len + j] = bias[j] + dot(&weights[j * input_len..(j + 1) * input_len], sample_inputs);
+                    }
                 }
-                // Push the output to the outputs vector
-                if j == self.layers[i + 1] - 1 {
-                    outputs.push((self.activation_func_output)(sum + self.bias[bias_index]));
-                } else {
-                    outputs.push((self.activation_func_hidden)(sum + self.bias[bias_index]));
+            }
+            for sample_outputs in next.chunks_exact_mut(output_len) {
+                self.activations[i].apply(sample_outputs);
+            }
+            std::mem::swap(&mut buffers.current, &mut buffers.next);
+            weight_index += input_len * output_len;
+            bias_index += output_len;
+        }
+        &buffers.current[..*self.layers.last().unwrap() as usize * batch_size]
+    }
+
+    // Forward pass of batch_size inputs stored one after the other, returns the activations of every layer
+    // (the inputs first, the outputs last) as needed by backpropagate
+    pub fn forward_trace(&self, inputs: &[f32], batch_size: usize) -> Vec<Vec<f32>> {
+        if inputs.len() != self.layers[0] as usize * batch_size {
+            panic!("The number of inputs is not equal to the number of neurons in the input layer");
+        }
+        let mut trace = vec![inputs.to_vec()];
+        let mut weight_index = 0;
+        let mut bias_index = 0;
+        for i in 0..self.layers.len() - 1 {
+            let input_len = self.layers[i] as usize;
+            let output_len = self.layers[i + 1] as usize;
+            let weights = &self.weights[weight_index..weight_index + input_len * output_len];
+            let bias = &self.bias[bias_index..bias_index + output_len];
+            let mut next = vec![0.0; output_len * batch_size];
+            for (sample_inputs, sample_outputs) in trace[i].chunks_exact(input_len).zip(next.chunks_exact_mut(output_len)) {
+                for (j, output) in sample_outputs.iter_mut().enumerate() {
+                    *output = bias[j] + dot(&weights[j * input_len..(j + 1) * input_len], sample_inputs);
                 }
-                bias_index += 1;
+                self.activations[i].apply(sample_outputs);
             }
-            current_layer = outputs;
+            trace.push(next);
+            weight_index += input_len * output_len;
+            bias_index += output_len;
         }
+        trace
+    }
 
-        return current_layer;
+    // Adds to gradients the gradient of a loss summed over the batch of the trace, given the gradient of the
+    // loss with respect to the outputs of the trace (stored the same way as the outputs)
+    pub fn backpropagate(&self, trace: &[Vec<f32>], output_gradients: &[f32], gradients: &mut Gradients) {
+        let mut delta = output_gradients.to_vec();
+        let mut weight_index = self.weights.len();
+        let mut bias_index = self.bias.len();
+        for i in (0..self.layers.len() - 1).rev() {
+            let input_len = self.layers[i] as usize;
+            let output_len = self.layers[i + 1] as usize;
+            weight_index -= input_len * output_len;
+            bias_index -= output_len;
+            // Gradient with respect to the layer's values before the activation
+            for (sample_outputs, sample_delta) in trace[i + 1].chunks_exact(output_len).zip(delta.chunks_exact_mut(output_len)) {
+                self.activations[i].backward(sample_outputs, sample_delta);
+            }
+            let weights = &self.weights[weight_index..weight_index + input_len * output_len];
+            let weight_gradients = &mut gradients.weights[weight_index..weight_index + input_len * output_len];
+            let bias_gradients = &mut gradients.bias[bias_index..bias_index + output_len];
+            let mut previous_delta = vec![0.0; input_len * delta.len() / output_len];
+            for ((sample_inputs, sample_delta), sample_previous_delta) in trace[i]
+                .chunks_exact(input_len)
+                .zip(delta.chunks_exact(output_len))
+                .zip(previous_delta.chunks_exact_mut(input_len))
+            {
+                for (j, &neuron_delta) in sample_delta.iter().enumerate() {
+                    if neuron_delta == 0.0 {
+                        continue;
+                    }
+                    bias_gradients[j] += neuron_delta;
+                    let row = j * input_len..(j + 1) * input_len;
+                    for ((gradient, weight), (input, previous)) in weight_gradients[row.clone()]
+                        .iter_mut()
+                        .zip(&weights[row])
+                        .zip(sample_inputs.iter().zip(sample_previous_delta.iter_mut()))
+                    {
+                        *gradient += neuron_delta * input;
+                        *previous += neuron_delta * weight;
+                    }
+                }
+            }
+            delta = previous_delta;
+        }
     }
 
-    pub fn mutate(&mut self, mutation_rate: f32, mutation_strength: f32) {
+    // Moves each weight and bias with probability mutation_rate by up to mutation_strength, drawing from the
+    // given generator so that the mutation can be reproduced. Nothing moves when mutation_strength is not positive
+    pub fn mutate_with_rng<R: Rng>(&mut self, mutation_rate: f32, mutation_strength: f32, rng: &mut R) {
+        if mutation_strength.is_nan() || mutation_strength <= 0.0 {
+            return;
+        }
         for i in 0..self.weights.len() {
-            if rand::rng().random_range(0.0..1.0) < mutation_rate {
-                self.weights[i] += rand::rng().random_range(-mutation_strength..mutation_strength);
+            if rng.random_range(0.0..1.0) < mutation_rate {
+                self.weights[i] += rng.random_range(-mutation_strength..mutation_strength);
             }
         }
 
         for i in 0..self.bias.len() {
-            if rand::rng().random_range(0.0..1.0) < mutation_rate {
-                self.bias[i] += rand::rng().random_range(-mutation_strength..mutation_strength);
+            if rng.random_range(0.0..1.0) < mutation_rate {
+                self.bias[i] += rng.random_range(-mutation_strength..mutation_strength);
+            }
+        }
+    }
+
+    // Child taking each weight and bias from either parent
+    pub fn uniform_crossover<R: Rng>(&self, other: &NeuralNetwork, rng: &mut R) -> NeuralNetwork {
+        if self.layers != other.layers {
+            panic!("Crossover needs parents with the same layers");
+        }
+        let mut child = self.clone();
+        for i in 0..child.weights.len() {
+            if rng.random_bool(0.5) {
+                child.weights[i] = other.weights[i];
+            }
+        }
+        for i in 0..child.bias.len() {
+            if rng.random_bool(0.5) {
+                child.bias[i] = other.bias[i];
             }
         }
+        child
+    }
+
+    // Child taking the weights and bias of each layer from either parent
+    pub fn layer_crossover<R: Rng>(&self, other: &NeuralNetwork, rng: &mut R) -> NeuralNetwork {
+        if self.layers != other.layers {
+            panic!("Crossover needs parents with the same layers");
+        }
+        let mut child = self.clone();
+        let mut weight_index = 0;
+        let mut bias_index = 0;
+        for i in 0..self.layers.len() - 1 {
+            let weight_len = (self.layers[i] * self.layers[i + 1]) as usize;
+            let bias_len = self.layers[i + 1] as usize;
+            if rng.random_bool(0.5) {
+                child.weights[weight_index..weight_index + weight_len]
+                    .copy_from_slice(&other.weights[weight_index..weight_index + weight_len]);
+                child.bias[bias_index..bias_index + bias_len]
+                    .copy_from_slice(&other.bias[bias_index..bias_index + bias_len]);
+            }
+            weight_index += weight_len;
+            bias_index += bias_len;
+        }
+        child
+    }
+
+    // Mean absolute difference between the parameters of the two networks, estimated on
+    // samples parameters evenly spread over the weights and bias
+    pub fn distance(&self, other: &NeuralNetwork, samples: usize) -> f32 {
+        let parameters = self.weights.len() + self.bias.len();
+        let samples = samples.clamp(1, parameters);
+        let mut total = 0.0;
+        for sample in 0..samples {
+            let index = sample * parameters / samples;
+            total += if index < self.weights.len() {
+                (self.weights[index] - other.weights[index]).abs()
+            } else {
+                (self.bias[index - self.weights.len()] - other.bias[index - self.weights.len()]).abs()
+            };
+        }
+        total / samples as f32
     }
 }
 
-// Array of activation functions
+// Activation functions
 pub fn sigmoid(x: f32) -> f32 {
     1.0 / (1.0 + (-x).exp())
 }
@@ -219,4 +742,279 @@ pub fn linear(x: f32) -> f32 {
     return x;
 }
 
-const ACTIVATION_FUNCTIONS: [fn(f32) -> f32; 6] = [sigmoid, tanh, relu, leaky_relu, elu, linear];
+pub fn softmax(layer: &mut [f32]) {
+    // Subtract the maximum to avoid overflowing the exponentials
+    let max = layer.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let mut total = 0.0;
+    for x in layer.iter_mut() {
+        *x = (*x - max).exp();
+        total += *x;
+    }
+    for x in layer.iter_mut() {
+        *x /= total;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activation {
+    Sigmoid,
+    Tanh,
+    Relu,
+    LeakyRelu,
+    Elu,
+    Linear,
+    // Applied to the whole layer, only makes sense for the output layer
+    Softmax,
+}
+
+// Order in which the activations are numbered in network files
+const ACTIVATIONS: [Activation; 7] = [
+    Activation::Sigmoid,
+    Activation::Tanh,
+    Activation::Relu,
+    Activation::LeakyRelu,
+    Activation::Elu,
+    Activation::Linear,
+    Activation::Softmax,
+];
+
+impl Activation {
+    pub fn from_index(index: usize) -> Option<Activation> {
+        ACTIVATIONS.get(index).copied()
+    }
+
+    pub fn index(&self) -> usize {
+        ACTIVATIONS.iter().position(|activation| activation == self).unwrap()
+    }
+
+    pub fn apply(&self, layer: &mut [f32]) {
+        let function: fn(f32) -> f32 = match self {
+            Activation::Sigmoid => sigmoid,
+            Activation::Tanh => tanh,
+            Activation::Relu => relu,
+            Activation::LeakyRelu => leaky_relu,
+            Activation::Elu => elu,
+            Activation::Linear => linear,
+            Activation::Softmax => return softmax(layer),
+        };
+        for x in layer.iter_mut() {
+            *x = function(*x);
+        }
+    }
+
+    // Turns the gradient with respect to the outputs of the activation into the gradient with respect to its
+    // inputs, the derivatives being computed from the outputs
+    pub fn backward(&self, outputs: &[f32], gradient: &mut [f32]) {
+        let derivative: fn(f32) -> f32 = match self {
+            Activation::Sigmoid => |y| y * (1.0 - y),
+            Activation::Tanh => |y| 1.0 - y * y,
+            Activation::Relu => |y| if y > 0.0 { 1.0 } else { 0.0 },
+            Activation::LeakyRelu => |y| if y > 0.0 { 1.0 } else { 0.01 },
+            // y = 0.01 * (e^x - 1) below 0, whose derivative is 0.01 * e^x = y + 0.01
+            Activation::Elu => |y| if y > 0.0 { 1.0 } else { y + 0.01 },
+            Activation::Linear => |_| 1.0,
+            Activation::Softmax => {
+                let weighted: f32 = outputs.iter().zip(gradient.iter()).map(|(y, g)| y * g).sum();
+                for (g, y) in gradient.iter_mut().zip(outputs) {
+                    *g = y * (*g - weighted);
+                }
+                return;
+            }
+        };
+        for (g, &y) in gradient.iter_mut().zip(outputs) {
+            *g *= derivative(y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastgame::FastGame;
+    use crate::finite_differences::{assert_close, parameter, EPSILON};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    // Network on scalar inputs whose weights and bias only depend on the seed
+    fn network(hidden: Activation, output: Activation, seed: u64) -> NeuralNetwork {
+        let mut network = NeuralNetwork::new(vec![16, 6, 5], vec![hidden, output], InputEncoding::default(), (-1.0, 1.0), (-1.0, 1.0));
+        let mut rng = SmallRng::seed_from_u64(seed);
+        for parameter in network.weights.iter_mut().chain(network.bias.iter_mut()) {
+            *parameter = rng.random_range(-0.8..0.8);
+        }
+        network
+    }
+
+    // Weighted sum of the outputs of every sample of the batch, whose gradient with respect to the outputs is coefficients
+    fn weighted_outputs(network: &NeuralNetwork, inputs: &[f32], coefficients: &[f32]) -> f32 {
+        let trace = network.forward_trace(inputs, 2);
+        trace.last().unwrap().iter().zip(coefficients).map(|(output, coefficient)| output * coefficient).sum()
+    }
+
+    fn assert_same_network(loaded: &(NeuralNetwork, NetworkMetadata), network: &NeuralNetwork, metadata: &NetworkMetadata) {
+        let (loaded, loaded_metadata) = loaded;
+        assert_eq!(loaded.layers, network.layers);
+        assert_eq!(loaded.activations, network.activations);
+        assert_eq!(loaded.encoding, network.encoding);
+        assert_eq!(loaded.weights, network.weights);
+        assert_eq!(loaded.bias, network.bias);
+        assert_eq!(loaded_metadata.generation, metadata.generation);
+        assert_eq!(loaded_metadata.fitness, metadata.fitness);
+        assert_eq!(loaded_metadata.saved_at, metadata.saved_at);
+    }
+
+    #[test]
+    fn networks_round_trip_for_every_activation_and_encoding() {
+        let metadata = NetworkMetadata { generation: 42, fitness: 1234.5, saved_at: 1_700_000_000 };
+        for cells in (0..).map_while(CellEncoding::from_index) {
+            for symmetric in [false, true] {
+                let encoding = InputEncoding { cells, symmetric };
+                for activation in ACTIVATIONS {
+                    let layers = vec![encoding.input_size() as u32, 5, 4];
+                    let network = NeuralNetwork::new(layers, vec![activation, activation], encoding, (-1.0, 1.0), (-1.0, 1.0));
+                    let loaded = NeuralNetwork::from_file_bytes(&network.to_bytes(&metadata)).unwrap();
+                    assert_same_network(&loaded, &network, &metadata);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn saved_networks_load_back() {
+        let network = network(Activation::LeakyRelu, Activation::Softmax, 1);
+        let metadata = NetworkMetadata::new(7, 99.0);
+        let path = std::env::temp_dir().join(format!("network_round_trip_{}.ntwk", std::process::id()));
+        let path = path.to_str().unwrap();
+        network.save(path, &metadata).unwrap();
+        let loaded = NeuralNetwork::load(path);
+        std::fs::remove_file(path).unwrap();
+        assert_same_network(&loaded.unwrap(), &network, &metadata);
+    }
+
+    #[test]
+    fn legacy_text_networks_load() {
+        // Generation, layer count, layers, hidden and output activations, then one weight or bias per line
+        let weights: Vec<f32> = (0..16 * 3 + 3 * 2).map(|i| i as f32 / 100.0 - 0.25).collect();
+        let bias = [0.5, -0.5, 0.25, 1.0, -1.0];
+        let mut text = format!("12\n3\n16\n3\n2\n{}\n{}\n", Activation::Tanh.index(), Activation::Sigmoid.index());
+        for value in weights.iter().chain(&bias) {
+            text += &format!("{}\n", value);
+        }
+        let (network, metadata) = NeuralNetwork::from_file_bytes(text.as_bytes()).unwrap();
+        assert_eq!(network.layers, vec![16, 3, 2]);
+        assert_eq!(network.activations, vec![Activation::Tanh, Activation::Sigmoid]);
+        assert_eq!(network.encoding, InputEncoding::default());
+        assert_eq!(network.weights, weights);
+        assert_eq!(network.bias, bias);
+        assert_eq!(metadata.generation, 12);
+        // A missing bias is reported instead of panicking
+        let truncated = &text[..text.trim_end().rfind('\n').unwrap()];
+        assert!(matches!(NeuralNetwork::from_file_bytes(truncated.as_bytes()), Err(NetworkError::Truncated)));
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let network = network(Activation::Relu, Activation::Linear, 2);
+        let bytes = network.to_bytes(&NetworkMetadata::new(1, 0.0));
+        let load = |bytes: &[u8]| NeuralNetwork::from_file_bytes(bytes).map(|_| ());
+
+        let mut corrupted = bytes.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 1;
+        assert!(matches!(load(&corrupted), Err(NetworkError::ChecksumMismatch)));
+
+        assert!(matches!(load(&bytes[..bytes.len() - 5]), Err(NetworkError::Truncated)));
+        assert!(matches!(load(&bytes[..MAGIC.len() + 1]), Err(NetworkError::Truncated)));
+
+        let mut trailing = bytes.clone();
+        trailing.extend_from_slice(&[0; 4]);
+        assert!(matches!(load(&trailing), Err(NetworkError::TrailingData)));
+
+        for version in [0, FORMAT_VERSION + 1] {
+            let mut future = bytes.clone();
+            future[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(load(&future), Err(NetworkError::UnsupportedVersion(v)) if v == version));
+        }
+    }
+
+    #[test]
+    fn activation_backward_matches_finite_differences() {
+        let inputs = [-1.5, -0.4, 0.3, 0.9, 2.0];
+        let coefficients = [0.3, -1.2, 0.7, 0.5, -0.4];
+        let weighted = |activation: Activation, inputs: &[f32]| {
+            let mut outputs = inputs.to_vec();
+            activation.apply(&mut outputs);
+            outputs.iter().zip(coefficients).map(|(output, coefficient)| output * coefficient).sum::<f32>()
+        };
+        for activation in ACTIVATIONS {
+            let mut outputs = inputs.to_vec();
+            activation.apply(&mut outputs);
+            let mut gradient = coefficients.to_vec();
+            activation.backward(&outputs, &mut gradient);
+            for i in 0..inputs.len() {
+                let (mut above, mut below) = (inputs, inputs);
+                above[i] += EPSILON;
+                below[i] -= EPSILON;
+                let numerical = (weighted(activation, &above) - weighted(activation, &below)) / (2.0 * EPSILON);
+                assert_close(gradient[i], numerical, &format!("{:?} input {}", activation, i));
+            }
+        }
+    }
+
+    #[test]
+    fn backpropagate_matches_finite_differences() {
+        let mut rng = SmallRng::seed_from_u64(1);
+        let inputs: Vec<f32> = (0..32).map(|_| rng.random_range(0.0..1.0)).collect();
+        let coefficients: Vec<f32> = (0..10).map(|_| rng.random_range(-1.0..1.0)).collect();
+        let shapes = ACTIVATIONS[..6].iter().map(|&hidden| (hidden, Activation::Linear)).chain([(Activation::Tanh, Activation::Softmax)]);
+        for (seed, (hidden, output)) in shapes.enumerate() {
+            let mut network = network(hidden, output, seed as u64);
+            let mut gradients = Gradients::zeros(&network);
+            let trace = network.forward_trace(&inputs, 2);
+            network.backpropagate(&trace, &coefficients, &mut gradients);
+            for i in 0..network.weights.len() + network.bias.len() {
+                let original = *parameter(&mut network, i);
+                *parameter(&mut network, i) = original + EPSILON;
+                let above = weighted_outputs(&network, &inputs, &coefficients);
+                *parameter(&mut network, i) = original - EPSILON;
+                let below = weighted_outputs(&network, &inputs, &coefficients);
+                *parameter(&mut network, i) = original;
+                let analytic = gradients.weights.iter().chain(&gradients.bias).nth(i).unwrap();
+                assert_close(*analytic, (above - below) / (2.0 * EPSILON), &format!("{:?}/{:?} parameter {}", hidden, output, i));
+            }
+        }
+    }
+
+    // Odd layer widths leave partial blocks of ROW_BLOCK_SIZE neurons and partial chunks in dot
+    #[test]
+    fn batched_forward_passes_match_per_board_ones() {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let grids: Vec<[u32; 4]> = (0..7)
+            .map(|_| FastGame::from_flat_array(std::array::from_fn(|_| if rng.random_bool(0.3) { 0 } else { rng.random_range(1..12) })))
+            .collect();
+        for cells in (0..).map_while(CellEncoding::from_index) {
+            for symmetric in [false, true] {
+                let encoding = InputEncoding { cells, symmetric };
+                let layers = vec![encoding.input_size() as u32, 37, 19, 5];
+                let activations = vec![Activation::LeakyRelu, Activation::Tanh, Activation::Linear];
+                let network = NeuralNetwork::new(layers, activations, encoding, (-1.0, 1.0), (-1.0, 1.0));
+                let mut buffers = ForwardBuffers::default();
+                let mut batched = vec![0.0; 5 * grids.len()];
+                network.evaluate_boards(&grids, &mut buffers, &mut batched);
+                // Reusing the larger buffers for single boards must not change anything
+                for (grid, batched_outputs) in grids.iter().zip(batched.chunks_exact(5)) {
+                    let mut outputs = [0.0; 5];
+                    network.evaluate_board(grid, &mut buffers, &mut outputs);
+                    assert_eq!(outputs, batched_outputs, "{}", encoding);
+                }
+                let mut inputs = Vec::new();
+                for grid in &grids {
+                    encoding.encode(grid, &mut inputs);
+                }
+                let batch_size = grids.len() * encoding.samples();
+                let trace = network.forward_trace(&inputs, batch_size);
+                assert_eq!(network.feed_forward_batch(&inputs, batch_size, &mut buffers), &trace.last().unwrap()[..], "{}", encoding);
+            }
+        }
+    }
+}
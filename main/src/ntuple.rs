@@ -0,0 +1,439 @@
+// N-tuple network: a value function of afterstates (grids after a move, before the spawn) which sums one lookup
+// table weight per tuple of cells and per symmetry of the grid, trained by temporal difference learning on
+// self-play games
+use crate::config::Config;
+use crate::fastgame::FastGame;
+use crate::game;
+use crate::neural_network::{crc32, ByteReader, NetworkError, NetworkMetadata};
+use seeded_random::{Random, Seed};
+use std::io::{Read, Write};
+
+// Binary n-tuple network files start with MAGIC and FORMAT_VERSION, and end with a CRC32 of their content
+const MAGIC: &[u8; 4] = b"NTUP";
+const FORMAT_VERSION: u16 = 1;
+// Cells hold 5 bits exponents
+const MAX_TILE_EXPONENT: u8 = 31;
+// Largest lookup table of a tuple, in weights
+const MAX_TABLE_SIZE: usize = 1 << 28;
+
+// Tuples of cells (indices in FastGame::to_flat_array), each also sampled on the 8 symmetries of the grid
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TupleSet {
+    // The outer and inner rows, 4-tuples
+    Rows,
+    // The outer and inner rows, and the corner, edge and center 2x2 squares, 4-tuples
+    Squares,
+    // The 4 6-tuples of Jaśkowski's 2048 agents: two 2x3 rectangles and two straight shapes
+    Standard,
+}
+
+impl std::str::FromStr for TupleSet {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rows" => Ok(TupleSet::Rows),
+            "squares" => Ok(TupleSet::Squares),
+            "standard" => Ok(TupleSet::Standard),
+            _ => Err("expected rows, squares or standard".to_string()),
+        }
+    }
+}
+
+impl TupleSet {
+    pub fn tuples(&self) -> Vec<Vec<usize>> {
+        match self {
+            TupleSet::Rows => vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]],
+            TupleSet::Squares => vec![
+                vec![0, 1, 2, 3],
+                vec![4, 5, 6, 7],
+                vec![0, 1, 4, 5],
+                vec![1, 2, 5, 6],
+                vec![5, 6, 9, 10],
+            ],
+            TupleSet::Standard => vec![
+                vec![0, 1, 2, 3, 4, 5],
+                vec![4, 5, 6, 7, 8, 9],
+                vec![0, 1, 2, 4, 5, 6],
+                vec![4, 5, 6, 8, 9, 10],
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NTupleConfig {
+    // Shape of new networks, loaded networks keep theirs
+    pub tuples: TupleSet,
+    // Tiles above 2^max_exponent share the weights of 2^max_exponent
+    pub max_exponent: u8,
+    // TD learning: the step of an update is spread over the weights of the afterstate
+    pub learning_rate: f32,
+    // 0 for TD(0), up to 1 for Monte Carlo returns
+    pub lambda: f32,
+    pub games: usize,
+    // Games between two progress lines and saves of the network
+    pub report_interval: usize,
+    // Playing: 0 picks the move maximizing reward + V(afterstate), otherwise the network evaluates the leaves
+    // of an expectimax search of this depth
+    pub search_depth: usize,
+}
+
+impl Default for NTupleConfig {
+    fn default() -> Self {
+        NTupleConfig {
+            tuples: TupleSet::Standard,
+            max_exponent: 15,
+            learning_rate: 0.1,
+            lambda: 0.0,
+            games: 10000,
+            report_interval: 500,
+            search_depth: 0,
+        }
+    }
+}
+
+impl NTupleConfig {
+    pub fn load(path: &str) -> Result<NTupleConfig, String> {
+        let config = Config::load(path)?;
+        let default = NTupleConfig::default();
+        Ok(NTupleConfig {
+            tuples: config.get("tuples", default.tuples)?,
+            max_exponent: config.get("max_exponent", default.max_exponent)?,
+            learning_rate: config.get("learning_rate", default.learning_rate)?,
+            lambda: config.get("lambda", default.lambda)?,
+            games: config.get("games", default.games)?,
+            report_interval: config.get("report_interval", default.report_interval)?,
+            search_depth: config.get("search_depth", default.search_depth)?,
+        })
+    }
+}
+
+pub struct NTupleNetwork {
+    tuples: Vec<Vec<usize>>,
+    max_exponent: u8,
+    // One lookup table per tuple, indexed by the capped exponents of its cells in base max_exponent + 1
+    weights: Vec<Vec<f32>>,
+    // Index of the tuple and cells of each of its 8 symmetric samplings
+    features: Vec<(usize, Vec<usize>)>,
+}
+
+// Cell read in place of cell by the symmetry, as in FastGame::symmetries
+fn symmetric_cell(symmetry: usize, cell: usize) -> usize {
+    let (i, j) = (cell / 4, cell % 4);
+    let (mut row, mut col) = if symmetry & 4 != 0 { (j, i) } else { (i, j) };
+    if symmetry & 1 != 0 {
+        col = 3 - col;
+    }
+    if symmetry & 2 != 0 {
+        row = 3 - row;
+    }
+    row * 4 + col
+}
+
+impl NTupleNetwork {
+    // Network with every weight at 0
+    pub fn new(tuples: Vec<Vec<usize>>, max_exponent: u8) -> Result<NTupleNetwork, NetworkError> {
+        let table_sizes = Self::table_sizes(&tuples, max_exponent)?;
+        let weights = table_sizes.into_iter().map(|size| vec![0.0; size]).collect();
+        Ok(Self::with_weights(tuples, max_exponent, weights))
+    }
+
+    fn with_weights(tuples: Vec<Vec<usize>>, max_exponent: u8, weights: Vec<Vec<f32>>) -> NTupleNetwork {
+        let mut features = Vec::new();
+        for (index, tuple) in tuples.iter().enumerate() {
+            for symmetry in 0..8 {
+                features.push((index, tuple.iter().map(|&cell| symmetric_cell(symmetry, cell)).collect()));
+            }
+        }
+        NTupleNetwork { tuples, max_exponent, weights, features }
+    }
+
+    fn table_sizes(tuples: &[Vec<usize>], max_exponent: u8) -> Result<Vec<usize>, NetworkError> {
+        if tuples.is_empty() || max_exponent == 0 || max_exponent > MAX_TILE_EXPONENT {
+            return Err(NetworkError::InvalidTuples);
+        }
+        tuples
+            .iter()
+            .map(|tuple| {
+                if tuple.is_empty() || tuple.iter().any(|&cell| cell >= 16) {
+                    return Err(NetworkError::InvalidTuples);
+                }
+                (max_exponent as usize + 1)
+                    .checked_pow(tuple.len() as u32)
+                    .filter(|&size| size <= MAX_TABLE_SIZE)
+                    .ok_or(NetworkError::InvalidTuples)
+            })
+            .collect()
+    }
+
+    fn feature_index(&self, flat: &[u8; 16], cells: &[usize]) -> usize {
+        let base = self.max_exponent as usize + 1;
+        cells
+            .iter()
+            .fold(0, |index, &cell| index * base + flat[cell].min(self.max_exponent) as usize)
+    }
+
+    // V(afterstate): the expected sum of the rewards until the end of the game
+    pub fn value(&self, afterstate: &[u32; 4]) -> f32 {
+        let flat = FastGame::to_flat_array(*afterstate);
+        self.features
+            .iter()
+            .map(|(tuple, cells)| self.weights[*tuple][self.feature_index(&flat, cells)])
+            .sum()
+    }
+
+    // Moves V(afterstate) by delta, shared equally between its weights
+    fn update(&mut self, afterstate: &[u32; 4], delta: f32) {
+        let flat = FastGame::to_flat_array(*afterstate);
+        let step = delta / self.features.len() as f32;
+        for feature in 0..self.features.len() {
+            let (tuple, cells) = &self.features[feature];
+            let index = self.feature_index(&flat, cells);
+            self.weights[*tuple][index] += step;
+        }
+    }
+
+    // The possible move maximizing reward + V(afterstate), with its afterstate, reward and value
+    fn best_move(&self, fast: &FastGame, grid: &[u32; 4]) -> Option<(game::Direction, [u32; 4], u32, f32)> {
+        fast.get_possible_directions(grid)
+            .into_iter()
+            .map(|direction| {
+                let (afterstate, reward) = fast.make_move(grid, &direction);
+                let value = reward as f32 + self.value(&afterstate);
+                (direction, afterstate, reward, value)
+            })
+            .max_by(|a, b| a.3.total_cmp(&b.3))
+    }
+
+    // Greedy player, game::Direction::None when the game is lost
+    pub fn get_best_direction(&self, fast: &FastGame, grid: &[u32; 4]) -> game::Direction {
+        match self.best_move(fast, grid) {
+            Some((direction, _, _, _)) => direction,
+            None => game::Direction::None,
+        }
+    }
+
+    // Value of a grid before the move: the value of its best move, 0 when the game is lost
+    pub fn state_value(&self, fast: &FastGame, grid: &[u32; 4]) -> f32 {
+        self.best_move(fast, grid).map_or(0.0, |(_, _, _, value)| value)
+    }
+
+    // Plays a self-play game with the greedy player, then updates the value of every afterstate of the game, from
+    // the last one, towards its λ-return: r' + (1 - λ) V(a') + λ G', a' being the next afterstate, r' the reward
+    // of the move leading to it and G' its own return (0 after the last afterstate, as the game is lost).
+    // Returns the score and the highest tile exponent of the game
+    pub fn train_game(&mut self, fast: &FastGame, config: &NTupleConfig, seed: u64) -> (u32, u8) {
+        let rand = Random::from_seed(Seed::unsafe_new(seed));
+        let mut game_state = [0; 4];
+        game_state = fast.add_random_block(game_state, &rand);
+        game_state = fast.add_random_block(game_state, &rand);
+        let mut score = 0;
+        let mut trajectory = Vec::new();
+        while let Some((_, afterstate, reward, _)) = self.best_move(fast, &game_state) {
+            trajectory.push((afterstate, reward));
+            score += reward;
+            game_state = fast.add_random_block(afterstate, &rand);
+        }
+        let mut next: Option<(u32, f32, f32)> = None;
+        for &(afterstate, reward) in trajectory.iter().rev() {
+            let target = match next {
+                Some((next_reward, next_value, next_return)) => {
+                    next_reward as f32 + (1.0 - config.lambda) * next_value + config.lambda * next_return
+                }
+                None => 0.0,
+            };
+            let error = target - self.value(&afterstate);
+            self.update(&afterstate, config.learning_rate * error);
+            next = Some((reward, self.value(&afterstate), target));
+        }
+        let max_tile = FastGame::to_flat_array(game_state).into_iter().max().unwrap_or(0);
+        (score, max_tile)
+    }
+
+    pub fn load(path: &str) -> Result<(NTupleNetwork, NetworkMetadata), NetworkError> {
+        let mut bytes = Vec::new();
+        std::fs::File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(NetworkError::Io)?;
+        Self::from_file_bytes(&bytes)
+    }
+
+    // Reads the content of a file written by save
+    pub fn from_file_bytes(bytes: &[u8]) -> Result<(NTupleNetwork, NetworkMetadata), NetworkError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(NetworkError::InvalidMagic);
+        }
+        let mut reader = ByteReader { bytes, position: MAGIC.len() };
+        let version = reader.read_u16()?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(NetworkError::UnsupportedVersion(version));
+        }
+        let max_exponent = reader.read_u8()?;
+        let tuple_count = reader.read_u32()? as usize;
+        let mut tuples = Vec::new();
+        for _ in 0..tuple_count.min(bytes.len()) {
+            let len = reader.read_u8()? as usize;
+            let mut tuple = Vec::new();
+            for _ in 0..len {
+                tuple.push(reader.read_u8()? as usize);
+            }
+            tuples.push(tuple);
+        }
+        let metadata = NetworkMetadata {
+            generation: reader.read_u64()? as usize,
+            fitness: reader.read_f32()?,
+            saved_at: reader.read_u64()?,
+        };
+        let table_sizes = Self::table_sizes(&tuples, max_exponent)?;
+        // Check the size before allocating the tables, then the checksum which covers everything before it
+        let expected_remaining = table_sizes.iter().sum::<usize>() * 4 + 4;
+        if reader.remaining() < expected_remaining {
+            return Err(NetworkError::Truncated);
+        }
+        if reader.remaining() > expected_remaining {
+            return Err(NetworkError::TrailingData);
+        }
+        let (content, checksum) = bytes.split_at(bytes.len() - 4);
+        // Fuzzing skips the checksum, which mutated files would almost never match, to reach the parsing after it
+        if !cfg!(fuzzing) && crc32(content) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(NetworkError::ChecksumMismatch);
+        }
+        let mut weights = Vec::with_capacity(table_sizes.len());
+        for size in table_sizes {
+            let mut table = Vec::with_capacity(size);
+            for _ in 0..size {
+                table.push(reader.read_f32()?);
+            }
+            weights.push(table);
+        }
+        Ok((Self::with_weights(tuples, max_exponent, weights), metadata))
+    }
+
+    pub fn save(&self, path: &str, metadata: &NetworkMetadata) -> std::io::Result<()> {
+        let table_size: usize = self.weights.iter().map(Vec::len).sum();
+        let mut bytes = Vec::with_capacity(64 + 4 * table_size);
+        // Header : magic, version, tile cap, tuples and metadata
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.push(self.max_exponent);
+        bytes.extend_from_slice(&(self.tuples.len() as u32).to_le_bytes());
+        for tuple in &self.tuples {
+            bytes.push(tuple.len() as u8);
+            bytes.extend(tuple.iter().map(|&cell| cell as u8));
+        }
+        bytes.extend_from_slice(&(metadata.generation as u64).to_le_bytes());
+        bytes.extend_from_slice(&metadata.fitness.to_le_bytes());
+        bytes.extend_from_slice(&metadata.saved_at.to_le_bytes());
+
+        for table in &self.weights {
+            for weight in table {
+                bytes.extend_from_slice(&weight.to_le_bytes());
+            }
+        }
+        bytes.extend_from_slice(&crc32(&bytes).to_le_bytes());
+
+        // Write to a temporary file first so that an interrupted save never leaves a truncated network
+        let temporary_path = format!("{}.tmp", path);
+        std::fs::File::create(&temporary_path)?.write_all(&bytes)?;
+        std::fs::rename(&temporary_path, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    // Network whose weights only depend on the seed
+    fn network(seed: u64) -> NTupleNetwork {
+        let mut network = NTupleNetwork::new(TupleSet::Squares.tuples(), 15).unwrap();
+        let mut rng = SmallRng::seed_from_u64(seed);
+        for weight in network.weights.iter_mut().flatten() {
+            *weight = rng.random_range(-1.0..1.0);
+        }
+        network
+    }
+
+    fn random_grid(rng: &mut SmallRng) -> [u32; 4] {
+        FastGame::from_flat_array(std::array::from_fn(|_| rng.random_range(0..14)))
+    }
+
+    #[test]
+    fn values_are_the_same_on_symmetric_grids() {
+        let network = network(1);
+        let mut rng = SmallRng::seed_from_u64(2);
+        for _ in 0..20 {
+            let symmetries = FastGame::symmetries(random_grid(&mut rng));
+            let value = network.value(&symmetries[0]);
+            for grid in &symmetries[1..] {
+                assert!((network.value(grid) - value).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn updates_move_the_value_towards_the_target() {
+        let mut network = network(3);
+        // With distinct tiles, every symmetric sampling reads its own weight, so V moves by exactly the step
+        let afterstate = FastGame::from_flat_array(std::array::from_fn(|cell| cell as u8));
+        let target = 50.0;
+        let learning_rate = 0.1;
+        let value = network.value(&afterstate);
+        network.update(&afterstate, learning_rate * (target - value));
+        let updated = network.value(&afterstate);
+        assert!((updated - (value + learning_rate * (target - value))).abs() < 1e-4, "{} -> {}", value, updated);
+        // A full step reaches the target
+        network.update(&afterstate, target - updated);
+        assert!((network.value(&afterstate) - target).abs() < 1e-4);
+    }
+
+    #[test]
+    fn trained_values_follow_the_returns() {
+        let fast = FastGame::new();
+        let mut network = NTupleNetwork::new(TupleSet::Rows.tuples(), 11).unwrap();
+        let config = NTupleConfig { learning_rate: 0.5, lambda: 0.5, ..NTupleConfig::default() };
+        for seed in 0..50 {
+            network.train_game(&fast, &config, seed);
+        }
+        // Early afterstates are followed by a whole game of rewards, the last afterstate by none
+        let (mut early, mut last) = (0.0, 0.0);
+        for seed in 100..110 {
+            let rand = Random::from_seed(Seed::unsafe_new(seed));
+            let mut game_state = fast.add_random_block(fast.add_random_block([0; 4], &rand), &rand);
+            let mut afterstates = Vec::new();
+            while let Some((_, afterstate, _, _)) = network.best_move(&fast, &game_state) {
+                afterstates.push(afterstate);
+                game_state = fast.add_random_block(afterstate, &rand);
+            }
+            early += network.value(&afterstates[5]);
+            last += network.value(afterstates.last().unwrap());
+        }
+        assert!(early > 2.0 * last, "early afterstates {} last ones {}", early / 10.0, last / 10.0);
+        assert!(network.weights.iter().flatten().all(|weight| weight.is_finite()));
+    }
+
+    #[test]
+    fn saved_networks_load_back() {
+        let network = network(5);
+        let metadata = NetworkMetadata { generation: 3, fitness: 4567.0, saved_at: 1_700_000_000 };
+        let path = std::env::temp_dir().join(format!("ntuple_round_trip_{}.ntup", std::process::id()));
+        let path = path.to_str().unwrap();
+        network.save(path, &metadata).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let loaded = NTupleNetwork::load(path);
+        std::fs::remove_file(path).unwrap();
+        let (loaded, loaded_metadata) = loaded.unwrap();
+        assert_eq!(loaded.tuples, network.tuples);
+        assert_eq!(loaded.max_exponent, network.max_exponent);
+        assert_eq!(loaded.weights, network.weights);
+        assert_eq!((loaded_metadata.generation, loaded_metadata.fitness), (3, 4567.0));
+        assert_eq!(loaded_metadata.saved_at, metadata.saved_at);
+
+        let mut damaged = bytes.clone();
+        damaged[bytes.len() / 2] ^= 1;
+        assert!(matches!(NTupleNetwork::from_file_bytes(&damaged), Err(NetworkError::ChecksumMismatch)));
+        assert!(matches!(NTupleNetwork::from_file_bytes(&bytes[..bytes.len() - 8]), Err(NetworkError::Truncated)));
+        assert!(matches!(NTupleNetwork::from_file_bytes(&bytes[1..]), Err(NetworkError::InvalidMagic)));
+    }
+}
@@ -1,109 +1,324 @@
+use crate::config::Config;
+use crate::encoding::InputEncoding;
+use crate::fastgame::{FastGame, MAX_BLOCK_EXPONENT};
+use crate::fitness::{self, FitnessConfig, GameStatistics};
 use crate::game;
+use crate::metrics::MetricsFormat;
 use crate::neural_network;
 use crate::neural_network::NeuralNetwork;
 use crate::GRID_SIZE;
+use rand::distr::weighted::WeightedIndex;
+use rand::distr::Distribution;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use seeded_random::{Random, Seed};
 
 pub const RUNS_PER_AGENT: usize = 10;
+// Sizes of the hidden layers of new agents, the input layer depends on the encoding
+const HIDDEN_LAYERS: [u32; 3] = [512, 512, 512];
+// Number of parameters compared to estimate the distance between two networks when speciating
+const DISTANCE_SAMPLES: usize = 256;
+// Seed of the first validation game, far from the training games' seeds (generation * RUNS_PER_AGENT onwards)
+const VALIDATION_SEED: u64 = 1 << 48;
+
+// How the parents of the next generation are picked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParentSelection {
+    // Best of tournament_size agents picked at random
+    Tournament,
+    // Agents picked with a probability proportional to their rank (1 for the worst)
+    Rank,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Crossover {
+    None,
+    // Each weight and bias comes from either parent
+    Uniform,
+    // Each layer comes from either parent
+    LayerWise,
+}
+
+// Bonus keeping the population diverse, applied to the fitness used for the selection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Diversity {
+    None,
+    // Fitness sharing : the fitness is divided by the number of agents closer than speciation_threshold
+    Speciation,
+    // The fitness is increased by novelty_weight times the agent's novelty, its mean behaviour distance
+    // to its novelty_neighbors closest agents (the behaviour being the share of each move and the highest tile)
+    Novelty,
+}
+
+impl std::str::FromStr for ParentSelection {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tournament" => Ok(ParentSelection::Tournament),
+            "rank" => Ok(ParentSelection::Rank),
+            _ => Err("expected tournament or rank".to_string()),
+        }
+    }
+}
+
+impl std::str::FromStr for Crossover {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Crossover::None),
+            "uniform" => Ok(Crossover::Uniform),
+            "layer_wise" => Ok(Crossover::LayerWise),
+            _ => Err("expected none, uniform or layer_wise".to_string()),
+        }
+    }
+}
+
+impl std::str::FromStr for Diversity {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Diversity::None),
+            "speciation" => Ok(Diversity::Speciation),
+            "novelty" => Ok(Diversity::Novelty),
+            _ => Err("expected none, speciation or novelty".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ParentSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParentSelection::Tournament => write!(f, "tournament"),
+            ParentSelection::Rank => write!(f, "rank"),
+        }
+    }
+}
+
+impl std::fmt::Display for Crossover {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Crossover::None => write!(f, "none"),
+            Crossover::Uniform => write!(f, "uniform"),
+            Crossover::LayerWise => write!(f, "layer_wise"),
+        }
+    }
+}
+
+impl std::fmt::Display for Diversity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Diversity::None => write!(f, "none"),
+            Diversity::Speciation => write!(f, "speciation"),
+            Diversity::Novelty => write!(f, "novelty"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GaConfig {
+    pub population_size: usize,
+    pub selection: ParentSelection,
+    pub tournament_size: usize,
+    // Number of best agents copied unchanged into the next generation
+    pub elite_count: usize,
+    pub crossover: Crossover,
+    // Share of the children made by crossing two parents, the others are copies of one parent
+    pub crossover_rate: f32,
+    pub mutation_rate: f32,
+    pub mutation_strength: f32,
+    pub diversity: Diversity,
+    pub speciation_threshold: f32,
+    pub novelty_weight: f32,
+    pub novelty_neighbors: usize,
+    // The whole training state is saved every checkpoint_interval generations, never when it is 0
+    pub checkpoint_interval: usize,
+    pub metrics_format: MetricsFormat,
+    pub fitness: FitnessConfig,
+    // The validation_candidates best agents of each generation play validation_games held-out games,
+    // no validation is done when either is 0
+    pub validation_games: usize,
+    pub validation_candidates: usize,
+}
+
+impl Default for GaConfig {
+    fn default() -> Self {
+        GaConfig {
+            population_size: 2000,
+            selection: ParentSelection::Tournament,
+            tournament_size: 4,
+            elite_count: 10,
+            crossover: Crossover::Uniform,
+            crossover_rate: 0.5,
+            mutation_rate: 0.25,
+            mutation_strength: 0.5,
+            diversity: Diversity::None,
+            speciation_threshold: 0.1,
+            novelty_weight: 0.5,
+            novelty_neighbors: 15,
+            checkpoint_interval: 10,
+            metrics_format: MetricsFormat::Csv,
+            fitness: FitnessConfig::default(),
+            validation_games: 50,
+            validation_candidates: 5,
+        }
+    }
+}
+
+impl GaConfig {
+    pub fn load(path: &str) -> Result<GaConfig, String> {
+        let config = Config::load(path)?;
+        let default = GaConfig::default();
+        let ga_config = GaConfig {
+            population_size: config.get("population_size", default.population_size)?,
+            selection: config.get("selection", default.selection)?,
+            tournament_size: config.get("tournament_size", default.tournament_size)?,
+            elite_count: config.get("elite_count", default.elite_count)?,
+            crossover: config.get("crossover", default.crossover)?,
+            crossover_rate: config.get("crossover_rate", default.crossover_rate)?,
+            mutation_rate: config.get("mutation_rate", default.mutation_rate)?,
+            mutation_strength: config.get("mutation_strength", default.mutation_strength)?,
+            diversity: config.get("diversity", default.diversity)?,
+            speciation_threshold: config.get("speciation_threshold", default.speciation_threshold)?,
+            novelty_weight: config.get("novelty_weight", default.novelty_weight)?,
+            novelty_neighbors: config.get("novelty_neighbors", default.novelty_neighbors)?,
+            checkpoint_interval: config.get("checkpoint_interval", default.checkpoint_interval)?,
+            metrics_format: config.get("metrics_format", default.metrics_format)?,
+            fitness: FitnessConfig::from_config(&config)?,
+            validation_games: config.get("validation_games", default.validation_games)?,
+            validation_candidates: config.get("validation_candidates", default.validation_candidates)?,
+        };
+        // Parents are drawn from the population, and mutations from +-mutation_strength
+        if ga_config.population_size == 0 {
+            return Err(format!("{}: population_size must be at least 1", path));
+        }
+        if ga_config.mutation_strength.is_nan() || ga_config.mutation_strength <= 0.0 {
+            return Err(format!("{}: mutation_strength must be positive", path));
+        }
+        Ok(ga_config)
+    }
+
+    // The config in the format read by load
+    pub fn to_config_string(self) -> String {
+        let values: [(&str, String); 16] = [
+            ("population_size", self.population_size.to_string()),
+            ("selection", self.selection.to_string()),
+            ("tournament_size", self.tournament_size.to_string()),
+            ("elite_count", self.elite_count.to_string()),
+            ("crossover", self.crossover.to_string()),
+            ("crossover_rate", self.crossover_rate.to_string()),
+            ("mutation_rate", self.mutation_rate.to_string()),
+            ("mutation_strength", self.mutation_strength.to_string()),
+            ("diversity", self.diversity.to_string()),
+            ("speciation_threshold", self.speciation_threshold.to_string()),
+            ("novelty_weight", self.novelty_weight.to_string()),
+            ("novelty_neighbors", self.novelty_neighbors.to_string()),
+            ("checkpoint_interval", self.checkpoint_interval.to_string()),
+            ("metrics_format", self.metrics_format.to_string()),
+            ("validation_games", self.validation_games.to_string()),
+            ("validation_candidates", self.validation_candidates.to_string()),
+        ];
+        let mut contents: String = values
+            .iter()
+            .map(|(key, value)| format!("{} = {}\n", key, value))
+            .collect();
+        contents += &self.fitness.to_config_string();
+        contents
+    }
+}
 
 pub struct Agent {
     pub neural_network: neural_network::NeuralNetwork,
-    pub game_state: [u8; GRID_SIZE * GRID_SIZE],
+    pub game_state: [u32; 4],
     fitness: [f32; RUNS_PER_AGENT],
     pub highest_tile: u8,
+    // Number of times each move (in game::DIRECTIONS order) was played over the runs
+    move_counts: [u32; 4],
     seed: u64,
+    buffers: neural_network::ForwardBuffers,
 }
 
 impl Agent {
-    pub fn new(seed: u64) -> Self {
+    pub fn new(seed: u64, encoding: InputEncoding) -> Self {
+        let mut layers = vec![encoding.input_size() as u32];
+        layers.extend(HIDDEN_LAYERS);
+        layers.push(4);
         return Agent {
             neural_network: neural_network::NeuralNetwork::new(
-                vec![(GRID_SIZE as u32) * (GRID_SIZE as u32), 512, 512, 512, 4],
-                3,
-                5,
+                layers,
+                vec![
+                    neural_network::Activation::LeakyRelu,
+                    neural_network::Activation::LeakyRelu,
+                    neural_network::Activation::LeakyRelu,
+                    neural_network::Activation::Linear,
+                ],
+                encoding,
                 (-1.0, 1.0),
                 (-0.1, 0.1),
             ),
-            game_state: [0; GRID_SIZE * GRID_SIZE],
+            game_state: [0; 4],
             fitness: [0.0; RUNS_PER_AGENT],
             highest_tile: 0,
+            move_counts: [0; 4],
             seed: seed,
+            buffers: neural_network::ForwardBuffers::default(),
         };
     }
     pub fn from(neural_network: neural_network::NeuralNetwork, seed: u64) -> Self {
         return Agent {
             neural_network: neural_network,
-            game_state: [0; GRID_SIZE * GRID_SIZE],
+            game_state: [0; 4],
             fitness: [0.0; RUNS_PER_AGENT],
             highest_tile: 0,
+            move_counts: [0; 4],
             seed: seed,
+            buffers: neural_network::ForwardBuffers::default(),
         };
     }
-    pub fn run(self: &mut Self) {
+    // Seed of the agent's next game
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn run(self: &mut Self, fast: &FastGame, fitness: &FitnessConfig) {
+        self.move_counts = [0; 4];
+        self.highest_tile = 0;
         for i in 0..RUNS_PER_AGENT {
-            self.fitness[i] = self.run_once(&Random::from_seed(Seed::unsafe_new(self.seed)));
+            self.fitness[i] = self.run_once(fast, &Random::from_seed(Seed::unsafe_new(self.seed)), fitness);
             self.seed += 1;
         }
     }
 
-    pub fn run_once(self: &mut Self, rand: &Random) -> f32 {
-        self.game_state = [0; GRID_SIZE * GRID_SIZE];
+    pub fn run_once(self: &mut Self, fast: &FastGame, rand: &Random, fitness: &FitnessConfig) -> f32 {
+        self.game_state = [0; 4];
         // Add two block to the game state
-        game::add_block(&mut self.game_state, rand);
-        game::add_block(&mut self.game_state, rand);
-        let mut move_number = 0;
-        let mut max_tile = 0;
-        let mut total_score = 0;
-        let mut total_empty = 0;
-        let mut total_smoothness = 0;
-        let mut total_monotonicity = 0;
+        self.game_state = fast.add_random_block(self.game_state, rand);
+        self.game_state = fast.add_random_block(self.game_state, rand);
+        let mut statistics = GameStatistics::default();
         loop {
             // Get the direction from the neural network
-            let direction = self.get_direction();
+            let direction = self.get_direction(fast);
             // If the position is unplayable, break
             if direction == game::Direction::None {
-                self.highest_tile = max_tile as u8;
-                return Agent::get_final_fitness(
-                    move_number,
-                    max_tile,
-                    total_score,
-                    total_empty,
-                    total_smoothness,
-                    total_monotonicity,
-                );
+                self.highest_tile = self.highest_tile.max(statistics.max_tile as u8);
+                return fitness.evaluate(&statistics);
             }
             // If not, execute the move chosen by the ai
-            let move_score = game::execute_move(&mut self.game_state, direction, rand);
+            self.move_counts[direction.index()] += 1;
+            let (moved, move_score) = fast.make_move(&self.game_state, &direction);
+            self.game_state = fast.add_random_block(moved, rand);
             // Update the fitness variables
-            move_number += 1;
-            match self.game_state.iter().max() {
-                Some(&max) => max_tile = max as i32,
-                _ => continue,
-            }
-            total_score += move_score as i32;
-            total_empty += self.game_state.iter().filter(|&&x| x == 0).count() as i32;
-            total_smoothness += self.smoothness();
-            total_monotonicity += self.monotonicity();
+            let board = FastGame::to_flat_array(self.game_state);
+            statistics.move_number += 1;
+            statistics.max_tile = *board.iter().max().unwrap() as i32;
+            statistics.total_score += move_score as i32;
+            statistics.total_empty += board.iter().filter(|&&x| x == 0).count() as i32;
+            statistics.total_smoothness += Agent::smoothness(&board);
+            statistics.total_monotonicity += Agent::monotonicity(&board);
         }
     }
 
-    fn get_final_fitness(
-        move_number: i32,
-        max_tile: i32,
-        total_score: i32,
-        total_empty: i32,
-        total_smoothness: i32,
-        total_monotonicity: i32,
-    ) -> f32 {
-        10.0 * max_tile as f32
-            + 1.0 * total_score as f32
-            + 0.5 * total_empty as f32 / move_number as f32
-            + -0.1 * total_smoothness as f32 / move_number as f32
-            + 0.5 * total_monotonicity as f32 / move_number as f32
-            + -0.05 * move_number as f32
-    }
-    fn smoothness(self: &mut Self) -> i32 {
+    fn smoothness(board: &[u8; GRID_SIZE * GRID_SIZE]) -> i32 {
         let mut sum = 0;
         for i in 0..GRID_SIZE * GRID_SIZE {
             let row = i / GRID_SIZE;
@@ -112,22 +327,22 @@ impl Agent {
             // Check right neighbor (same row, next column)
             if col < GRID_SIZE - 1 {
                 let right = i + 1;
-                sum += (self.game_state[i] as i32 - self.game_state[right] as i32).abs();
+                sum += (board[i] as i32 - board[right] as i32).abs();
             }
 
             // Check bottom neighbor (same column, next row)
             if row < GRID_SIZE - 1 {
                 let bottom = i + GRID_SIZE;
-                sum += (self.game_state[i] as i32 - self.game_state[bottom] as i32).abs();
+                sum += (board[i] as i32 - board[bottom] as i32).abs();
             }
         }
         return sum;
     }
 
-    fn monotonicity(self: &mut Self) -> i32 {
+    fn monotonicity(board: &[u8; GRID_SIZE * GRID_SIZE]) -> i32 {
         let mut total = 0;
         // Check rows
-        for row in self.game_state.chunks_exact(GRID_SIZE) {
+        for row in board.chunks_exact(GRID_SIZE) {
             let (mut inc, mut dec) = (0, 0);
             for j in 0..GRID_SIZE - 1 {
                 if row[j] <= row[j + 1] {
@@ -146,10 +361,10 @@ impl Agent {
             for row in 0..GRID_SIZE - 1 {
                 let idx = col + row * GRID_SIZE;
                 let next_idx = col + (row + 1) * GRID_SIZE;
-                if self.game_state[idx] <= self.game_state[next_idx] {
+                if board[idx] <= board[next_idx] {
                     inc += 1;
                 }
-                if self.game_state[idx] >= self.game_state[next_idx] {
+                if board[idx] >= board[next_idx] {
                     dec += 1;
                 }
             }
@@ -159,37 +374,18 @@ impl Agent {
         total
     }
 
-    pub fn get_direction(self: &mut Self) -> game::Direction {
-        // Transform the game_state into an input for the network
-        let mut input_game_state = Vec::with_capacity(GRID_SIZE * GRID_SIZE);
-        for i in 0..self.game_state.len() {
-            if self.game_state[i] == 0 {
-                input_game_state.push(0.0);
-                continue;
-            }
-            input_game_state.push((self.game_state[i] as f32 + 2.0) / 10.0);
-        }
-        // First get the 4 outputs from the neural network
-        let outputs = self.neural_network.feed_forward(input_game_state);
-        // Then create a vec with each index corresponding to the directions ranked by the neural network
-        let mut indices: Vec<usize> = (0..outputs.len()).collect();
+    pub fn get_direction(self: &mut Self, fast: &FastGame) -> game::Direction {
+        // First get the 4 outputs from the neural network, which encodes the game_state itself
+        let mut outputs = [0.0; 4];
+        self.neural_network
+            .evaluate_board(&self.game_state, &mut self.buffers, &mut outputs);
+        // Then create an array with each index corresponding to the directions ranked by the neural network
+        let mut indices = [0, 1, 2, 3];
         indices.sort_by(|&i, &j| outputs[j].partial_cmp(&outputs[i]).unwrap());
         // Then loop through the indices to get the first valid move
         for index in indices {
-            let direction = match index {
-                0 => game::Direction::Up,
-                1 => game::Direction::Down,
-                2 => game::Direction::Left,
-                3 => game::Direction::Right,
-                _ => game::Direction::None,
-            };
-            if match direction {
-                game::Direction::Up => game::can_up(&self.game_state),
-                game::Direction::Down => game::can_down(&self.game_state),
-                game::Direction::Left => game::can_left(&self.game_state),
-                game::Direction::Right => game::can_right(&self.game_state),
-                game::Direction::None => false,
-            } {
+            let direction = game::DIRECTIONS.get(index).cloned().unwrap_or(game::Direction::None);
+            if fast.can_move(&self.game_state, &direction) {
                 return direction;
             }
         }
@@ -197,16 +393,21 @@ impl Agent {
         return game::Direction::None;
     }
 
-    pub fn geometric_mean(self: &mut Self) -> f32 {
-        // Calculate the the geometric mean of the scores by computing the arithmetic mean of logarithms of the scores
-        return (self
-            .fitness
-            .iter()
-            .map(|&x| x as f32)
-            .fold(0.0, |acc, x| acc + x.ln())
-            / RUNS_PER_AGENT as f32)
-            .exp();
+    pub fn geometric_mean(&mut self) -> f32 {
+        return fitness::geometric_mean(&self.fitness);
+    }
+    // Share of each move and highest tile, used to measure how differently two agents play
+    fn behaviour(&self) -> [f32; 5] {
+        let total = self.move_counts.iter().sum::<u32>().max(1) as f32;
+        let [up, down, left, right] = self.move_counts.map(|count| count as f32 / total);
+        [up, down, left, right, self.highest_tile as f32 / MAX_BLOCK_EXPONENT as f32]
+    }
+
+    // Fitness of the agent's best game
+    pub fn best_run(&self) -> f32 {
+        self.fitness.iter().cloned().fold(f32::NEG_INFINITY, f32::max)
     }
+
     pub fn _get_worst(self: &mut Self) -> f32 {
         // Get the minimum score
         return *self
@@ -217,16 +418,16 @@ impl Agent {
     }
 }
 
-pub fn run_all(agents: &mut Vec<Agent>) {
+pub fn run_all(agents: &mut Vec<Agent>, fast: &FastGame, fitness: &FitnessConfig) {
     agents.par_iter_mut().enumerate().for_each(|(_, agent)| {
-        agent.run();
+        agent.run(fast, fitness);
     });
 }
 
-pub fn create_population(size: usize, seed: u64) -> Vec<Agent> {
+pub fn create_population(size: usize, seed: u64, encoding: InputEncoding) -> Vec<Agent> {
     let mut agents = Vec::new();
     for _ in 0..size {
-        agents.push(Agent::new(seed as u64));
+        agents.push(Agent::new(seed, encoding));
     }
     return agents;
 }
@@ -234,36 +435,304 @@ pub fn create_population(size: usize, seed: u64) -> Vec<Agent> {
 pub fn load_population(size: usize, seed: u64, neural_network: NeuralNetwork) -> Vec<Agent> {
     let mut agents = Vec::new();
     for _ in 0..size {
-        agents.push(Agent::from(neural_network.clone(), seed as u64));
+        agents.push(Agent::from(neural_network.clone(), seed));
     }
     return agents;
 }
 
-pub fn clone_population(
-    agents: &mut Vec<Agent>,
-    best: NeuralNetwork,
-    seed: u64,
-    mutation_rate: f32,
-    mutation_strength: f32,
-) {
-    // Get size
-    let size = agents.len();
-    // Clear the agents vector
-    agents.clear();
-    // Add the best neural network to the agents vector
-    agents.push(Agent::from(best.clone(), seed));
-    // Add the rest of the agents (parallelized)
-    let new_agents: Vec<Agent> = (1..size)
+// Geometric mean of the network's fitness over the validation games, which are the same for every network
+pub fn validation_score(network: &NeuralNetwork, fast: &FastGame, fitness: &FitnessConfig, games: usize) -> f32 {
+    let mut agent = Agent::from(network.clone(), VALIDATION_SEED);
+    let scores: Vec<f32> = (0..games as u64)
+        .map(|game| agent.run_once(fast, &Random::from_seed(Seed::unsafe_new(VALIDATION_SEED + game)), fitness))
+        .collect();
+    fitness::geometric_mean(&scores)
+}
+
+// Validates the best agents of a population (already run), returns the index and validation score of the best
+// one, or None when validation is disabled
+pub fn validate_best(agents: &mut [Agent], fast: &FastGame, config: &GaConfig) -> Option<(usize, f32)> {
+    if config.validation_games == 0 || config.validation_candidates == 0 {
+        return None;
+    }
+    let fitness: Vec<f32> = agents.iter_mut().map(|agent| agent.geometric_mean()).collect();
+    let mut candidates: Vec<usize> = (0..agents.len()).collect();
+    candidates.sort_by(|&a, &b| fitness[b].total_cmp(&fitness[a]));
+    candidates.truncate(config.validation_candidates);
+    candidates
+        .into_par_iter()
+        .map(|index| {
+            let score = validation_score(&agents[index].neural_network, fast, &config.fitness, config.validation_games);
+            (index, score)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+// Fitness used to pick the parents, which includes the diversity bonus
+fn selection_fitness(agents: &mut [Agent], config: &GaConfig) -> Vec<f32> {
+    let fitness: Vec<f32> = agents.iter_mut().map(|agent| agent.geometric_mean()).collect();
+    match config.diversity {
+        Diversity::None => fitness,
+        Diversity::Speciation => (0..agents.len())
+            .into_par_iter()
+            .map(|i| {
+                let niche_count = agents
+                    .iter()
+                    .filter(|other| {
+                        agents[i].neural_network.distance(&other.neural_network, DISTANCE_SAMPLES)
+                            < config.speciation_threshold
+                    })
+                    .count();
+                fitness[i] / niche_count.max(1) as f32
+            })
+            .collect(),
+        Diversity::Novelty => {
+            let behaviours: Vec<[f32; 5]> = agents.iter().map(|agent| agent.behaviour()).collect();
+            (0..agents.len())
+                .into_par_iter()
+                .map(|i| {
+                    let mut distances: Vec<f32> = (0..behaviours.len())
+                        .filter(|&j| j != i)
+                        .map(|j| {
+                            behaviours[i]
+                                .iter()
+                                .zip(&behaviours[j])
+                                .map(|(a, b)| (a - b).powi(2))
+                                .sum::<f32>()
+                                .sqrt()
+                        })
+                        .collect();
+                    distances.sort_by(|a, b| a.total_cmp(b));
+                    let neighbors = config.novelty_neighbors.clamp(1, distances.len().max(1));
+                    let novelty = distances.iter().take(neighbors).sum::<f32>() / neighbors as f32;
+                    fitness[i] * (1.0 + config.novelty_weight * novelty)
+                })
+                .collect()
+        }
+    }
+}
+
+// Generator of the child with this index, so that a generation can be reproduced from the seed alone
+pub fn child_rng(seed: u64, generation: u64, index: usize) -> SmallRng {
+    let mut hash = seed ^ generation.wrapping_mul(0x9E3779B97F4A7C15) ^ (index as u64).wrapping_mul(0xC2B2AE3D27D4EB4F);
+    // SplitMix64 finalizer, so that close inputs give unrelated seeds
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D049BB133111EB);
+    SmallRng::seed_from_u64(hash ^ (hash >> 31))
+}
+
+fn pick_parent<R: Rng>(
+    fitness: &[f32],
+    config: &GaConfig,
+    rank_distribution: &Option<WeightedIndex<usize>>,
+    ranking: &[usize],
+    rng: &mut R,
+) -> usize {
+    match rank_distribution {
+        Some(distribution) => ranking[distribution.sample(rng)],
+        None => (0..config.tournament_size.max(1))
+            .map(|_| rng.random_range(0..fitness.len()))
+            .max_by(|&a, &b| fitness[a].total_cmp(&fitness[b]))
+            .unwrap(),
+    }
+}
+
+// Replaces the population (already run) by the next generation: the elites are kept, the other agents are
+// children of parents picked with the configured selection, crossover and mutation
+pub fn next_generation(agents: &mut Vec<Agent>, config: &GaConfig, seed: u64, generation: u64) {
+    let fitness = selection_fitness(agents, config);
+    let raw_fitness: Vec<f32> = agents.iter_mut().map(|agent| agent.geometric_mean()).collect();
+    // Agents from the worst to the best
+    let mut ranking: Vec<usize> = (0..agents.len()).collect();
+    ranking.sort_by(|&a, &b| fitness[a].total_cmp(&fitness[b]));
+    let rank_distribution = match config.selection {
+        ParentSelection::Rank => Some(WeightedIndex::new(1..=agents.len()).unwrap()),
+        ParentSelection::Tournament => None,
+    };
+    // The elites are the best agents by their own fitness, without the diversity bonus
+    let mut elites: Vec<usize> = (0..agents.len()).collect();
+    elites.sort_by(|&a, &b| raw_fitness[b].total_cmp(&raw_fitness[a]));
+    elites.truncate(config.elite_count.min(config.population_size));
+
+    let game_seed = generation * RUNS_PER_AGENT as u64;
+    let networks: Vec<NeuralNetwork> = (0..config.population_size)
         .into_par_iter()
-        .map(|_| {
-            // Clone the best neural network
-            let mut neural_network = best.clone();
-            // Mutate the neural network
-            neural_network.mutate(mutation_rate, mutation_strength);
-            // Create a new agent
-            Agent::from(neural_network, seed)
+        .map(|index| {
+            if index < elites.len() {
+                return agents[elites[index]].neural_network.clone();
+            }
+            let mut rng = child_rng(seed, generation, index);
+            let parent = pick_parent(&fitness, config, &rank_distribution, &ranking, &mut rng);
+            let crossing = config.crossover != Crossover::None && rng.random_bool(config.crossover_rate.clamp(0.0, 1.0) as f64);
+            let mut neural_network = match config.crossover {
+                Crossover::Uniform if crossing => {
+                    let other_parent = pick_parent(&fitness, config, &rank_distribution, &ranking, &mut rng);
+                    agents[parent].neural_network.uniform_crossover(&agents[other_parent].neural_network, &mut rng)
+                }
+                Crossover::LayerWise if crossing => {
+                    let other_parent = pick_parent(&fitness, config, &rank_distribution, &ranking, &mut rng);
+                    agents[parent].neural_network.layer_crossover(&agents[other_parent].neural_network, &mut rng)
+                }
+                _ => agents[parent].neural_network.clone(),
+            };
+            neural_network.mutate_with_rng(config.mutation_rate, config.mutation_strength, &mut rng);
+            neural_network
         })
         .collect();
-    // Extend the agents vector with the new agents
-    agents.extend(new_agents);
+    agents.clear();
+    agents.extend(networks.into_iter().map(|neural_network| Agent::from(neural_network, game_seed)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural_network::Activation;
+
+    // Agents with small random networks, scoring the given fitness in every run
+    fn population(fitness: &[f32]) -> Vec<Agent> {
+        fitness
+            .iter()
+            .map(|&value| {
+                let layers = vec![16, 5, 4, 4];
+                let activations = vec![Activation::LeakyRelu, Activation::LeakyRelu, Activation::Linear];
+                let network = NeuralNetwork::new(layers, activations, InputEncoding::default(), (-1.0, 1.0), (-1.0, 1.0));
+                let mut agent = Agent::from(network, 0);
+                agent.fitness = [value; RUNS_PER_AGENT];
+                agent
+            })
+            .collect()
+    }
+
+    fn parameters(network: &NeuralNetwork) -> Vec<f32> {
+        network.weights.iter().chain(&network.bias).cloned().collect()
+    }
+
+    // Copy of the agents, with the same networks and fitness
+    fn copy(agents: &[Agent]) -> Vec<Agent> {
+        agents
+            .iter()
+            .map(|agent| {
+                let mut copy = Agent::from(agent.neural_network.clone(), agent.seed);
+                copy.fitness = agent.fitness;
+                copy
+            })
+            .collect()
+    }
+
+    #[test]
+    fn elites_are_kept_and_generations_are_reproducible() {
+        let fitness: Vec<f32> = (0..20).map(|i| ((i * 7) % 20) as f32 + 1.0).collect();
+        let config = GaConfig { population_size: 30, elite_count: 3, ..GaConfig::default() };
+        let mut agents = population(&fitness);
+        let mut copies = copy(&agents);
+        let originals: Vec<Vec<f32>> = agents.iter().map(|agent| parameters(&agent.neural_network)).collect();
+        next_generation(&mut agents, &config, 5, 2);
+        next_generation(&mut copies, &config, 5, 2);
+        assert_eq!(agents.len(), 30);
+        // The fitness 20, 19 and 18 are those of the agents 17, 14 and 11
+        for (elite, index) in [17, 14, 11].into_iter().enumerate() {
+            assert_eq!(parameters(&agents[elite].neural_network), originals[index]);
+        }
+        for (agent, copy) in agents.iter().zip(&copies) {
+            assert_eq!(parameters(&agent.neural_network), parameters(&copy.neural_network));
+        }
+        // The other agents are mutated children
+        assert!(agents[3..].iter().all(|agent| !originals.contains(&parameters(&agent.neural_network))));
+    }
+
+    #[test]
+    fn selections_favour_the_fittest() {
+        let parents = population(&[1.0, 2.0, 3.0, 4.0]);
+        let originals: Vec<Vec<f32>> = parents.iter().map(|agent| parameters(&agent.neural_network)).collect();
+        let config = GaConfig {
+            population_size: 4000,
+            elite_count: 0,
+            crossover: Crossover::None,
+            mutation_rate: 0.0,
+            ..GaConfig::default()
+        };
+        // Number of children of each parent, which are unchanged copies of it
+        let children = |config: &GaConfig| {
+            let mut agents = copy(&parents);
+            next_generation(&mut agents, config, 1, 1);
+            let mut counts = [0; 4];
+            for agent in &agents {
+                counts[originals.iter().position(|original| *original == parameters(&agent.neural_network)).unwrap()] += 1;
+            }
+            counts
+        };
+        // Ranks 1 to 4 give probabilities 0.1 to 0.4
+        let counts = children(&GaConfig { selection: ParentSelection::Rank, ..config });
+        for (rank, count) in counts.into_iter().enumerate() {
+            assert!((count as f32 - 400.0 * (rank + 1) as f32).abs() < 150.0, "{:?}", counts);
+        }
+        // A tournament between a large number of agents almost surely includes the best one
+        let counts = children(&GaConfig { selection: ParentSelection::Tournament, tournament_size: 200, ..config });
+        assert_eq!(counts, [0, 0, 0, 4000]);
+    }
+
+    #[test]
+    fn crossovers_take_every_parameter_from_a_parent() {
+        let parents = population(&[1.0, 1.0]);
+        let (first, second) = (&parents[0].neural_network, &parents[1].neural_network);
+        let mut rng = SmallRng::seed_from_u64(3);
+        let child = first.uniform_crossover(second, &mut rng);
+        let from_first: Vec<bool> = parameters(&child).iter().zip(parameters(first)).map(|(a, b)| *a == b).collect();
+        for ((&child, &first), &second) in parameters(&child).iter().zip(&parameters(first)).zip(&parameters(second)) {
+            assert!(child == first || child == second);
+        }
+        assert!(from_first.contains(&true) && from_first.contains(&false));
+        // Layer-wise, the weights and bias of each layer come from the same parent
+        let weight_layers = [(0, 80), (80, 100), (100, 116)];
+        let bias_layers = [(0, 5), (5, 9), (9, 13)];
+        for _ in 0..10 {
+            let child = first.layer_crossover(second, &mut rng);
+            for ((weight_start, weight_end), (bias_start, bias_end)) in weight_layers.into_iter().zip(bias_layers) {
+                let weights = &child.weights[weight_start..weight_end];
+                let bias = &child.bias[bias_start..bias_end];
+                let is_first = weights == &first.weights[weight_start..weight_end] && bias == &first.bias[bias_start..bias_end];
+                let is_second = weights == &second.weights[weight_start..weight_end] && bias == &second.bias[bias_start..bias_end];
+                assert!(is_first || is_second);
+            }
+        }
+    }
+
+    #[test]
+    fn configs_round_trip_and_invalid_ones_are_rejected() {
+        let path = std::env::temp_dir().join(format!("ga_config_{}.cfg", std::process::id()));
+        let path = path.to_str().unwrap();
+        let config = GaConfig {
+            population_size: 12,
+            selection: ParentSelection::Rank,
+            crossover: Crossover::LayerWise,
+            diversity: Diversity::Novelty,
+            mutation_strength: 0.125,
+            ..GaConfig::default()
+        };
+        std::fs::write(path, config.to_config_string()).unwrap();
+        let loaded = GaConfig::load(path);
+        std::fs::write(path, "population_size = 0\n").unwrap();
+        let empty = GaConfig::load(path);
+        std::fs::write(path, "mutation_strength = -1\n").unwrap();
+        let negative = GaConfig::load(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.unwrap().to_config_string(), config.to_config_string());
+        assert!(empty.unwrap_err().contains("population_size"));
+        assert!(negative.unwrap_err().contains("mutation_strength"));
+    }
+
+    #[test]
+    fn only_the_best_agents_are_validated() {
+        let fast = FastGame::new();
+        let mut agents = population(&[5.0, 1.0, 9.0, 3.0, 7.0]);
+        let disabled = GaConfig { validation_games: 0, ..GaConfig::default() };
+        assert!(validate_best(&mut agents, &fast, &disabled).is_none());
+        let config = GaConfig { validation_games: 4, validation_candidates: 2, ..GaConfig::default() };
+        let (index, score) = validate_best(&mut agents, &fast, &config).unwrap();
+        assert!(index == 2 || index == 4, "agent {} is not among the best", index);
+        // The validation games are the same for every network and every call
+        let scores = [2, 4].map(|candidate| validation_score(&agents[candidate].neural_network, &fast, &config.fitness, 4));
+        assert_eq!(score, scores[0].max(scores[1]));
+        assert_eq!(validation_score(&agents[index].neural_network, &fast, &config.fitness, 4), score);
+    }
 }
@@ -67,7 +67,11 @@ fn create_block(value: u8, size: usize) -> Vec<String> {
     }
     let mut block = vec![];
     let color = COLORS[(value-1) as usize % COLORS.len()];
-    let number = format!("{}", 1 << value);
+    // Values too long for the block are written as a power of 2
+    let mut number = format!("{}", 1u64 << value.min(63));
+    if number.len() > 4 * size + 2 {
+        number = format!("2^{}", value);
+    }
     let length = number.len();
 
     // Top border
@@ -0,0 +1,307 @@
+// Gradient-based training of NeuralNetwork: losses, their gradients for a sample, and the optimizers applying
+// the gradients of a mini-batch
+use crate::encoding::InputEncoding;
+use crate::neural_network::{self, Activation, Gradients, Initialization, NeuralNetwork};
+use rayon::prelude::*;
+
+// Adam hyperparameters
+const ADAM_BETA1: f32 = 0.9;
+const ADAM_BETA2: f32 = 0.999;
+const ADAM_EPSILON: f32 = 1e-8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Loss {
+    // Mean of the squared errors, NaN targets being ignored (for outputs without a target, such as the
+    // values of the moves not played)
+    MeanSquaredError,
+    // Cross-entropy between the softmax of the outputs and the targets, which must sum to 1.
+    // The outputs are logits: the network's last activation should be linear
+    CrossEntropy,
+}
+
+impl std::str::FromStr for Loss {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mse" => Ok(Loss::MeanSquaredError),
+            "cross_entropy" => Ok(Loss::CrossEntropy),
+            _ => Err("expected mse or cross_entropy".to_string()),
+        }
+    }
+}
+
+impl Loss {
+    // Loss of the outputs, whose gradient with respect to the outputs is written to gradient
+    pub fn evaluate(&self, outputs: &[f32], targets: &[f32], gradient: &mut [f32]) -> f32 {
+        match self {
+            Loss::MeanSquaredError => {
+                let count = targets.iter().filter(|target| !target.is_nan()).count().max(1) as f32;
+                let mut loss = 0.0;
+                for ((output, target), g) in outputs.iter().zip(targets).zip(gradient.iter_mut()) {
+                    if target.is_nan() {
+                        *g = 0.0;
+                        continue;
+                    }
+                    loss += (output - target).powi(2) / count;
+                    *g = 2.0 * (output - target) / count;
+                }
+                loss
+            }
+            Loss::CrossEntropy => {
+                gradient.copy_from_slice(outputs);
+                neural_network::softmax(gradient);
+                let mut loss = 0.0;
+                // The gradient with respect to the logits is the probabilities minus the targets
+                for (probability, target) in gradient.iter_mut().zip(targets) {
+                    loss -= target * probability.max(1e-7).ln();
+                    *probability -= target;
+                }
+                loss
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OptimizerKind {
+    // Stochastic gradient descent, with momentum when it is not 0
+    Sgd,
+    Adam,
+}
+
+impl std::str::FromStr for OptimizerKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sgd" => Ok(OptimizerKind::Sgd),
+            "adam" => Ok(OptimizerKind::Adam),
+            _ => Err("expected sgd or adam".to_string()),
+        }
+    }
+}
+
+pub struct Optimizer {
+    kind: OptimizerKind,
+    learning_rate: f32,
+    momentum: f32,
+    // Per parameter (every weight then every bias): velocity for SGD, first and second moments for Adam
+    first_moment: Vec<f32>,
+    second_moment: Vec<f32>,
+    steps: i32,
+}
+
+impl Optimizer {
+    pub fn new(kind: OptimizerKind, learning_rate: f32, momentum: f32, network: &NeuralNetwork) -> Optimizer {
+        let len = network.weights.len() + network.bias.len();
+        Optimizer {
+            kind,
+            learning_rate,
+            momentum,
+            first_moment: vec![0.0; len],
+            second_moment: if kind == OptimizerKind::Adam { vec![0.0; len] } else { Vec::new() },
+            steps: 0,
+        }
+    }
+
+    // Moves the network against the gradients multiplied by scale (1 / batch size for summed gradients)
+    pub fn step(&mut self, network: &mut NeuralNetwork, gradients: &Gradients, scale: f32) {
+        self.steps += 1;
+        let parameters = network.weights.iter_mut().chain(network.bias.iter_mut());
+        let gradients = gradients.weights.iter().chain(gradients.bias.iter());
+        match self.kind {
+            OptimizerKind::Sgd => {
+                for ((parameter, gradient), velocity) in parameters.zip(gradients).zip(self.first_moment.iter_mut()) {
+                    *velocity = self.momentum * *velocity + gradient * scale;
+                    *parameter -= self.learning_rate * *velocity;
+                }
+            }
+            OptimizerKind::Adam => {
+                let first_correction = 1.0 - ADAM_BETA1.powi(self.steps);
+                let second_correction = 1.0 - ADAM_BETA2.powi(self.steps);
+                let moments = self.first_moment.iter_mut().zip(self.second_moment.iter_mut());
+                for ((parameter, gradient), (first_moment, second_moment)) in parameters.zip(gradients).zip(moments) {
+                    let g = gradient * scale;
+                    *first_moment = ADAM_BETA1 * *first_moment + (1.0 - ADAM_BETA1) * g;
+                    *second_moment = ADAM_BETA2 * *second_moment + (1.0 - ADAM_BETA2) * g * g;
+                    let first = *first_moment / first_correction;
+                    let second = *second_moment / second_correction;
+                    *parameter -= self.learning_rate * first / (second.sqrt() + ADAM_EPSILON);
+                }
+            }
+        }
+    }
+}
+
+// Network with leaky ReLU hidden layers and one linear output per direction, in game::DIRECTIONS order
+pub fn direction_network(
+    encoding: InputEncoding,
+    hidden_layers: usize,
+    hidden_size: u32,
+    initialization: Initialization,
+) -> NeuralNetwork {
+    let mut layers = vec![encoding.input_size() as u32];
+    layers.extend(std::iter::repeat_n(hidden_size, hidden_layers));
+    layers.push(4);
+    let mut activations = vec![Activation::LeakyRelu; hidden_layers];
+    activations.push(Activation::Linear);
+    NeuralNetwork::initialized(layers, activations, encoding, initialization, (-0.1, 0.1), (-0.01, 0.01))
+}
+
+// Adds the gradient of the loss for a packed grid fed with the network's encoding, returns the loss.
+// Only the first targets.len() outputs are trained, and written to outputs (of the size of targets)
+pub fn add_board_gradient(
+    network: &NeuralNetwork,
+    grid: &[u32; 4],
+    targets: &[f32],
+    loss: Loss,
+    outputs: &mut [f32],
+    gradients: &mut Gradients,
+) -> f32 {
+    add_board_gradient_with(network, grid, outputs, gradients, |outputs, gradient| {
+        loss.evaluate(outputs, targets, gradient)
+    })
+}
+
+// Same as add_board_gradient for any loss: loss receives the first outputs.len() outputs, writes its gradient with
+// respect to them and returns its value. Used by networks whose outputs are trained by different losses
+pub fn add_board_gradient_with<F>(
+    network: &NeuralNetwork,
+    grid: &[u32; 4],
+    outputs: &mut [f32],
+    gradients: &mut Gradients,
+    loss: F,
+) -> f32
+where
+    F: FnOnce(&[f32], &mut [f32]) -> f32,
+{
+    let encoding = network.encoding();
+    let mut inputs = Vec::with_capacity(encoding.samples() * encoding.input_size());
+    encoding.encode(grid, &mut inputs);
+    let trace = network.forward_trace(&inputs, encoding.samples());
+    let sample_outputs = trace.last().unwrap();
+    encoding.combine(sample_outputs, outputs);
+    let mut gradient = vec![0.0; outputs.len()];
+    let value = loss(outputs, &mut gradient);
+    let output_gradients = encoding.split_gradient(&gradient, sample_outputs.len() / encoding.samples());
+    network.backpropagate(&trace, &output_gradients, gradients);
+    value
+}
+
+// Gradients and loss summed over a mini-batch, computed in parallel. sample_gradient adds the gradient of a sample
+// and returns its loss
+pub fn batch_gradients<S, F>(network: &NeuralNetwork, batch: &[S], sample_gradient: F) -> (Gradients, f32)
+where
+    S: Sync,
+    F: Fn(&S, &mut Gradients) -> f32 + Sync + Send,
+{
+    batch
+        .par_iter()
+        .fold(
+            || (Gradients::zeros(network), 0.0),
+            |(mut gradients, loss), sample| {
+                let sample_loss = sample_gradient(sample, &mut gradients);
+                (gradients, loss + sample_loss)
+            },
+        )
+        .reduce(
+            || (Gradients::zeros(network), 0.0),
+            |(mut gradients, loss), (other, other_loss)| {
+                gradients.add(&other);
+                (gradients, loss + other_loss)
+            },
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fastgame::FastGame;
+    use crate::finite_differences::{assert_close, parameter, EPSILON};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn losses_match_finite_differences() {
+        let outputs = [0.2, 0.7, -0.3, 1.1];
+        let cases = [
+            (Loss::MeanSquaredError, [0.5, f32::NAN, -1.0, 2.0]),
+            (Loss::CrossEntropy, [0.1, 0.2, 0.3, 0.4]),
+            (Loss::CrossEntropy, [0.0, 0.0, 1.0, 0.0]),
+        ];
+        for (loss, targets) in cases {
+            let mut gradient = [0.0; 4];
+            loss.evaluate(&outputs, &targets, &mut gradient);
+            let mut scratch = [0.0; 4];
+            for i in 0..4 {
+                let (mut above, mut below) = (outputs, outputs);
+                above[i] += EPSILON;
+                below[i] -= EPSILON;
+                let numerical = (loss.evaluate(&above, &targets, &mut scratch)
+                    - loss.evaluate(&below, &targets, &mut scratch))
+                    / (2.0 * EPSILON);
+                assert_close(gradient[i], numerical, &format!("{:?} output {}", loss, i));
+            }
+        }
+    }
+
+    // Gradients through the encoding, the network and the loss, for the plain and the symmetric encodings
+    #[test]
+    fn board_gradients_match_finite_differences() {
+        let grid = FastGame::from_flat_array([1, 2, 0, 3, 0, 1, 4, 0, 2, 0, 0, 5, 1, 1, 3, 0]);
+        let cases = [
+            (Loss::CrossEntropy, [0.7, 0.1, 0.2, 0.0]),
+            (Loss::MeanSquaredError, [0.0, -0.5, f32::NAN, -1.0]),
+        ];
+        for encoding in ["scalar", "symmetric_scalar", "one_hot", "symmetric_one_hot"] {
+            let encoding: InputEncoding = encoding.parse().unwrap();
+            let mut network = direction_network(encoding, 1, 6, Initialization::Xavier);
+            let mut rng = SmallRng::seed_from_u64(1);
+            for parameter in network.weights.iter_mut().chain(network.bias.iter_mut()) {
+                *parameter = rng.random_range(-0.5..0.5);
+            }
+            for (loss, targets) in cases {
+                let board_loss = |network: &NeuralNetwork| {
+                    let mut outputs = [0.0; 4];
+                    add_board_gradient(network, &grid, &targets, loss, &mut outputs, &mut Gradients::zeros(network))
+                };
+                let mut gradients = Gradients::zeros(&network);
+                add_board_gradient(&network, &grid, &targets, loss, &mut [0.0; 4], &mut gradients);
+                let analytic: Vec<f32> = gradients.weights.iter().chain(&gradients.bias).copied().collect();
+                for (i, &analytic) in analytic.iter().enumerate() {
+                    let original = *parameter(&mut network, i);
+                    *parameter(&mut network, i) = original + EPSILON;
+                    let above = board_loss(&network);
+                    *parameter(&mut network, i) = original - EPSILON;
+                    let below = board_loss(&network);
+                    *parameter(&mut network, i) = original;
+                    let what = format!("{} {:?} parameter {}", encoding, loss, i);
+                    assert_close(analytic, (above - below) / (2.0 * EPSILON), &what);
+                }
+            }
+        }
+    }
+
+    // Both optimizers must reach the minimum of a sum of squares of the parameters' distances to targets
+    #[test]
+    fn optimizers_minimize_a_quadratic() {
+        let cases = [(OptimizerKind::Sgd, 0.1, 0.0), (OptimizerKind::Sgd, 0.05, 0.9), (OptimizerKind::Adam, 0.05, 0.0)];
+        for (kind, learning_rate, momentum) in cases {
+            let mut network = direction_network(InputEncoding::default(), 0, 0, Initialization::Xavier);
+            let targets: Vec<f32> = (0..network.weights.len() + network.bias.len()).map(|i| (i as f32).sin()).collect();
+            let mut optimizer = Optimizer::new(kind, learning_rate, momentum, &network);
+            for _ in 0..1000 {
+                let mut gradients = Gradients::zeros(&network);
+                let parameters = network.weights.iter().chain(&network.bias);
+                let gradient_values = gradients.weights.iter_mut().chain(gradients.bias.iter_mut());
+                for ((gradient, parameter), target) in gradient_values.zip(parameters).zip(&targets) {
+                    *gradient = 2.0 * (parameter - target);
+                }
+                optimizer.step(&mut network, &gradients, 1.0);
+            }
+            let parameters = network.weights.iter().chain(&network.bias);
+            for (parameter, target) in parameters.zip(&targets) {
+                assert!((parameter - target).abs() < 1e-2, "{:?}: {} instead of {}", kind, parameter, target);
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Checkpoints of the genetic training, which must load back exactly, even after an interrupted save
+use main::checkpoint;
+use main::encoding::InputEncoding;
+use main::neural_network::{Activation, NeuralNetwork};
+use main::population::{Agent, GaConfig, ParentSelection};
+
+fn network() -> NeuralNetwork {
+    let encoding: InputEncoding = "scalar".parse().unwrap();
+    NeuralNetwork::new(vec![16, 6, 4], vec![Activation::Tanh, Activation::Linear], encoding, (-1.0, 1.0), (-1.0, 1.0))
+}
+
+fn assert_same_network(loaded: &NeuralNetwork, network: &NeuralNetwork) {
+    assert_eq!(loaded.weights, network.weights);
+    assert_eq!(loaded.bias, network.bias);
+}
+
+// Saves a checkpoint of three agents about to play generation
+fn save(directory: &str, generation: u64) -> Vec<NeuralNetwork> {
+    let networks: Vec<NeuralNetwork> = (0..3).map(|_| network()).collect();
+    let agents: Vec<Agent> = networks.iter().map(|network| Agent::from(network.clone(), 1000 + generation)).collect();
+    let config = GaConfig { population_size: 3, selection: ParentSelection::Rank, ..GaConfig::default() };
+    let history: Vec<(u64, f32)> = (1..generation).map(|played| (played, played as f32 * 10.0)).collect();
+    checkpoint::save(directory, generation, 42, &config, &agents, &history, Some((&networks[0], 123.5)), None).unwrap();
+    networks
+}
+
+fn remove(directory: &str) {
+    for path in [directory.to_string(), format!("{}.tmp", directory), format!("{}.old", directory)] {
+        if std::path::Path::new(&path).exists() {
+            std::fs::remove_dir_all(&path).unwrap();
+        }
+    }
+}
+
+#[test]
+fn checkpoints_load_back() {
+    let directory = std::env::temp_dir().join(format!("checkpoint_round_trip_{}", std::process::id()));
+    let directory = directory.to_str().unwrap();
+    let networks = save(directory, 5);
+    let state = checkpoint::load(directory);
+    remove(directory);
+    let state = state.unwrap();
+    assert_eq!((state.generation, state.seed, state.game_seed), (5, 42, 1005));
+    assert_eq!(state.config.population_size, 3);
+    assert_eq!(state.config.selection, ParentSelection::Rank);
+    assert_eq!(state.history, vec![(1, 10.0), (2, 20.0), (3, 30.0), (4, 40.0)]);
+    assert_eq!(state.networks.len(), networks.len());
+    for (loaded, network) in state.networks.iter().zip(&networks) {
+        assert_same_network(loaded, network);
+    }
+    let (best, fitness) = state.best_ever.unwrap();
+    assert_same_network(&best, &networks[0]);
+    assert_eq!(fitness, 123.5);
+    assert!(state.best_validation.is_none());
+}
+
+#[test]
+fn interrupted_saves_leave_a_complete_checkpoint() {
+    let directory = std::env::temp_dir().join(format!("checkpoint_interrupted_{}", std::process::id()));
+    let directory = directory.to_str().unwrap();
+    let temporary = format!("{}.tmp", directory);
+    let old = format!("{}.old", directory);
+    // Interrupted after moving the previous checkpoint away: the new one is complete in .tmp
+    let new = format!("{}.new", directory);
+    save(directory, 1);
+    std::fs::rename(directory, &old).unwrap();
+    let networks = save(&new, 2);
+    std::fs::rename(&new, &temporary).unwrap();
+    let newest = checkpoint::load(directory).map(|state| state.generation);
+    // Interrupted while writing .tmp: the previous checkpoint is used
+    std::fs::remove_file(format!("{}/state.cfg", temporary)).unwrap();
+    let previous = checkpoint::load(directory).map(|state| state.generation);
+    // The next save replaces everything
+    let next_networks = save(directory, 3);
+    let leftovers = [&temporary, &old].map(|path| std::path::Path::new(path).exists());
+    let next = checkpoint::load(directory);
+    remove(directory);
+    assert_eq!(newest, Ok(2));
+    assert_eq!(previous, Ok(1));
+    assert_eq!(leftovers, [false, false]);
+    let next = next.unwrap();
+    assert_eq!(next.generation, 3);
+    assert_same_network(&next.networks[2], &next_networks[2]);
+    assert_ne!(next.networks[2].weights, networks[2].weights);
+}
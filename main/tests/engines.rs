@@ -0,0 +1,224 @@
+// Cross-engine equivalence of game.rs (boards of [u8; 16]) and the table-driven FastGame, and properties of the
+// moves which any refactoring of the engines must keep
+use main::encoding::symmetric_direction;
+use main::fastgame::{FastGame, MAX_BLOCK_EXPONENT};
+use main::game::{self, Direction, DIRECTIONS};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use seeded_random::{Random, Seed};
+
+const RANDOM_BOARDS: usize = 1 << 20;
+const PROPERTY_BOARDS: usize = 1 << 16;
+// Exponent ranges of the random boards: merges are frequent with small ranges, lost boards with large ones, and the
+// last range exercises the highest exponent
+const EXPONENT_RANGES: [(u8, u8); 6] = [(1, 2), (1, 4), (1, 11), (1, 17), (1, 31), (28, 31)];
+const EMPTY_SHARES: [f64; 3] = [0.0, 0.2, 0.6];
+
+fn random_board(rng: &mut SmallRng) -> [u8; 16] {
+    let (lowest, highest) = EXPONENT_RANGES[rng.random_range(0..EXPONENT_RANGES.len())];
+    let empty_share = EMPTY_SHARES[rng.random_range(0..EMPTY_SHARES.len())];
+    core::array::from_fn(|_| if rng.random_bool(empty_share) { 0 } else { rng.random_range(lowest..=highest) })
+}
+
+fn slow_move(board: &[u8; 16], direction: &Direction) -> ([u8; 16], i32) {
+    let mut moved = *board;
+    let score = match direction {
+        Direction::Up => game::move_up(&mut moved),
+        Direction::Down => game::move_down(&mut moved),
+        Direction::Left => game::move_left(&mut moved),
+        Direction::Right => game::move_right(&mut moved),
+        Direction::None => 0,
+    };
+    (moved, score)
+}
+
+// Legal moves in DIRECTIONS order
+fn slow_legal_moves(board: &[u8; 16]) -> [bool; 4] {
+    [game::can_up(board), game::can_down(board), game::can_left(board), game::can_right(board)]
+}
+
+fn fast_legal_moves(fast: &FastGame, grid: &[u32; 4]) -> [bool; 4] {
+    let possible = fast.get_possible_directions(grid);
+    core::array::from_fn(|i| possible.contains(&DIRECTIONS[i]))
+}
+
+// game.rs scores are i32 and saturate at i32::MAX, FastGame scores are u32 and saturate at u32::MAX
+fn same_score(fast_score: u32, slow_score: i32) -> bool {
+    fast_score.min(i32::MAX as u32) == slow_score as u32
+}
+
+// Checks that both engines agree on every move, the legal moves and the end of the game
+fn assert_engines_agree(fast: &FastGame, board: &[u8; 16]) {
+    let grid = FastGame::from_flat_array(*board);
+    for direction in &DIRECTIONS {
+        let (fast_grid, fast_score) = fast.make_move(&grid, direction);
+        let (slow_board, slow_score) = slow_move(board, direction);
+        assert_eq!(FastGame::to_flat_array(fast_grid), slow_board, "{} on {:?}", direction, board);
+        assert!(same_score(fast_score, slow_score), "{} on {:?}: {} and {}", direction, board, fast_score, slow_score);
+    }
+    let legal = slow_legal_moves(board);
+    assert_eq!(fast_legal_moves(fast, &grid), legal, "legal moves of {:?}", board);
+    for (direction, &legal) in DIRECTIONS.iter().zip(&legal) {
+        assert_eq!(fast.can_move(&grid, direction), legal, "{} on {:?}", direction, board);
+    }
+    assert_eq!(fast.is_lost(&grid), game::is_lost(board), "is_lost of {:?}", board);
+}
+
+#[test]
+fn engines_agree_on_every_row_and_column() {
+    let fast = FastGame::new();
+    for row in 0..1u32 << 20 {
+        let cells: [u8; 4] = core::array::from_fn(|i| ((row >> ((3 - i) * 5)) & 0x1F) as u8);
+        let mut as_row = [0; 16];
+        as_row[4..8].copy_from_slice(&cells);
+        assert_engines_agree(&fast, &as_row);
+        let mut as_column = [0; 16];
+        for (i, &cell) in cells.iter().enumerate() {
+            as_column[i * 4 + 2] = cell;
+        }
+        assert_engines_agree(&fast, &as_column);
+    }
+}
+
+#[test]
+fn engines_agree_on_random_boards() {
+    let fast = FastGame::new();
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut lost = 0;
+    for _ in 0..RANDOM_BOARDS {
+        let board = random_board(&mut rng);
+        assert_engines_agree(&fast, &board);
+        lost += game::is_lost(&board) as usize;
+    }
+    // The boards must cover both ongoing and lost games
+    assert!(lost > 0 && lost < RANDOM_BOARDS);
+}
+
+#[test]
+fn engines_agree_along_played_games() {
+    let fast = FastGame::new();
+    for seed in 0..200 {
+        let rand = Random::from_seed(Seed::unsafe_new(seed));
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut grid = fast.add_random_block(fast.add_random_block([0; 4], &rand), &rand);
+        while !fast.is_lost(&grid) {
+            assert_engines_agree(&fast, &FastGame::to_flat_array(grid));
+            let possible = fast.get_possible_directions(&grid);
+            let direction = possible[rng.random_range(0..possible.len())].clone();
+            grid = fast.play_move(grid, direction, &rand).0;
+        }
+    }
+}
+
+fn tile_sum(board: &[u8; 16]) -> u64 {
+    board.iter().filter(|&&cell| cell != 0).map(|&cell| 1u64 << cell).sum()
+}
+
+fn tile_count(board: &[u8; 16]) -> usize {
+    board.iter().filter(|&&cell| cell != 0).count()
+}
+
+#[test]
+fn moves_keep_the_tile_sum_and_score_the_merges() {
+    let fast = FastGame::new();
+    let mut rng = SmallRng::seed_from_u64(1);
+    for _ in 0..PROPERTY_BOARDS {
+        let board = random_board(&mut rng);
+        let grid = FastGame::from_flat_array(board);
+        for direction in &DIRECTIONS {
+            let (moved, score) = fast.make_move(&grid, direction);
+            let moved = FastGame::to_flat_array(moved);
+            assert_eq!(tile_sum(&moved), tile_sum(&board), "{} on {:?}", direction, board);
+            // Each merge removes a tile and scores the tile it creates, the biggest possible being 2^31
+            let merges = tile_count(&board) - tile_count(&moved);
+            assert_eq!(score == 0, merges == 0, "{} on {:?}", direction, board);
+            assert!(score as u64 >= 4 * merges as u64);
+            assert!(score as u64 <= merges as u64 * (1 << MAX_BLOCK_EXPONENT));
+            // A move is legal exactly when it changes the board
+            assert_eq!(fast.can_move(&grid, direction), moved != board, "{} on {:?}", direction, board);
+        }
+    }
+}
+
+#[test]
+fn spawns_add_a_two_or_a_four_in_an_empty_cell() {
+    let fast = FastGame::new();
+    let mut rng = SmallRng::seed_from_u64(2);
+    let rand = Random::from_seed(Seed::unsafe_new(2));
+    for _ in 0..PROPERTY_BOARDS {
+        let board = random_board(&mut rng);
+        let spawned = FastGame::to_flat_array(fast.add_random_block(FastGame::from_flat_array(board), &rand));
+        let changed: Vec<usize> = (0..16).filter(|&i| spawned[i] != board[i]).collect();
+        if tile_count(&board) == 16 {
+            assert!(changed.is_empty());
+            continue;
+        }
+        assert_eq!(changed.len(), 1, "spawn on {:?}", board);
+        assert_eq!(board[changed[0]], 0);
+        assert!(spawned[changed[0]] == 1 || spawned[changed[0]] == 2);
+    }
+}
+
+#[test]
+fn symmetric_boards_have_symmetric_moves() {
+    let fast = FastGame::new();
+    let mut rng = SmallRng::seed_from_u64(3);
+    for _ in 0..PROPERTY_BOARDS {
+        let grid = FastGame::from_flat_array(random_board(&mut rng));
+        let symmetries = FastGame::symmetries(grid);
+        assert_eq!(symmetries[0], grid);
+        for (symmetry, symmetric_grid) in symmetries.iter().enumerate() {
+            assert_eq!(fast.is_lost(symmetric_grid), fast.is_lost(&grid));
+            assert_eq!(FastGame::canonical(*symmetric_grid), FastGame::canonical(grid));
+            for direction in &DIRECTIONS {
+                let (moved, score) = fast.make_move(&grid, direction);
+                let symmetric = DIRECTIONS[symmetric_direction(direction.index(), symmetry)].clone();
+                let (symmetric_moved, symmetric_score) = fast.make_move(symmetric_grid, &symmetric);
+                assert_eq!(FastGame::symmetries(moved)[symmetry], symmetric_moved, "{} and symmetry {}", direction, symmetry);
+                assert_eq!(score, symmetric_score);
+                assert_eq!(fast.can_move(&grid, direction), fast.can_move(symmetric_grid, &symmetric));
+            }
+        }
+    }
+}
+
+#[test]
+fn symmetric_positions_follow_the_symmetries() {
+    let fast = FastGame::new();
+    let mut rng = SmallRng::seed_from_u64(5);
+    for _ in 0..PROPERTY_BOARDS / 16 {
+        let grid = FastGame::from_flat_array(random_board(&mut rng));
+        let symmetries = FastGame::symmetries(grid);
+        for pos in FastGame::empty_list(&grid) {
+            let spawned = FastGame::symmetries(fast.place_block(grid, pos, 1));
+            for (symmetry, symmetric_grid) in symmetries.iter().enumerate() {
+                let symmetric_pos = FastGame::symmetric_position(pos, symmetry);
+                assert_eq!(fast.place_block(*symmetric_grid, symmetric_pos, 1), spawned[symmetry]);
+            }
+        }
+    }
+}
+
+#[test]
+fn flat_arrays_round_trip() {
+    let mut rng = SmallRng::seed_from_u64(4);
+    for _ in 0..PROPERTY_BOARDS {
+        let board = random_board(&mut rng);
+        assert_eq!(FastGame::to_flat_array(FastGame::from_flat_array(board)), board);
+    }
+}
+
+#[test]
+fn highest_tiles_never_merge() {
+    let fast = FastGame::new();
+    let highest = MAX_BLOCK_EXPONENT as u8;
+    let grid = FastGame::from_flat_array([highest, highest, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert!(!fast.can_move(&grid, &Direction::Left));
+    assert_eq!(fast.make_move(&grid, &Direction::Right).1, 0);
+    // Two merges into 2^31 score 2^32, which saturates
+    let row = highest - 1;
+    let grid = FastGame::from_flat_array([row, row, row, row, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let (moved, score) = fast.make_move(&grid, &Direction::Left);
+    assert_eq!(FastGame::to_flat_array(moved)[..4], [highest, highest, 0, 0]);
+    assert_eq!(score, u32::MAX);
+}
@@ -0,0 +1,187 @@
+// Exports of the MCTS tree, which must be valid JSON and DOT describing the same nodes
+use main::fastgame::FastGame;
+use main::mcts::{MctsConfig, MonteCarloTree};
+use seeded_random::{Random, Seed};
+
+const MAX_DEPTH: usize = 3;
+const MAX_CHILDREN: usize = 2;
+
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool,
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => &fields.iter().find(|(name, _)| name == key).unwrap_or_else(|| panic!("no {}", key)).1,
+            _ => panic!("{:?} is not an object", self),
+        }
+    }
+}
+
+// Strict parser for the subset of JSON written by the export (no escapes in strings)
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.position < self.text.len() && self.text[self.position].is_ascii_whitespace() {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) {
+        self.skip_whitespace();
+        assert_eq!(self.text.get(self.position), Some(&byte), "at {}", self.position);
+        self.position += 1;
+    }
+
+    fn peek(&mut self) -> u8 {
+        self.skip_whitespace();
+        self.text[self.position]
+    }
+
+    fn string(&mut self) -> String {
+        self.expect(b'"');
+        let start = self.position;
+        while self.text[self.position] != b'"' {
+            assert_ne!(self.text[self.position], b'\\');
+            self.position += 1;
+        }
+        self.position += 1;
+        String::from_utf8(self.text[start..self.position - 1].to_vec()).unwrap()
+    }
+
+    // Elements of an array or an object, separated by commas
+    fn elements<T>(&mut self, close: u8, mut element: impl FnMut(&mut Self) -> T) -> Vec<T> {
+        let mut elements = Vec::new();
+        if self.peek() == close {
+            self.position += 1;
+            return elements;
+        }
+        loop {
+            elements.push(element(self));
+            if self.peek() == close {
+                self.position += 1;
+                return elements;
+            }
+            self.expect(b',');
+        }
+    }
+
+    fn value(&mut self) -> Json {
+        match self.peek() {
+            b'{' => {
+                self.position += 1;
+                Json::Object(self.elements(b'}', |parser| {
+                    let key = parser.string();
+                    parser.expect(b':');
+                    (key, parser.value())
+                }))
+            }
+            b'[' => {
+                self.position += 1;
+                Json::Array(self.elements(b']', Self::value))
+            }
+            b'"' => Json::String(self.string()),
+            _ => {
+                let start = self.position;
+                while self.position < self.text.len() && !b",]}".contains(&self.text[self.position]) {
+                    self.position += 1;
+                }
+                match std::str::from_utf8(&self.text[start..self.position]).unwrap().trim() {
+                    "null" => Json::Null,
+                    "true" | "false" => Json::Bool,
+                    number => Json::Number(number.parse().unwrap_or_else(|_| panic!("{} is not a number", number))),
+                }
+            }
+        }
+    }
+}
+
+fn parse(text: &str) -> Json {
+    let mut parser = Parser { text: text.as_bytes(), position: 0 };
+    let json = parser.value();
+    parser.skip_whitespace();
+    assert_eq!(parser.position, text.len(), "trailing characters");
+    json
+}
+
+// Checks the fields of a node and its subtree, returns the (index, parent index) of every node
+fn check_node(node: &Json, parent: Option<usize>, depth: usize, nodes: &mut Vec<(usize, Option<usize>)>) {
+    let Json::Number(index) = node.get("index") else { panic!("index is not a number") };
+    nodes.push((*index as usize, parent));
+    let is_spawn = match node.get("type") {
+        Json::String(kind) => kind == "spawn",
+        other => panic!("type {:?}", other),
+    };
+    match node.get("board") {
+        Json::Array(cells) => {
+            assert_eq!(cells.len(), 16);
+            for cell in cells {
+                let Json::Number(value) = cell else { panic!("cell {:?}", cell) };
+                assert!(*value == 0.0 || (*value as u32).is_power_of_two());
+            }
+        }
+        other => panic!("board {:?}", other),
+    }
+    // Spawn nodes follow a move, move nodes (but the root) follow a spawn
+    assert_eq!(matches!(node.get("move"), Json::String(_)), is_spawn);
+    assert_eq!(matches!(node.get("spawn"), Json::Object(_)), !is_spawn && parent.is_some());
+    let Json::Number(visits) = node.get("visits") else { panic!("visits is not a number") };
+    for key in ["mean", "variance", "probability"] {
+        assert!(matches!(node.get(key), Json::Number(_) | Json::Null), "{}", key);
+    }
+    let Json::Array(children) = node.get("children") else { panic!("children is not an array") };
+    assert!(children.len() <= MAX_CHILDREN && (depth < MAX_DEPTH || children.is_empty()));
+    let mut previous_visits = f64::INFINITY;
+    for child in children {
+        let Json::Number(child_visits) = child.get("visits") else { panic!("visits is not a number") };
+        assert!(*child_visits <= *visits && *child_visits <= previous_visits, "children are not the most visited first");
+        previous_visits = *child_visits;
+        check_node(child, Some(*index as usize), depth + 1, nodes);
+    }
+}
+
+#[test]
+fn exports_are_well_formed_and_agree() {
+    let fast = FastGame::new();
+    let rand = Random::from_seed(Seed::unsafe_new(1));
+    let game_state = fast.add_random_block(fast.add_random_block([0; 4], &rand), &rand);
+    let mut tree = MonteCarloTree::new(&fast, game_state, MctsConfig::default());
+    tree.grow_tree(&fast, 0.0, 2000);
+
+    let mut json_nodes = Vec::new();
+    check_node(&parse(&tree.export_json(MAX_DEPTH, MAX_CHILDREN)), None, 0, &mut json_nodes);
+    assert!(json_nodes.len() > MAX_DEPTH, "the export stopped at the root");
+
+    // The DOT graph has the same nodes, and one edge from each node to its parent
+    let dot = tree.export_dot(MAX_DEPTH, MAX_CHILDREN);
+    assert!(dot.starts_with("digraph mcts {\n") && dot.ends_with("}\n"));
+    let mut dot_nodes = Vec::new();
+    let mut dot_edges = Vec::new();
+    for line in dot.lines().skip(2).take_while(|line| *line != "}") {
+        let line = line.trim();
+        assert!(line.ends_with("];") && line.matches('"').count() == 2, "{}", line);
+        let statement = &line[..line.find(" [").unwrap()];
+        let node = |name: &str| name.strip_prefix('n').unwrap().parse::<usize>().unwrap();
+        match statement.split_once(" -> ") {
+            Some((parent, child)) => dot_edges.push((node(child), node(parent))),
+            None => dot_nodes.push(node(statement)),
+        }
+    }
+    assert_eq!(dot_nodes, json_nodes.iter().map(|&(index, _)| index).collect::<Vec<_>>());
+    let mut json_edges: Vec<(usize, usize)> =
+        json_nodes.iter().filter_map(|&(index, parent)| parent.map(|parent| (index, parent))).collect();
+    json_edges.sort();
+    dot_edges.sort();
+    assert_eq!(dot_edges, json_edges);
+}
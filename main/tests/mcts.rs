@@ -0,0 +1,37 @@
+// Rerooting of the MCTS, which must keep the subtree of the move played and the spawn drawn
+use main::fastgame::FastGame;
+use main::game::DIRECTIONS;
+use main::mcts::{ChanceSampling, MctsConfig, MonteCarloTree};
+use seeded_random::{Random, Seed};
+
+const GAMES: u64 = 20;
+// Boards stay symmetric, and spawns grouped, in the first moves from a single tile
+const MOVES: usize = 4;
+const ITERATIONS: usize = 1000;
+
+#[test]
+fn grouped_trees_are_kept_for_every_spawn_of_a_group() {
+    let fast = FastGame::new();
+    let config = MctsConfig { chance_sampling: ChanceSampling::Grouped, ..MctsConfig::default() };
+    let mut symmetric_reroots = 0;
+    for seed in 0..GAMES {
+        let rand = Random::from_seed(Seed::unsafe_new(seed));
+        let mut game_state = fast.place_block([0; 4], (0, 3), 1);
+        let mut tree = MonteCarloTree::new(&fast, game_state, config);
+        for _ in 0..MOVES {
+            tree.grow_tree(&fast, 0.0, ITERATIONS);
+            let direction = tree.get_best_direction();
+            let (new_game_state, move_score) = fast.play_move(game_state, direction, &rand);
+            game_state = new_game_state;
+            // A spawn node stops expanding once its 2s or its 4s are all expanded, so the spawn drawn may have no node
+            let symmetry = tree.find_new_root(game_state).map_or(0, |(_, symmetry)| symmetry);
+            symmetric_reroots += (symmetry != 0) as usize;
+            tree.reroot(&fast, move_score, game_state);
+            // The kept subtree must describe the real board: only its legal moves have been visited
+            for (direction, share) in DIRECTIONS.iter().zip(tree.get_visit_distribution()) {
+                assert!(share == 0.0 || fast.can_move(&game_state, direction), "{} after symmetry {}", direction, symmetry);
+            }
+        }
+    }
+    assert!(symmetric_reroots > 0);
+}